@@ -1,46 +1,279 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 
-#[derive(PartialEq, Debug)]
+use crate::{
+    error::ParseError,
+    message::MAGIC_COOKIE,
+    registry::{self, CustomAttribute, CustomValue},
+};
+
+const FAMILY_IPV4: u8 = 0x01;
+const FAMILY_IPV6: u8 = 0x02;
+
+const CHANGE_IP: u32 = 0x04;
+const CHANGE_PORT: u32 = 0x02;
+
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub enum AttributeType {
-    Unknown,
+    Unknown(u16),
+    /// Registered by the application, see [`registry`]
+    Custom(u16),
     MappedAddress,
+    ChangeRequest,
+    Username,
+    MessageIntegrity,
+    ErrorCode,
+    UnknownAttributes,
+    ChannelNumber,
+    Lifetime,
+    XorPeerAddress,
+    Data,
+    Realm,
+    Nonce,
+    XorRelayedAddress,
+    RequestedTransport,
+    DontFragment,
+    XorMappedAddress,
+    Priority,
+    UseCandidate,
     Software,
+    Fingerprint,
+    IceControlled,
+    IceControlling,
+    ResponseOrigin,
+    OtherAddress,
 }
 
 impl AttributeType {
     pub fn bytes(&self) -> [u8; 2] {
         match self {
-            Self::Unknown => [0x00, 0x00],
+            Self::Unknown(x) | Self::Custom(x) => x.to_be_bytes(),
             Self::MappedAddress => [0x00, 0x01],
+            Self::ChangeRequest => [0x00, 0x03],
+            Self::Username => [0x00, 0x06],
+            Self::MessageIntegrity => [0x00, 0x08],
+            Self::ErrorCode => [0x00, 0x09],
+            Self::UnknownAttributes => [0x00, 0x0A],
+            Self::ChannelNumber => [0x00, 0x0C],
+            Self::Lifetime => [0x00, 0x0D],
+            Self::XorPeerAddress => [0x00, 0x12],
+            Self::Data => [0x00, 0x13],
+            Self::Realm => [0x00, 0x14],
+            Self::Nonce => [0x00, 0x15],
+            Self::XorRelayedAddress => [0x00, 0x16],
+            Self::RequestedTransport => [0x00, 0x19],
+            Self::DontFragment => [0x00, 0x1A],
+            Self::XorMappedAddress => [0x00, 0x20],
+            Self::Priority => [0x00, 0x24],
+            Self::UseCandidate => [0x00, 0x25],
             Self::Software => [0x80, 0x22],
+            Self::Fingerprint => [0x80, 0x28],
+            Self::IceControlled => [0x80, 0x29],
+            Self::IceControlling => [0x80, 0x2A],
+            Self::ResponseOrigin => [0x80, 0x2B],
+            Self::OtherAddress => [0x80, 0x2C],
         }
     }
 
-    pub fn from_bytes(bytes: Bytes) -> Self {
-        for x in [Self::MappedAddress, Self::Software] {
-            if &x.bytes()[..] == bytes {
+    pub fn from_bytes(bytes: [u8; 2]) -> Self {
+        match Self::builtin(bytes) {
+            Self::Unknown(x) if registry::is_registered(x) => Self::Custom(x),
+            x => x,
+        }
+    }
+
+    /// Like `from_bytes`, but without the registered types
+    pub(crate) fn builtin(bytes: [u8; 2]) -> Self {
+        for x in [
+            Self::MappedAddress,
+            Self::ChangeRequest,
+            Self::Username,
+            Self::MessageIntegrity,
+            Self::ErrorCode,
+            Self::UnknownAttributes,
+            Self::ChannelNumber,
+            Self::Lifetime,
+            Self::XorPeerAddress,
+            Self::Data,
+            Self::Realm,
+            Self::Nonce,
+            Self::XorRelayedAddress,
+            Self::RequestedTransport,
+            Self::DontFragment,
+            Self::XorMappedAddress,
+            Self::Priority,
+            Self::UseCandidate,
+            Self::Software,
+            Self::Fingerprint,
+            Self::IceControlled,
+            Self::IceControlling,
+            Self::ResponseOrigin,
+            Self::OtherAddress,
+        ] {
+            if x.bytes() == bytes {
                 return x;
             }
         }
-        Self::Unknown
+        Self::Unknown(u16::from_be_bytes(bytes))
+    }
+
+    pub fn value(&self) -> u16 {
+        u16::from_be_bytes(self.bytes())
+    }
+
+    /// Types 0x0000-0x7FFF must be understood by the receiver, types
+    /// 0x8000-0xFFFF may be ignored.
+    pub fn is_comprehension_required(&self) -> bool {
+        self.value() < 0x8000
     }
 }
 
-#[derive(Debug)]
-pub struct Attribute {
-    pub attrib_type: AttributeType,
-    pub value: Bytes,
+#[derive(PartialEq, Clone, Debug)]
+pub enum Attribute {
+    MappedAddress(SocketAddr),
+    /// Ask for the response to come from a different IP and/or port
+    ChangeRequest {
+        change_ip: bool,
+        change_port: bool,
+    },
+    Username(String),
+    MessageIntegrity([u8; 20]),
+    /// Code (300-699) and reason phrase
+    ErrorCode(u16, String),
+    UnknownAttributes(Vec<u16>),
+    ChannelNumber(u16),
+    /// Seconds
+    Lifetime(u32),
+    XorPeerAddress(SocketAddr),
+    Data(Bytes),
+    Realm(String),
+    Nonce(String),
+    XorRelayedAddress(SocketAddr),
+    /// IP protocol number, 17 for UDP
+    RequestedTransport(u8),
+    DontFragment,
+    XorMappedAddress(SocketAddr),
+    /// ICE priority of the peer-reflexive candidate the check would create
+    Priority(u32),
+    /// The controlling agent nominates the pair
+    UseCandidate,
+    Software(String),
+    Fingerprint(u32),
+    /// Tie-breaker of an agent which thinks it is controlled
+    IceControlled(u64),
+    /// Tie-breaker of an agent which thinks it is controlling
+    IceControlling(u64),
+    /// Where a response was sent from
+    ResponseOrigin(SocketAddr),
+    /// The server's alternate IP and port
+    OtherAddress(SocketAddr),
+    /// An attribute we do not understand, kept as is
+    Unknown(u16, Bytes),
+    /// A registered attribute
+    Custom(Arc<dyn CustomValue>),
 }
 
 impl Attribute {
-    pub fn bytes(&self) -> Bytes {
+    pub fn attrib_type(&self) -> AttributeType {
+        match self {
+            Self::MappedAddress(_) => AttributeType::MappedAddress,
+            Self::ChangeRequest { .. } => AttributeType::ChangeRequest,
+            Self::Username(_) => AttributeType::Username,
+            Self::MessageIntegrity(_) => AttributeType::MessageIntegrity,
+            Self::ErrorCode(_, _) => AttributeType::ErrorCode,
+            Self::UnknownAttributes(_) => AttributeType::UnknownAttributes,
+            Self::ChannelNumber(_) => AttributeType::ChannelNumber,
+            Self::Lifetime(_) => AttributeType::Lifetime,
+            Self::XorPeerAddress(_) => AttributeType::XorPeerAddress,
+            Self::Data(_) => AttributeType::Data,
+            Self::Realm(_) => AttributeType::Realm,
+            Self::Nonce(_) => AttributeType::Nonce,
+            Self::XorRelayedAddress(_) => AttributeType::XorRelayedAddress,
+            Self::RequestedTransport(_) => AttributeType::RequestedTransport,
+            Self::DontFragment => AttributeType::DontFragment,
+            Self::XorMappedAddress(_) => AttributeType::XorMappedAddress,
+            Self::Priority(_) => AttributeType::Priority,
+            Self::UseCandidate => AttributeType::UseCandidate,
+            Self::Software(_) => AttributeType::Software,
+            Self::Fingerprint(_) => AttributeType::Fingerprint,
+            Self::IceControlled(_) => AttributeType::IceControlled,
+            Self::IceControlling(_) => AttributeType::IceControlling,
+            Self::ResponseOrigin(_) => AttributeType::ResponseOrigin,
+            Self::OtherAddress(_) => AttributeType::OtherAddress,
+            Self::Unknown(x, _) => AttributeType::Unknown(*x),
+            Self::Custom(x) => AttributeType::Custom(x.attrib_type()),
+        }
+    }
+
+    /// The attribute for a value of a registered type
+    pub fn custom<T: CustomAttribute>(value: T) -> Self {
+        Self::Custom(Arc::new(value))
+    }
+
+    /// The value, if this is a registered attribute of type `T`
+    pub fn as_custom<T: CustomAttribute>(&self) -> Option<&T> {
+        match self {
+            Self::Custom(x) => x.as_any().downcast_ref(),
+            _ => None,
+        }
+    }
+
+    /// The transaction `id` is needed for XOR-MAPPED-ADDRESS with IPv6.
+    pub fn bytes(&self, id: &[u8; 12]) -> Bytes {
+        let mut value = BytesMut::new();
+        match self {
+            Self::MappedAddress(addr) | Self::ResponseOrigin(addr) | Self::OtherAddress(addr) => {
+                put_address(&mut value, *addr)
+            }
+            Self::ChangeRequest {
+                change_ip,
+                change_port,
+            } => value.put_u32(
+                if *change_ip { CHANGE_IP } else { 0 } | if *change_port { CHANGE_PORT } else { 0 },
+            ),
+            Self::Username(s) | Self::Realm(s) | Self::Nonce(s) | Self::Software(s) => {
+                value.put_slice(s.as_bytes())
+            }
+            Self::MessageIntegrity(hmac) => value.put_slice(hmac),
+            Self::ErrorCode(code, reason) => {
+                value.put_u16(0x0000);
+                value.put_u8((code / 100) as u8);
+                value.put_u8((code % 100) as u8);
+                value.put_slice(reason.as_bytes());
+            }
+            Self::UnknownAttributes(types) => types.iter().for_each(|x| value.put_u16(*x)),
+            Self::ChannelNumber(x) => {
+                value.put_u16(*x);
+                value.put_u16(0x0000);
+            }
+            Self::Lifetime(x) => value.put_u32(*x),
+            Self::Data(data) => value.put_slice(data),
+            Self::RequestedTransport(x) => {
+                value.put_u8(*x);
+                value.put_slice(&[0x00; 3]);
+            }
+            Self::DontFragment | Self::UseCandidate => {}
+            Self::XorMappedAddress(addr)
+            | Self::XorPeerAddress(addr)
+            | Self::XorRelayedAddress(addr) => put_address(&mut value, xor_address(*addr, id)),
+            Self::Priority(x) => value.put_u32(*x),
+            Self::Fingerprint(crc) => value.put_u32(*crc),
+            Self::IceControlled(x) | Self::IceControlling(x) => value.put_u64(*x),
+            Self::Unknown(_, v) => value.put_slice(v),
+            Self::Custom(x) => x.encode(&mut value),
+        }
+
         let mut bytes = BytesMut::new();
-        bytes.put_slice(&self.attrib_type.bytes());
-        bytes.put_u16(self.value.len() as u16);
-        bytes.put_slice(&self.value);
+        bytes.put_slice(&self.attrib_type().bytes());
+        bytes.put_u16(value.len() as u16);
+        bytes.put(value);
 
         // Padding
-        while bytes.len() % 4 != 0 {
+        while !bytes.len().is_multiple_of(4) {
             log::trace!("Padding");
             bytes.put_u8(0x00);
         }
@@ -48,11 +281,185 @@ impl Attribute {
         bytes.into()
     }
 
-    pub fn from_bytes(bytes: &mut Bytes) -> Self {
-        let attrib_type = AttributeType::from_bytes(bytes.copy_to_bytes(2));
-        assert_ne!(attrib_type, AttributeType::Unknown);
-        let len = bytes.get_u16();
-        let value = bytes.copy_to_bytes(len as usize);
-        Self { attrib_type, value }
+    pub fn from_bytes(bytes: &mut Bytes, id: &[u8; 12]) -> Result<Self, ParseError> {
+        if bytes.remaining() < 4 {
+            return Err(ParseError::Truncated);
+        }
+        let attrib_type = AttributeType::from_bytes([bytes.get_u8(), bytes.get_u8()]);
+        let len = bytes.get_u16() as usize;
+        let padding = (4 - len % 4) % 4;
+        if bytes.remaining() < len + padding {
+            return Err(ParseError::Truncated);
+        }
+        let mut value = bytes.copy_to_bytes(len);
+        bytes.advance(padding);
+
+        let invalid = || ParseError::InvalidAttribute(attrib_type.value());
+        let string = |value: Bytes| String::from_utf8(value.to_vec()).map_err(|_| invalid());
+        let attribute = match attrib_type {
+            AttributeType::MappedAddress => {
+                Self::MappedAddress(get_address(&mut value).ok_or_else(invalid)?)
+            }
+            AttributeType::ChangeRequest => {
+                if len != 4 {
+                    return Err(invalid());
+                }
+                let flags = value.get_u32();
+                Self::ChangeRequest {
+                    change_ip: flags & CHANGE_IP != 0,
+                    change_port: flags & CHANGE_PORT != 0,
+                }
+            }
+            AttributeType::Username => Self::Username(string(value)?),
+            AttributeType::MessageIntegrity => {
+                Self::MessageIntegrity(value[..].try_into().map_err(|_| invalid())?)
+            }
+            AttributeType::ErrorCode => {
+                if len < 4 {
+                    return Err(invalid());
+                }
+                value.advance(2);
+                let class = (value.get_u8() & 0x07) as u16;
+                let number = value.get_u8() as u16;
+                if !(3..=6).contains(&class) || number > 99 {
+                    return Err(invalid());
+                }
+                Self::ErrorCode(class * 100 + number, string(value)?)
+            }
+            AttributeType::UnknownAttributes => {
+                if !len.is_multiple_of(2) {
+                    return Err(invalid());
+                }
+                let mut types = Vec::with_capacity(len / 2);
+                while value.has_remaining() {
+                    types.push(value.get_u16());
+                }
+                Self::UnknownAttributes(types)
+            }
+            AttributeType::ChannelNumber => {
+                if len != 4 {
+                    return Err(invalid());
+                }
+                Self::ChannelNumber(value.get_u16())
+            }
+            AttributeType::Lifetime => {
+                if len != 4 {
+                    return Err(invalid());
+                }
+                Self::Lifetime(value.get_u32())
+            }
+            AttributeType::XorPeerAddress => {
+                let addr = get_address(&mut value).ok_or_else(invalid)?;
+                Self::XorPeerAddress(xor_address(addr, id))
+            }
+            AttributeType::Data => Self::Data(value),
+            AttributeType::Realm => Self::Realm(string(value)?),
+            AttributeType::Nonce => Self::Nonce(string(value)?),
+            AttributeType::XorRelayedAddress => {
+                let addr = get_address(&mut value).ok_or_else(invalid)?;
+                Self::XorRelayedAddress(xor_address(addr, id))
+            }
+            AttributeType::RequestedTransport => {
+                if len != 4 {
+                    return Err(invalid());
+                }
+                Self::RequestedTransport(value.get_u8())
+            }
+            AttributeType::DontFragment => Self::DontFragment,
+            AttributeType::XorMappedAddress => {
+                let addr = get_address(&mut value).ok_or_else(invalid)?;
+                Self::XorMappedAddress(xor_address(addr, id))
+            }
+            AttributeType::Priority => {
+                if len != 4 {
+                    return Err(invalid());
+                }
+                Self::Priority(value.get_u32())
+            }
+            AttributeType::UseCandidate => Self::UseCandidate,
+            AttributeType::Software => Self::Software(string(value)?),
+            AttributeType::Fingerprint => {
+                if len != 4 {
+                    return Err(invalid());
+                }
+                Self::Fingerprint(value.get_u32())
+            }
+            AttributeType::IceControlled | AttributeType::IceControlling => {
+                if len != 8 {
+                    return Err(invalid());
+                }
+                let tie_breaker = value.get_u64();
+                if attrib_type == AttributeType::IceControlled {
+                    Self::IceControlled(tie_breaker)
+                } else {
+                    Self::IceControlling(tie_breaker)
+                }
+            }
+            AttributeType::ResponseOrigin => {
+                Self::ResponseOrigin(get_address(&mut value).ok_or_else(invalid)?)
+            }
+            AttributeType::OtherAddress => {
+                Self::OtherAddress(get_address(&mut value).ok_or_else(invalid)?)
+            }
+            AttributeType::Unknown(x) => Self::Unknown(x, value),
+            // Optional, so a bad value is no reason to reject the message
+            AttributeType::Custom(x) => match registry::decode(x, &value) {
+                Some(attribute) => attribute,
+                None => {
+                    log::debug!("Invalid attribute {:#06x}, kept as unknown", x);
+                    Self::Unknown(x, value)
+                }
+            },
+        };
+        Ok(attribute)
+    }
+}
+
+/// Write the value of a MAPPED-ADDRESS style attribute.
+pub(crate) fn put_address(bytes: &mut impl BufMut, addr: SocketAddr) {
+    bytes.put_u8(0x00);
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            bytes.put_u8(FAMILY_IPV4);
+            bytes.put_u16(addr.port());
+            bytes.put_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            bytes.put_u8(FAMILY_IPV6);
+            bytes.put_u16(addr.port());
+            bytes.put_slice(&ip.octets());
+        }
+    }
+}
+
+/// Read the value of a MAPPED-ADDRESS style attribute.
+pub(crate) fn get_address(mut bytes: impl Buf) -> Option<SocketAddr> {
+    if bytes.remaining() < 4 {
+        return None;
     }
+    let _ = bytes.get_u8();
+    let family = bytes.get_u8();
+    let port = bytes.get_u16();
+    let ip = match (family, bytes.remaining()) {
+        (FAMILY_IPV4, 4) => IpAddr::V4(Ipv4Addr::from(bytes.get_u32())),
+        (FAMILY_IPV6, 16) => IpAddr::V6(Ipv6Addr::from(bytes.get_u128())),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+/// XOR an address with the magic cookie (and the transaction id for IPv6).
+/// Applying this twice gives back the original address.
+pub(crate) fn xor_address(addr: SocketAddr, id: &[u8; 12]) -> SocketAddr {
+    let port = addr.port() ^ (MAGIC_COOKIE >> 16) as u16;
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) => IpAddr::V4(Ipv4Addr::from(u32::from(ip) ^ MAGIC_COOKIE)),
+        IpAddr::V6(ip) => {
+            let mut key = [0; 16];
+            key[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+            key[4..].copy_from_slice(id);
+            IpAddr::V6(Ipv6Addr::from(u128::from(ip) ^ u128::from_be_bytes(key)))
+        }
+    };
+    SocketAddr::new(ip, port)
 }
@@ -0,0 +1,42 @@
+//! Telling apart what arrives on one UDP socket (RFC 7983)
+//!
+//! Once a hole is punched, the same socket carries STUN checks and
+//! application data. The first byte says which protocol a datagram belongs
+//! to. STUN must also have the magic cookie, so application data that happens
+//! to start with 0 to 3 is not taken for STUN.
+
+use crate::message::{HEADER_LEN, MAGIC_COOKIE};
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Protocol {
+    Stun,
+    Zrtp,
+    Dtls,
+    /// TURN ChannelData
+    ChannelData,
+    /// RTP or RTCP
+    Rtp,
+    /// Anything else, e.g. our own payloads
+    Other,
+}
+
+impl Protocol {
+    /// Classify a datagram by its first byte (RFC 7983 section 7)
+    pub fn of(datagram: &[u8]) -> Self {
+        match datagram.first() {
+            Some(0..=3) if is_stun(datagram) => Self::Stun,
+            Some(16..=19) => Self::Zrtp,
+            Some(20..=63) => Self::Dtls,
+            Some(64..=79) => Self::ChannelData,
+            Some(128..=191) => Self::Rtp,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// Whether a datagram looks like a STUN message, without decoding it
+pub fn is_stun(datagram: &[u8]) -> bool {
+    datagram.len() >= HEADER_LEN
+        && datagram[0] & 0xC0 == 0
+        && datagram[4..8] == MAGIC_COOKIE.to_be_bytes()
+}
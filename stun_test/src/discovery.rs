@@ -0,0 +1,425 @@
+//! NAT behaviour discovery (RFC 5780 section 4)
+//!
+//! Needs a server with two IPs and two ports, which advertises the other one
+//! in OTHER-ADDRESS. This gives a second opinion on what the udp_nat_trav
+//! rendezvous server concludes in its alpha and beta tests.
+//!
+//! Ordinary servers at different IPs give a rougher answer: if they see
+//! different public addresses for the same socket, the mapping is not
+//! endpoint independent.
+
+use std::{cmp::Reverse, fs, io, net::SocketAddr, path::Path, sync::Arc, time::Duration};
+
+use rand::random;
+use tokio::task::JoinSet;
+
+use crate::{
+    attribure::{Attribute, AttributeType},
+    error::{DiscoveryError, TransactionError},
+    message::{Message, MessageClass, MessageMethod},
+    transaction::{AsyncClient, Response, TransactionConfig},
+};
+
+/// For the filtering tests, where no response is an answer rather than a
+/// failure: gives up after 1.75s instead of the RFC 5389 39.5s
+const FILTERING_CONFIG: TransactionConfig = TransactionConfig {
+    rto: Duration::from_millis(250),
+    rc: 3,
+    rm: 4,
+    ti: Duration::from_millis(1750),
+};
+
+/// How the NAT picks our public address for different destinations
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum MappingBehaviour {
+    /// Same mapping for every destination
+    EndpointIndependent,
+    /// A new mapping for each destination IP
+    AddressDependent,
+    /// A new mapping for each destination IP and port
+    AddressAndPortDependent,
+}
+
+/// Which sources the NAT lets through to an existing mapping
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum FilteringBehaviour {
+    /// Anybody
+    EndpointIndependent,
+    /// Only IPs we have sent to
+    AddressDependent,
+    /// Only IPs and ports we have sent to
+    AddressAndPortDependent,
+}
+
+#[derive(Debug)]
+pub struct Discovery {
+    /// Our address as seen by the server's primary address
+    pub mapped_address: SocketAddr,
+    /// The server's alternate address
+    pub other_address: SocketAddr,
+    pub mapping: MappingBehaviour,
+    pub filtering: FilteringBehaviour,
+}
+
+/// What one of several servers saw
+#[derive(Debug)]
+pub struct ServerReport {
+    pub server: SocketAddr,
+    /// Our address as seen by the server
+    pub result: Result<SocketAddr, DiscoveryError>,
+}
+
+#[derive(Debug)]
+pub struct Consensus {
+    /// In the order the servers were given
+    pub reports: Vec<ServerReport>,
+}
+
+impl Consensus {
+    /// The addresses seen, with how many servers saw each, most seen first
+    pub fn addresses(&self) -> Vec<(SocketAddr, usize)> {
+        let mut addresses: Vec<(SocketAddr, usize)> = Vec::new();
+        for addr in self.reports.iter().filter_map(|r| r.result.as_ref().ok()) {
+            match addresses.iter_mut().find(|(x, _)| x == addr) {
+                Some((_, count)) => *count += 1,
+                None => addresses.push((*addr, 1)),
+            }
+        }
+        addresses.sort_by_key(|(_, count)| Reverse(*count));
+        addresses
+    }
+
+    /// The address seen by the most servers, if any answered
+    pub fn mapped_address(&self) -> Option<SocketAddr> {
+        self.addresses().first().map(|(addr, _)| *addr)
+    }
+
+    /// Whether the servers which answered all saw the same address
+    pub fn agrees(&self) -> bool {
+        self.addresses().len() <= 1
+    }
+
+    /// What the answers say about the mapping. None if they cannot tell,
+    /// e.g. fewer than two servers answered, or those which did share an IP
+    /// and agree.
+    pub fn mapping(&self) -> Option<MappingBehaviour> {
+        let answers: Vec<(SocketAddr, SocketAddr)> = self
+            .reports
+            .iter()
+            .filter_map(|r| Some((r.server, *r.result.as_ref().ok()?)))
+            .collect();
+        let pairs = || {
+            answers.iter().enumerate().flat_map(|(i, a)| {
+                answers[i + 1..]
+                    .iter()
+                    .filter(move |b| b.0 != a.0)
+                    .map(move |b| (a, b))
+            })
+        };
+
+        // Different ports on the same server IP
+        if pairs().any(|(a, b)| a.0.ip() == b.0.ip() && a.1 != b.1) {
+            Some(MappingBehaviour::AddressAndPortDependent)
+        } else if pairs().any(|(a, b)| a.1 != b.1) {
+            Some(MappingBehaviour::AddressDependent)
+        } else if pairs().any(|(a, b)| a.0.ip() != b.0.ip()) {
+            Some(MappingBehaviour::EndpointIndependent)
+        } else {
+            None
+        }
+    }
+}
+
+/// Ask every server for our address at once, from the client's socket.
+/// Servers which do not answer are reported as such.
+pub async fn query_all(client: &Arc<AsyncClient>, servers: &[SocketAddr]) -> Consensus {
+    let mut join_set = JoinSet::new();
+    for (i, server) in servers.iter().copied().enumerate() {
+        let client = client.clone();
+        join_set.spawn(async move {
+            let result = binding(&client, server, None)
+                .await
+                .and_then(|response| get_mapped_address(&response));
+            log::debug!("{} sees {:?}", server, result);
+            (i, ServerReport { server, result })
+        });
+    }
+
+    let mut reports = Vec::new();
+    while let Some(res) = join_set.join_next().await {
+        reports.push(res.expect("query task panicked"));
+    }
+    reports.sort_by_key(|(i, _)| *i);
+    Consensus {
+        reports: reports.into_iter().map(|(_, report)| report).collect(),
+    }
+}
+
+/// Read a file of servers, one `host:port` per line. Blank lines and lines
+/// starting with `#` are skipped.
+pub fn load_servers(path: &Path) -> io::Result<Vec<String>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+/// Run the mapping and filtering tests against `server`.
+pub async fn discover(
+    client: &AsyncClient,
+    server: SocketAddr,
+) -> Result<Discovery, DiscoveryError> {
+    // Test I
+    let response = binding(client, server, None).await?;
+    let mapped_address = get_mapped_address(&response)?;
+    let other_address = match response.message.attribute(AttributeType::OtherAddress) {
+        Some(Attribute::OtherAddress(addr)) => *addr,
+        _ => return Err(DiscoveryError::NoOtherAddress),
+    };
+    log::debug!("Test I: {} via {}", mapped_address, server);
+
+    let mapping = mapping(client, server, other_address, mapped_address).await?;
+    let filtering = filtering(client, server, other_address).await?;
+
+    Ok(Discovery {
+        mapped_address,
+        other_address,
+        mapping,
+        filtering,
+    })
+}
+
+async fn mapping(
+    client: &AsyncClient,
+    server: SocketAddr,
+    other_address: SocketAddr,
+    mapped_address: SocketAddr,
+) -> Result<MappingBehaviour, DiscoveryError> {
+    // Test II: alternate IP, primary port
+    let addr = SocketAddr::new(other_address.ip(), server.port());
+    let mapped_address_2 = get_mapped_address(&binding(client, addr, None).await?)?;
+    log::debug!("Mapping test II: {} via {}", mapped_address_2, addr);
+    if mapped_address_2 == mapped_address {
+        return Ok(MappingBehaviour::EndpointIndependent);
+    }
+
+    // Test III: alternate IP and port
+    let mapped_address_3 = get_mapped_address(&binding(client, other_address, None).await?)?;
+    log::debug!(
+        "Mapping test III: {} via {}",
+        mapped_address_3,
+        other_address
+    );
+    if mapped_address_3 == mapped_address_2 {
+        Ok(MappingBehaviour::AddressDependent)
+    } else {
+        Ok(MappingBehaviour::AddressAndPortDependent)
+    }
+}
+
+async fn filtering(
+    client: &AsyncClient,
+    server: SocketAddr,
+    other_address: SocketAddr,
+) -> Result<FilteringBehaviour, DiscoveryError> {
+    // Test II: ask for the response from the alternate IP and port
+    let response = binding_if_any(client, server, Some((true, true))).await?;
+    log::debug!("Filtering test II: {:?}", response.as_ref().map(|r| r.from));
+    if let Some(response) = response {
+        check_origin(&response, other_address);
+        return Ok(FilteringBehaviour::EndpointIndependent);
+    }
+
+    // Test III: ask for the response from the alternate port
+    let response = binding_if_any(client, server, Some((false, true))).await?;
+    log::debug!(
+        "Filtering test III: {:?}",
+        response.as_ref().map(|r| r.from)
+    );
+    match response {
+        Some(response) => {
+            check_origin(
+                &response,
+                SocketAddr::new(server.ip(), other_address.port()),
+            );
+            Ok(FilteringBehaviour::AddressDependent)
+        }
+        None => Ok(FilteringBehaviour::AddressAndPortDependent),
+    }
+}
+
+/// A Binding transaction, optionally with CHANGE-REQUEST (change IP, change
+/// port). Error responses are errors.
+async fn binding(
+    client: &AsyncClient,
+    server: SocketAddr,
+    change: Option<(bool, bool)>,
+) -> Result<Response, DiscoveryError> {
+    binding_with(client, server, change, client.config()).await
+}
+
+async fn binding_with(
+    client: &AsyncClient,
+    server: SocketAddr,
+    change: Option<(bool, bool)>,
+    config: TransactionConfig,
+) -> Result<Response, DiscoveryError> {
+    let mut attributes = Vec::new();
+    if let Some((change_ip, change_port)) = change {
+        attributes.push(Attribute::ChangeRequest {
+            change_ip,
+            change_port,
+        });
+    }
+    let request = Message {
+        method: MessageMethod::Binding,
+        class: MessageClass::Request,
+        id: random(),
+        attributes,
+    };
+
+    let response = client
+        .transaction_with(request.bytes(), server, config)
+        .await?;
+    if response.message.class == MessageClass::ResponseFailure {
+        let code = match response.message.attribute(AttributeType::ErrorCode) {
+            Some(Attribute::ErrorCode(code, _)) => *code,
+            _ => 0,
+        };
+        return Err(DiscoveryError::ErrorResponse(code));
+    }
+    Ok(response)
+}
+
+/// Like [`binding`], but no response is not an error, and does not take as
+/// long.
+async fn binding_if_any(
+    client: &AsyncClient,
+    server: SocketAddr,
+    change: Option<(bool, bool)>,
+) -> Result<Option<Response>, DiscoveryError> {
+    match binding_with(client, server, change, FILTERING_CONFIG).await {
+        Ok(response) => Ok(Some(response)),
+        Err(DiscoveryError::Transaction(TransactionError::Timeout)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn get_mapped_address(response: &Response) -> Result<SocketAddr, DiscoveryError> {
+    response
+        .message
+        .mapped_address()
+        .ok_or(DiscoveryError::NoMappedAddress)
+}
+
+/// A server which ignores CHANGE-REQUEST makes filtering look more open than
+/// it is.
+fn check_origin(response: &Response, expected: SocketAddr) {
+    if response.from != expected {
+        log::warn!(
+            "Response came from {}, expected {}. Does the server honour CHANGE-REQUEST?",
+            response.from,
+            expected
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server;
+    use tokio::net::UdpSocket;
+
+    fn report(server: &str, result: Option<&str>) -> ServerReport {
+        ServerReport {
+            server: server.parse().unwrap(),
+            result: result
+                .map(|addr| addr.parse().unwrap())
+                .ok_or(DiscoveryError::NoMappedAddress),
+        }
+    }
+
+    #[tokio::test]
+    async fn query_all_on_loopback() {
+        let mut servers = Vec::new();
+        let mut tasks = JoinSet::new();
+        for ip in ["127.0.0.1", "127.0.0.2", "127.0.0.1"] {
+            let socket = UdpSocket::bind((ip, 0)).await.unwrap();
+            servers.push(socket.local_addr().unwrap());
+            tasks.spawn(server::run_udp(socket));
+        }
+        // Bound, so nothing answers, not even with ICMP
+        let dead = UdpSocket::bind("127.0.0.3:0").await.unwrap();
+        servers.insert(1, dead.local_addr().unwrap());
+
+        let config = TransactionConfig {
+            rto: Duration::from_millis(100),
+            rc: 3,
+            rm: 2,
+            ..Default::default()
+        };
+        let client = AsyncClient::new(UdpSocket::bind("127.0.0.1:0").await.unwrap(), config);
+        let local = client.socket().local_addr().unwrap();
+        let consensus = query_all(&Arc::new(client), &servers).await;
+
+        let order: Vec<_> = consensus.reports.iter().map(|r| r.server).collect();
+        assert_eq!(order, servers);
+        assert!(matches!(
+            consensus.reports[1].result,
+            Err(DiscoveryError::Transaction(TransactionError::Timeout))
+        ));
+        for report in [0, 2, 3].map(|i| &consensus.reports[i]) {
+            assert_eq!(report.result.as_ref().unwrap(), &local);
+        }
+
+        assert_eq!(consensus.addresses(), vec![(local, 3)]);
+        assert_eq!(consensus.mapped_address(), Some(local));
+        assert!(consensus.agrees());
+        assert_eq!(
+            consensus.mapping(),
+            Some(MappingBehaviour::EndpointIndependent)
+        );
+
+        tasks.shutdown().await;
+    }
+
+    #[test]
+    fn mapping_from_reports() {
+        let consensus = |reports| Consensus { reports };
+
+        // Different IPs see different ports
+        let c = consensus(vec![
+            report("192.0.2.1:3478", Some("198.51.100.1:1000")),
+            report("192.0.2.2:3478", Some("198.51.100.1:1001")),
+            report("192.0.2.3:3478", None),
+        ]);
+        assert!(!c.agrees());
+        assert_eq!(c.mapping(), Some(MappingBehaviour::AddressDependent));
+
+        // Two ports of one IP see different ports
+        let c = consensus(vec![
+            report("192.0.2.1:3478", Some("198.51.100.1:1000")),
+            report("192.0.2.1:3479", Some("198.51.100.1:1001")),
+            report("192.0.2.2:3478", Some("198.51.100.1:1001")),
+        ]);
+        assert_eq!(c.addresses()[0], ("198.51.100.1:1001".parse().unwrap(), 2));
+        assert_eq!(c.mapping(), Some(MappingBehaviour::AddressAndPortDependent));
+
+        // One IP only, or only one answer, cannot tell
+        let c = consensus(vec![
+            report("192.0.2.1:3478", Some("198.51.100.1:1000")),
+            report("192.0.2.1:3479", Some("198.51.100.1:1000")),
+        ]);
+        assert!(c.agrees());
+        assert_eq!(c.mapping(), None);
+        let c = consensus(vec![
+            report("192.0.2.1:3478", Some("198.51.100.1:1000")),
+            report("192.0.2.2:3478", None),
+        ]);
+        assert_eq!(c.mapping(), None);
+
+        assert_eq!(consensus(Vec::new()).mapped_address(), None);
+    }
+}
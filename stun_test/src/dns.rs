@@ -0,0 +1,614 @@
+//! Finding STUN servers through DNS SRV records (RFC 5389 section 9)
+//!
+//! A minimal DNS client over UDP: SRV and A queries to one resolver, which
+//! is the system's by default but can be e.g. a local stand-in in tests.
+//! Targets are ordered by priority and weight as in RFC 2782, for the client
+//! to try in turn.
+
+use std::{
+    fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use bytes::{BufMut, BytesMut};
+use rand::{random, Rng};
+use tokio::{
+    net::UdpSocket,
+    time::{timeout_at, Instant},
+};
+
+use crate::error::DnsError;
+
+/// Port to use when a domain has no SRV records
+pub const DEFAULT_PORT: u16 = 3478;
+
+const TYPE_A: u16 = 1;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+const HEADER_LEN: usize = 12;
+/// Recursion desired
+const FLAG_RD: u16 = 0x0100;
+const FLAG_QR: u16 = 0x8000;
+const FLAG_TC: u16 = 0x0200;
+const RCODE_NXDOMAIN: u8 = 3;
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct SrvRecord {
+    /// Lower is tried first
+    pub priority: u16,
+    /// Share of the load among records of the same priority
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+/// A record of a response, with its owner name
+#[derive(Debug)]
+struct Record {
+    name: String,
+    data: RecordData,
+}
+
+#[derive(Debug)]
+enum RecordData {
+    Srv(SrvRecord),
+    A(Ipv4Addr),
+    Other,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Resolver {
+    pub addr: SocketAddr,
+    /// How long to wait for each response
+    pub timeout: Duration,
+    /// Total number of sends of a query
+    pub attempts: u32,
+}
+
+impl Resolver {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            timeout: Duration::from_secs(2),
+            attempts: 3,
+        }
+    }
+
+    /// The first nameserver in /etc/resolv.conf
+    pub fn system() -> io::Result<Self> {
+        fs::read_to_string("/etc/resolv.conf")?
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("nameserver"))
+            .find_map(|ip| ip.trim().parse::<IpAddr>().ok())
+            .map(|ip| Self::new(SocketAddr::new(ip, 53)))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no nameserver in resolv.conf"))
+    }
+
+    /// Addresses of the STUN servers for `domain`, in the order to try them.
+    /// Without SRV records, the domain itself on the default port.
+    pub async fn stun_servers(&self, domain: &str, tcp: bool) -> Result<Vec<SocketAddr>, DnsError> {
+        let name = format!("_stun._{}.{}", if tcp { "tcp" } else { "udp" }, domain);
+        let records = match self.query(&name, TYPE_SRV).await {
+            Ok(records) => records,
+            Err(DnsError::NoSuchName) => Vec::new(),
+            Err(e) => return Err(e),
+        };
+
+        let srv: Vec<SrvRecord> = records
+            .iter()
+            .filter_map(|r| match &r.data {
+                RecordData::Srv(srv) if r.name.eq_ignore_ascii_case(&name) => Some(srv.clone()),
+                _ => None,
+            })
+            .collect();
+        if srv.is_empty() {
+            log::debug!("No SRV records for {}, using {}", name, domain);
+            let ips = self.ipv4(domain).await?;
+            return Ok(ips
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip.into(), DEFAULT_PORT))
+                .collect());
+        }
+        // "." means the service is not offered
+        if srv.len() == 1 && srv[0].target.is_empty() {
+            return Err(DnsError::NoRecords);
+        }
+
+        let mut addrs = Vec::new();
+        for srv in order(srv) {
+            // Resolvers usually send the addresses of the targets along
+            let mut ips: Vec<Ipv4Addr> = records
+                .iter()
+                .filter_map(|r| match r.data {
+                    RecordData::A(ip) if r.name.eq_ignore_ascii_case(&srv.target) => Some(ip),
+                    _ => None,
+                })
+                .collect();
+            if ips.is_empty() {
+                ips = match self.ipv4(&srv.target).await {
+                    Ok(ips) => ips,
+                    Err(e) => {
+                        log::warn!("Failed to resolve {}: {}", srv.target, e);
+                        continue;
+                    }
+                };
+            }
+            log::debug!("{} -> {:?} port {}", srv.target, ips, srv.port);
+            addrs.extend(
+                ips.into_iter()
+                    .map(|ip| SocketAddr::new(ip.into(), srv.port)),
+            );
+        }
+        if addrs.is_empty() {
+            return Err(DnsError::NoRecords);
+        }
+        Ok(addrs)
+    }
+
+    /// The SRV records of `name`, unordered
+    pub async fn srv(&self, name: &str) -> Result<Vec<SrvRecord>, DnsError> {
+        let records = self.query(name, TYPE_SRV).await?;
+        Ok(records
+            .into_iter()
+            .filter_map(|r| match r.data {
+                RecordData::Srv(srv) => Some(srv),
+                _ => None,
+            })
+            .collect())
+    }
+
+    pub async fn ipv4(&self, name: &str) -> Result<Vec<Ipv4Addr>, DnsError> {
+        let ips: Vec<Ipv4Addr> = self
+            .query(name, TYPE_A)
+            .await?
+            .into_iter()
+            .filter_map(|r| match r.data {
+                RecordData::A(ip) => Some(ip),
+                _ => None,
+            })
+            .collect();
+        if ips.is_empty() {
+            return Err(DnsError::NoRecords);
+        }
+        Ok(ips)
+    }
+
+    /// Every record of the answer and additional sections
+    async fn query(&self, name: &str, qtype: u16) -> Result<Vec<Record>, DnsError> {
+        let id: u16 = random();
+        let query = encode_query(id, name, qtype)?;
+        let bind: SocketAddr = match self.addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(bind).await?;
+        socket.connect(self.addr).await?;
+
+        let mut buf = vec![0; 65535];
+        for _ in 0..self.attempts {
+            socket.send(&query).await?;
+            let deadline = Instant::now() + self.timeout;
+            // Skip anything which is not the response to this query
+            while let Ok(len) = timeout_at(deadline, socket.recv(&mut buf)).await {
+                match decode_response(id, &buf[..len?]) {
+                    Ok(records) => return Ok(records),
+                    Err(DnsError::Malformed) => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Err(DnsError::Timeout)
+    }
+}
+
+/// Order SRV records for trying in turn: by priority, and within a
+/// priority by a weighted random draw (RFC 2782).
+pub fn order(mut records: Vec<SrvRecord>) -> Vec<SrvRecord> {
+    records.sort_by_key(|r| r.priority);
+    let mut ordered = Vec::with_capacity(records.len());
+    let mut rng = rand::thread_rng();
+    while !records.is_empty() {
+        let priority = records[0].priority;
+        let end = records
+            .iter()
+            .position(|r| r.priority != priority)
+            .unwrap_or(records.len());
+        let mut group: Vec<SrvRecord> = records.drain(..end).collect();
+        // Zero weights first, so they only have a small chance of being
+        // picked before the others
+        group.sort_by_key(|r| r.weight != 0);
+        while !group.is_empty() {
+            let total: u32 = group.iter().map(|r| r.weight as u32).sum();
+            let pick = rng.gen_range(0..=total);
+            let mut sum = 0;
+            let i = group
+                .iter()
+                .position(|r| {
+                    sum += r.weight as u32;
+                    sum >= pick
+                })
+                .unwrap_or(0);
+            ordered.push(group.remove(i));
+        }
+    }
+    ordered
+}
+
+fn encode_query(id: u16, name: &str, qtype: u16) -> Result<BytesMut, DnsError> {
+    let mut query = BytesMut::new();
+    query.put_u16(id);
+    query.put_u16(FLAG_RD);
+    // One question, no other records
+    query.put_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(DnsError::BadName(String::from(name)));
+        }
+        query.put_u8(label.len() as u8);
+        query.put_slice(label.as_bytes());
+    }
+    query.put_u8(0);
+    query.put_u16(qtype);
+    query.put_u16(CLASS_IN);
+    Ok(query)
+}
+
+/// Malformed if it is not the response to query `id`, as well as if it
+/// cannot be decoded.
+fn decode_response(id: u16, msg: &[u8]) -> Result<Vec<Record>, DnsError> {
+    let u16_at = |at: usize| -> Result<u16, DnsError> {
+        let bytes = msg.get(at..at + 2).ok_or(DnsError::Malformed)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+    let flags = u16_at(2)?;
+    if u16_at(0)? != id || flags & FLAG_QR == 0 {
+        return Err(DnsError::Malformed);
+    }
+    if flags & FLAG_TC != 0 {
+        log::warn!("DNS response truncated, using what arrived");
+    }
+    match (flags & 0x000F) as u8 {
+        0 => {}
+        RCODE_NXDOMAIN => return Err(DnsError::NoSuchName),
+        x => return Err(DnsError::ResponseCode(x)),
+    }
+
+    let questions = u16_at(4)?;
+    let records = u16_at(6)? as usize + u16_at(8)? as usize + u16_at(10)? as usize;
+    let mut offset = HEADER_LEN;
+    for _ in 0..questions {
+        offset = read_name(msg, offset)?.1 + 4;
+    }
+
+    let mut decoded = Vec::new();
+    for _ in 0..records {
+        let (name, end) = match read_name(msg, offset) {
+            Ok(x) => x,
+            // What fitted in a truncated response
+            Err(_) if flags & FLAG_TC != 0 => break,
+            Err(e) => return Err(e),
+        };
+        let rtype = u16_at(end)?;
+        let class = u16_at(end + 2)?;
+        let len = u16_at(end + 8)? as usize;
+        let start = end + 10;
+        let rdata = msg.get(start..start + len).ok_or(DnsError::Malformed)?;
+        offset = start + len;
+
+        let data = match (rtype, class) {
+            (TYPE_A, CLASS_IN) => {
+                let octets: [u8; 4] = rdata.try_into().map_err(|_| DnsError::Malformed)?;
+                RecordData::A(Ipv4Addr::from(octets))
+            }
+            (TYPE_SRV, CLASS_IN) if len > 6 => RecordData::Srv(SrvRecord {
+                priority: u16_at(start)?,
+                weight: u16_at(start + 2)?,
+                port: u16_at(start + 4)?,
+                target: read_name(msg, start + 6)?.0,
+            }),
+            _ => RecordData::Other,
+        };
+        decoded.push(Record { name, data });
+    }
+    Ok(decoded)
+}
+
+/// A possibly compressed name at `offset`, and where what follows it starts.
+/// The root is the empty string.
+fn read_name(msg: &[u8], mut offset: usize) -> Result<(String, usize), DnsError> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    // Enough for any name, but stops pointer loops
+    for _ in 0..128 {
+        let len = *msg.get(offset).ok_or(DnsError::Malformed)? as usize;
+        match len {
+            0 => {
+                return Ok((labels.join("."), end.unwrap_or(offset + 1)));
+            }
+            0xC0.. => {
+                let low = *msg.get(offset + 1).ok_or(DnsError::Malformed)? as usize;
+                end.get_or_insert(offset + 2);
+                offset = ((len & 0x3F) << 8) | low;
+            }
+            1..=63 => {
+                let label = msg
+                    .get(offset + 1..offset + 1 + len)
+                    .ok_or(DnsError::Malformed)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                offset += 1 + len;
+            }
+            _ => return Err(DnsError::Malformed),
+        }
+    }
+    Err(DnsError::Malformed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRV_NAME: &str = "_stun._udp.example.com";
+
+    fn name(name: &str) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        for label in name.split('.').filter(|label| !label.is_empty()) {
+            encoded.push(label.len() as u8);
+            encoded.extend_from_slice(label.as_bytes());
+        }
+        encoded.push(0);
+        encoded
+    }
+
+    fn srv(priority: u16, weight: u16, port: u16, target: &[u8]) -> Vec<u8> {
+        let mut rdata = Vec::new();
+        for x in [priority, weight, port] {
+            rdata.extend_from_slice(&x.to_be_bytes());
+        }
+        rdata.extend_from_slice(target);
+        rdata
+    }
+
+    fn record(owner: &[u8], rtype: u16, rdata: &[u8]) -> Vec<u8> {
+        let mut record = owner.to_vec();
+        record.extend_from_slice(&rtype.to_be_bytes());
+        record.extend_from_slice(&CLASS_IN.to_be_bytes());
+        record.extend_from_slice(&300u32.to_be_bytes());
+        record.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        record.extend_from_slice(rdata);
+        record
+    }
+
+    /// A response to query `id` for `qname` with `answers`, which may use
+    /// pointers to the question name at offset 12
+    fn response(id: u16, flags: u16, qname: &str, qtype: u16, answers: &[Vec<u8>]) -> Vec<u8> {
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&id.to_be_bytes());
+        msg.extend_from_slice(&(FLAG_QR | flags).to_be_bytes());
+        for count in [1, answers.len() as u16, 0, 0] {
+            msg.extend_from_slice(&count.to_be_bytes());
+        }
+        msg.extend_from_slice(&name(qname));
+        msg.extend_from_slice(&qtype.to_be_bytes());
+        msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+        for answer in answers {
+            msg.extend_from_slice(answer);
+        }
+        msg
+    }
+
+    fn srv_records(records: &[Record]) -> Vec<SrvRecord> {
+        records
+            .iter()
+            .filter_map(|r| match &r.data {
+                RecordData::Srv(srv) => Some(srv.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn compression_pointers() {
+        // The owner is the question name, and the target ends with its
+        // "example.com", after "_stun" and "_udp"
+        let target = [&[4][..], b"stun", &[0xC0, 12 + 6 + 5]].concat();
+        let answers = [
+            record(&[0xC0, 12], TYPE_SRV, &srv(10, 5, 3478, &target)),
+            record(&target, TYPE_A, &[192, 0, 2, 1]),
+        ];
+        let msg = response(7, 0, SRV_NAME, TYPE_SRV, &answers);
+
+        let records = decode_response(7, &msg).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].name, SRV_NAME);
+        assert_eq!(
+            srv_records(&records),
+            vec![SrvRecord {
+                priority: 10,
+                weight: 5,
+                port: 3478,
+                target: String::from("stun.example.com"),
+            }]
+        );
+        assert_eq!(records[1].name, "stun.example.com");
+        assert!(matches!(records[1].data, RecordData::A(ip) if ip == Ipv4Addr::new(192, 0, 2, 1)));
+    }
+
+    #[test]
+    fn pointer_loop() {
+        // Two pointers to each other
+        assert!(matches!(
+            read_name(&[0xC0, 2, 0xC0, 0], 0),
+            Err(DnsError::Malformed)
+        ));
+
+        // The owner of an answer points to itself
+        let owner = response(7, 0, SRV_NAME, TYPE_SRV, &[]).len() as u8;
+        let answer = record(&[0xC0, owner], TYPE_A, &[192, 0, 2, 1]);
+        let msg = response(7, 0, SRV_NAME, TYPE_SRV, &[answer]);
+        assert!(matches!(decode_response(7, &msg), Err(DnsError::Malformed)));
+    }
+
+    #[test]
+    fn truncated() {
+        let first = record(&name("example.com"), TYPE_A, &[192, 0, 2, 1]);
+        let second = record(&name("example.com"), TYPE_A, &[192, 0, 2, 2]);
+        let cut = |msg: Vec<u8>| msg[..msg.len() - second.len() + 3].to_vec();
+
+        // What arrived is used
+        let answers = [first.clone(), second.clone()];
+        let msg = cut(response(7, FLAG_TC, "example.com", TYPE_A, &answers));
+        let records = decode_response(7, &msg).unwrap();
+        assert_eq!(records.len(), 1);
+
+        // Without TC it is malformed
+        let msg = cut(response(7, 0, "example.com", TYPE_A, &answers));
+        assert!(matches!(decode_response(7, &msg), Err(DnsError::Malformed)));
+    }
+
+    #[test]
+    fn response_codes() {
+        let msg = response(7, RCODE_NXDOMAIN as u16, SRV_NAME, TYPE_SRV, &[]);
+        assert!(matches!(
+            decode_response(7, &msg),
+            Err(DnsError::NoSuchName)
+        ));
+        // SERVFAIL
+        let msg = response(7, 2, SRV_NAME, TYPE_SRV, &[]);
+        assert!(matches!(
+            decode_response(7, &msg),
+            Err(DnsError::ResponseCode(2))
+        ));
+        // Not the response to our query
+        assert!(matches!(decode_response(8, &msg), Err(DnsError::Malformed)));
+    }
+
+    #[test]
+    fn root_target() {
+        let answer = record(&[0xC0, 12], TYPE_SRV, &srv(0, 0, 0, &[0]));
+        let msg = response(7, 0, SRV_NAME, TYPE_SRV, &[answer]);
+        let records = decode_response(7, &msg).unwrap();
+        assert_eq!(srv_records(&records)[0].target, "");
+    }
+
+    fn srv_record(priority: u16, weight: u16, target: &str) -> SrvRecord {
+        SrvRecord {
+            priority,
+            weight,
+            port: 3478,
+            target: String::from(target),
+        }
+    }
+
+    #[test]
+    fn order_by_priority_then_weight() {
+        let records = vec![
+            srv_record(20, 0, "c"),
+            srv_record(10, 0, "a-zero"),
+            srv_record(30, 1, "d"),
+            srv_record(10, 100, "a-heavy"),
+            srv_record(20, 0, "c-zero"),
+        ];
+
+        let mut zero_first = 0;
+        for _ in 0..1000 {
+            let ordered = order(records.clone());
+            let priorities: Vec<_> = ordered.iter().map(|r| r.priority).collect();
+            assert_eq!(priorities, [10, 10, 20, 20, 30]);
+            if ordered[0].target == "a-zero" {
+                zero_first += 1;
+            }
+            // Zero weights only: every one of them still comes out
+            let mut group: Vec<_> = ordered[2..4].iter().map(|r| r.target.as_str()).collect();
+            group.sort_unstable();
+            assert_eq!(group, ["c", "c-zero"]);
+        }
+        // About 1 in 101
+        assert!(zero_first < 50, "zero weight first {} times", zero_first);
+    }
+
+    /// Name, type and the answers, for a stand-in to serve
+    type Zone = Vec<(&'static str, u16, Vec<Vec<u8>>)>;
+
+    /// A DNS server answering from `zone`, and NXDOMAIN for anything else
+    async fn stand_in(zone: Zone) -> Resolver {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut resolver = Resolver::new(socket.local_addr().unwrap());
+        resolver.timeout = Duration::from_millis(500);
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let query = &buf[..len];
+                let id = u16::from_be_bytes([query[0], query[1]]);
+                let (qname, end) = read_name(query, HEADER_LEN).unwrap();
+                let qtype = u16::from_be_bytes([query[end], query[end + 1]]);
+                let msg = match zone.iter().find(|(n, t, _)| *n == qname && *t == qtype) {
+                    Some((_, _, answers)) => response(id, 0, &qname, qtype, answers),
+                    None => response(id, RCODE_NXDOMAIN as u16, &qname, qtype, &[]),
+                };
+                socket.send_to(&msg, from).await.unwrap();
+            }
+        });
+        resolver
+    }
+
+    #[tokio::test]
+    async fn stun_servers_from_stand_in() {
+        // No SRV records: the domain's A records on the default port
+        let a = |ip: [u8; 4]| record(&[0xC0, 12], TYPE_A, &ip);
+        let resolver = stand_in(vec![("example.com", TYPE_A, vec![a([192, 0, 2, 1])])]).await;
+        assert_eq!(
+            resolver.stun_servers("example.com", false).await.unwrap(),
+            vec!["192.0.2.1:3478".parse::<SocketAddr>().unwrap()]
+        );
+
+        // SRV records by priority, with the address of one target sent along
+        // and the other's looked up
+        let resolver = stand_in(vec![
+            (
+                SRV_NAME,
+                TYPE_SRV,
+                vec![
+                    record(
+                        &[0xC0, 12],
+                        TYPE_SRV,
+                        &srv(20, 0, 3479, &name("b.example.com")),
+                    ),
+                    record(
+                        &[0xC0, 12],
+                        TYPE_SRV,
+                        &srv(10, 0, 3480, &name("a.example.com")),
+                    ),
+                    record(&name("a.example.com"), TYPE_A, &[192, 0, 2, 10]),
+                ],
+            ),
+            ("b.example.com", TYPE_A, vec![a([192, 0, 2, 20])]),
+        ])
+        .await;
+        assert_eq!(
+            resolver.stun_servers("example.com", false).await.unwrap(),
+            vec![
+                "192.0.2.10:3480".parse::<SocketAddr>().unwrap(),
+                "192.0.2.20:3479".parse().unwrap(),
+            ]
+        );
+
+        // "." means no service
+        let resolver = stand_in(vec![(
+            SRV_NAME,
+            TYPE_SRV,
+            vec![record(&[0xC0, 12], TYPE_SRV, &srv(0, 0, 0, &[0]))],
+        )])
+        .await;
+        assert!(matches!(
+            resolver.stun_servers("example.com", false).await,
+            Err(DnsError::NoRecords)
+        ));
+        // Neither SRV nor A records
+        assert!(matches!(
+            resolver.stun_servers("example.org", false).await,
+            Err(DnsError::NoSuchName)
+        ));
+    }
+}
@@ -0,0 +1,237 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    io,
+};
+
+use crate::attribure::AttributeType;
+
+#[derive(PartialEq, Debug)]
+pub enum ParseError {
+    /// Ran out of bytes part way through a header or value
+    Truncated,
+    /// The two most significant bits were not zero
+    NotStun,
+    BadMagicCookie(u32),
+    /// The length field did not match the bytes received
+    BadLength(usize),
+    UnknownMethod(u16),
+    /// The value of the attribute with this type could not be decoded
+    InvalidAttribute(u16),
+    BadFingerprint,
+    /// MESSAGE-INTEGRITY was missing or did not match
+    BadIntegrity,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated => write!(f, "message truncated"),
+            Self::NotStun => write!(f, "not a STUN message"),
+            Self::BadMagicCookie(x) => write!(f, "bad magic cookie {:#010x}", x),
+            Self::BadLength(x) => write!(f, "bad message length {}", x),
+            Self::UnknownMethod(x) => write!(f, "unknown method {:#05x}", x),
+            Self::InvalidAttribute(x) => write!(f, "invalid value for attribute {:#06x}", x),
+            Self::BadFingerprint => write!(f, "bad fingerprint"),
+            Self::BadIntegrity => write!(f, "bad message integrity"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(PartialEq, Debug)]
+pub enum RegistryError {
+    /// Receivers which do not know the type would have to reject the message
+    ComprehensionRequired(u16),
+    /// Built in, or registered already
+    AlreadyKnown(u16),
+}
+
+impl Display for RegistryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ComprehensionRequired(x) => {
+                write!(f, "attribute {:#06x} is comprehension-required", x)
+            }
+            Self::AlreadyKnown(x) => write!(f, "attribute {:#06x} is already known", x),
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+#[derive(Debug)]
+pub enum TransactionError {
+    /// No response after all retransmissions
+    Timeout,
+    /// The request could not be encoded or was not a request
+    BadRequest,
+    Io(io::Error),
+}
+
+impl Display for TransactionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timeout => write!(f, "transaction timed out"),
+            Self::BadRequest => write!(f, "bad request"),
+            Self::Io(_) => write!(f, "transaction I/O error"),
+        }
+    }
+}
+
+impl std::error::Error for TransactionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for TransactionError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+#[derive(Debug)]
+pub enum DiscoveryError {
+    Transaction(TransactionError),
+    /// The server sent an error response with this code
+    ErrorResponse(u16),
+    NoMappedAddress,
+    /// The server does not support RFC 5780
+    NoOtherAddress,
+}
+
+impl Display for DiscoveryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Transaction(_) => write!(f, "transaction failed"),
+            Self::ErrorResponse(x) => write!(f, "error response {}", x),
+            Self::NoMappedAddress => write!(f, "no mapped address in response"),
+            Self::NoOtherAddress => write!(f, "server did not send OTHER-ADDRESS"),
+        }
+    }
+}
+
+impl std::error::Error for DiscoveryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Transaction(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<TransactionError> for DiscoveryError {
+    fn from(e: TransactionError) -> Self {
+        Self::Transaction(e)
+    }
+}
+
+#[derive(Debug)]
+pub enum TurnError {
+    Transaction(TransactionError),
+    /// The server sent an error response with this code and reason
+    ErrorResponse(u16, String),
+    /// A response failed the MESSAGE-INTEGRITY check
+    BadIntegrity,
+    /// A success response was missing a required attribute
+    MissingAttribute(AttributeType),
+    /// All channel numbers are in use
+    NoChannels,
+    /// The allocation has been closed
+    Closed,
+}
+
+impl Display for TurnError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Transaction(_) => write!(f, "transaction failed"),
+            Self::ErrorResponse(code, reason) => write!(f, "error response {} {}", code, reason),
+            Self::BadIntegrity => write!(f, "bad message integrity in response"),
+            Self::MissingAttribute(x) => write!(f, "response missing {:?}", x),
+            Self::NoChannels => write!(f, "no free channel numbers"),
+            Self::Closed => write!(f, "allocation closed"),
+        }
+    }
+}
+
+impl std::error::Error for TurnError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Transaction(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<TransactionError> for TurnError {
+    fn from(e: TransactionError) -> Self {
+        Self::Transaction(e)
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub enum PcapError {
+    /// Neither pcap nor pcapng
+    NotPcap,
+    /// Ran out of bytes in the file or section header
+    Truncated,
+}
+
+impl Display for PcapError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotPcap => write!(f, "not a pcap or pcapng file"),
+            Self::Truncated => write!(f, "capture header truncated"),
+        }
+    }
+}
+
+impl std::error::Error for PcapError {}
+
+#[derive(Debug)]
+pub enum DnsError {
+    /// No response from the resolver after all attempts
+    Timeout,
+    /// NXDOMAIN
+    NoSuchName,
+    /// The name exists but has no records of the type asked for
+    NoRecords,
+    /// A response code other than NXDOMAIN
+    ResponseCode(u8),
+    BadName(String),
+    Malformed,
+    Io(io::Error),
+}
+
+impl Display for DnsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timeout => write!(f, "DNS query timed out"),
+            Self::NoSuchName => write!(f, "no such name"),
+            Self::NoRecords => write!(f, "no records"),
+            Self::ResponseCode(x) => write!(f, "DNS response code {}", x),
+            Self::BadName(x) => write!(f, "bad name {:?}", x),
+            Self::Malformed => write!(f, "malformed DNS response"),
+            Self::Io(_) => write!(f, "DNS I/O error"),
+        }
+    }
+}
+
+impl std::error::Error for DnsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DnsError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
@@ -0,0 +1,331 @@
+//! Decoding messages from captures or logs for a person to read
+//!
+//! Unlike [`Message::from_bytes`](crate::message::Message::from_bytes) this
+//! does not stop at the first bad attribute, and it reports whether
+//! MESSAGE-INTEGRITY and FINGERPRINT match rather than rejecting the message.
+
+use std::fmt::{self, Display, Formatter};
+
+use serde_json::{json, Value};
+
+use crate::{
+    attribure::{Attribute, AttributeType},
+    error::ParseError,
+    message::MessageType,
+    message_ref::MessageRef,
+};
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Check {
+    /// The message does not have the attribute
+    Absent,
+    /// No key to check MESSAGE-INTEGRITY with
+    NotChecked,
+    Valid,
+    Invalid,
+}
+
+impl Check {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Absent => "absent",
+            Self::NotChecked => "not checked",
+            Self::Valid => "valid",
+            Self::Invalid => "invalid",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Inspection {
+    pub message_type: MessageType,
+    pub id: [u8; 12],
+    pub length: usize,
+    pub attributes: Vec<AttributeInspection>,
+    /// The last attribute ran past the end of the message
+    pub truncated: bool,
+    pub integrity: Check,
+    pub fingerprint: Check,
+}
+
+#[derive(Debug)]
+pub struct AttributeInspection {
+    pub attrib_type: AttributeType,
+    /// Without padding
+    pub value: Vec<u8>,
+    pub decoded: Result<Attribute, ParseError>,
+}
+
+/// Decode every attribute of a message, and check MESSAGE-INTEGRITY if given
+/// the key.
+pub fn inspect(raw: &[u8], key: Option<&[u8]>) -> Result<Inspection, ParseError> {
+    let msg = MessageRef::new(raw)?;
+
+    let mut attributes = Vec::new();
+    let mut truncated = false;
+    for attribute in msg.attributes() {
+        match attribute {
+            Ok(attribute) => attributes.push(AttributeInspection {
+                attrib_type: attribute.attrib_type(),
+                value: attribute.value().to_vec(),
+                decoded: attribute.to_attribute(),
+            }),
+            Err(_) => truncated = true,
+        }
+    }
+
+    let has = |attrib_type| attributes.iter().any(|a| a.attrib_type == attrib_type);
+    let integrity = match key {
+        _ if !has(AttributeType::MessageIntegrity) => Check::Absent,
+        None => Check::NotChecked,
+        Some(key) if msg.check_integrity(key) => Check::Valid,
+        Some(_) => Check::Invalid,
+    };
+    let fingerprint = match msg.check_fingerprint() {
+        _ if !has(AttributeType::Fingerprint) => Check::Absent,
+        true => Check::Valid,
+        false => Check::Invalid,
+    };
+
+    Ok(Inspection {
+        message_type: msg.message_type(),
+        id: *msg.id(),
+        length: raw.len(),
+        attributes,
+        truncated,
+        integrity,
+        fingerprint,
+    })
+}
+
+impl Inspection {
+    pub fn method_name(&self) -> String {
+        match self.message_type.method() {
+            Some(method) => format!("{:?}", method),
+            None => format!("{:#05x}", self.message_type.method_value()),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let attributes: Vec<Value> = self
+            .attributes
+            .iter()
+            .map(|a| {
+                let (value, error) = match &a.decoded {
+                    Ok(decoded) => (describe(decoded), None),
+                    Err(e) => (String::new(), Some(e.to_string())),
+                };
+                json!({
+                    "type": a.attrib_type.value(),
+                    "name": name(a.attrib_type),
+                    "length": a.value.len(),
+                    "raw": hex::encode(&a.value),
+                    "value": value,
+                    "error": error,
+                })
+            })
+            .collect();
+        json!({
+            "class": format!("{:?}", self.message_type.class()),
+            "method": self.method_name(),
+            "id": hex::encode(self.id),
+            "length": self.length,
+            "attributes": attributes,
+            "truncated": self.truncated,
+            "integrity": self.integrity.name(),
+            "fingerprint": self.fingerprint.name(),
+        })
+    }
+}
+
+impl Display for Inspection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} {:?}, {} bytes",
+            self.method_name(),
+            self.message_type.class(),
+            self.length
+        )?;
+        writeln!(f, "  transaction ID: {}", hex::encode(self.id))?;
+        for a in &self.attributes {
+            write!(
+                f,
+                "  {} ({:#06x}, {} bytes): ",
+                name(a.attrib_type),
+                a.attrib_type.value(),
+                a.value.len()
+            )?;
+            match &a.decoded {
+                Ok(decoded) => writeln!(f, "{}", describe(decoded))?,
+                Err(e) => writeln!(f, "{}: {}", e, hex::encode(&a.value))?,
+            }
+        }
+        if self.truncated {
+            writeln!(f, "  last attribute truncated")?;
+        }
+        writeln!(f, "  integrity: {}", self.integrity.name())?;
+        write!(f, "  fingerprint: {}", self.fingerprint.name())
+    }
+}
+
+/// The name from the RFCs, or e.g. "unknown optional"
+fn name(attrib_type: AttributeType) -> &'static str {
+    match attrib_type {
+        AttributeType::MappedAddress => "MAPPED-ADDRESS",
+        AttributeType::ChangeRequest => "CHANGE-REQUEST",
+        AttributeType::Username => "USERNAME",
+        AttributeType::MessageIntegrity => "MESSAGE-INTEGRITY",
+        AttributeType::ErrorCode => "ERROR-CODE",
+        AttributeType::UnknownAttributes => "UNKNOWN-ATTRIBUTES",
+        AttributeType::ChannelNumber => "CHANNEL-NUMBER",
+        AttributeType::Lifetime => "LIFETIME",
+        AttributeType::XorPeerAddress => "XOR-PEER-ADDRESS",
+        AttributeType::Data => "DATA",
+        AttributeType::Realm => "REALM",
+        AttributeType::Nonce => "NONCE",
+        AttributeType::XorRelayedAddress => "XOR-RELAYED-ADDRESS",
+        AttributeType::RequestedTransport => "REQUESTED-TRANSPORT",
+        AttributeType::DontFragment => "DONT-FRAGMENT",
+        AttributeType::XorMappedAddress => "XOR-MAPPED-ADDRESS",
+        AttributeType::Priority => "PRIORITY",
+        AttributeType::UseCandidate => "USE-CANDIDATE",
+        AttributeType::Software => "SOFTWARE",
+        AttributeType::Fingerprint => "FINGERPRINT",
+        AttributeType::IceControlled => "ICE-CONTROLLED",
+        AttributeType::IceControlling => "ICE-CONTROLLING",
+        AttributeType::ResponseOrigin => "RESPONSE-ORIGIN",
+        AttributeType::OtherAddress => "OTHER-ADDRESS",
+        AttributeType::Custom(_) => "registered",
+        x if x.is_comprehension_required() => "unknown required",
+        _ => "unknown optional",
+    }
+}
+
+fn describe(attribute: &Attribute) -> String {
+    match attribute {
+        Attribute::MappedAddress(addr)
+        | Attribute::XorPeerAddress(addr)
+        | Attribute::XorRelayedAddress(addr)
+        | Attribute::XorMappedAddress(addr)
+        | Attribute::ResponseOrigin(addr)
+        | Attribute::OtherAddress(addr) => addr.to_string(),
+        Attribute::ChangeRequest {
+            change_ip,
+            change_port,
+        } => format!("change IP {}, change port {}", change_ip, change_port),
+        Attribute::Username(s)
+        | Attribute::Realm(s)
+        | Attribute::Nonce(s)
+        | Attribute::Software(s) => {
+            format!("{:?}", s)
+        }
+        Attribute::MessageIntegrity(hmac) => hex::encode(hmac),
+        Attribute::ErrorCode(code, reason) => format!("{} {}", code, reason),
+        Attribute::UnknownAttributes(types) => types
+            .iter()
+            .map(|x| format!("{:#06x}", x))
+            .collect::<Vec<_>>()
+            .join(", "),
+        Attribute::ChannelNumber(x) => format!("{:#06x}", x),
+        Attribute::Lifetime(x) => format!("{} s", x),
+        Attribute::Data(data) | Attribute::Unknown(_, data) => hex::encode(data),
+        Attribute::RequestedTransport(x) => x.to_string(),
+        Attribute::DontFragment | Attribute::UseCandidate => String::new(),
+        Attribute::Priority(x) => x.to_string(),
+        Attribute::Fingerprint(x) => format!("{:#010x}", x),
+        Attribute::IceControlled(x) | Attribute::IceControlling(x) => format!("{:#018x}", x),
+        Attribute::Custom(x) => format!("{:?}", x),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{
+        tests::{vector, ID, PASSWORD, REQUEST, RESPONSE_V6},
+        MessageClass, HEADER_LEN,
+    };
+
+    #[test]
+    fn rfc5769_integrity() {
+        let raw = vector(REQUEST);
+        for (key, expected) in [
+            (Some(PASSWORD), Check::Valid),
+            (Some(&b"wrong"[..]), Check::Invalid),
+            (None, Check::NotChecked),
+        ] {
+            let inspection = inspect(&raw, key).unwrap();
+            assert_eq!(inspection.integrity, expected);
+            assert_eq!(inspection.fingerprint, Check::Valid);
+            assert_eq!(inspection.to_json()["integrity"], expected.name());
+        }
+
+        let inspection = inspect(&raw, Some(PASSWORD)).unwrap();
+        assert_eq!(inspection.message_type.class(), MessageClass::Request);
+        assert_eq!(inspection.method_name(), "Binding");
+        assert_eq!(inspection.id, ID);
+        assert_eq!(inspection.length, raw.len());
+        assert!(!inspection.truncated);
+        let types: Vec<_> = inspection
+            .attributes
+            .iter()
+            .map(|a| a.attrib_type)
+            .collect();
+        assert_eq!(
+            types,
+            [
+                AttributeType::Software,
+                AttributeType::Priority,
+                AttributeType::IceControlled,
+                AttributeType::Username,
+                AttributeType::MessageIntegrity,
+                AttributeType::Fingerprint,
+            ]
+        );
+        assert!(inspection.attributes.iter().all(|a| a.decoded.is_ok()));
+        assert!(inspection
+            .to_string()
+            .contains("USERNAME (0x0006, 9 bytes): \"evtj:h6vY\""));
+    }
+
+    #[test]
+    fn keeps_going_past_bad_attributes() {
+        // Tampered XOR-MAPPED-ADDRESS family, so nothing matches any more
+        let mut raw = vector(RESPONSE_V6).to_vec();
+        raw[HEADER_LEN + 16 + 5] = 0x07;
+        let inspection = inspect(&raw, Some(PASSWORD)).unwrap();
+        assert_eq!(inspection.integrity, Check::Invalid);
+        assert_eq!(inspection.fingerprint, Check::Invalid);
+        assert_eq!(inspection.attributes.len(), 4);
+        assert!(inspection.attributes[1].decoded.is_err());
+        assert!(inspection.attributes[3].decoded.is_ok());
+
+        // The last attribute cut short
+        let mut raw = vector(RESPONSE_V6).to_vec();
+        let at = raw.len() - 8;
+        raw[at + 3] = 8;
+        let inspection = inspect(&raw, Some(PASSWORD)).unwrap();
+        assert!(inspection.truncated);
+        assert_eq!(inspection.integrity, Check::Valid);
+        assert_eq!(inspection.fingerprint, Check::Absent);
+
+        // Not even a header
+        assert_eq!(
+            inspect(&raw[..HEADER_LEN - 1], None).unwrap_err(),
+            ParseError::Truncated
+        );
+    }
+
+    #[test]
+    fn absent() {
+        let raw = [0x00, 0x01, 0x00, 0x00, 0x21, 0x12, 0xa4, 0x42]
+            .into_iter()
+            .chain(ID)
+            .collect::<Vec<_>>();
+        let inspection = inspect(&raw, Some(PASSWORD)).unwrap();
+        assert!(inspection.attributes.is_empty());
+        assert_eq!(inspection.integrity, Check::Absent);
+        assert_eq!(inspection.fingerprint, Check::Absent);
+    }
+}
@@ -0,0 +1,66 @@
+use hmac::{Hmac, Mac};
+use md5::{Digest, Md5};
+use sha1::Sha1;
+
+/// FINGERPRINT is the CRC-32 of the message XOR'ed with this value
+pub const FINGERPRINT_XOR: u32 = 0x5354554E;
+
+#[derive(Clone, Debug)]
+pub enum Credentials {
+    ShortTerm {
+        username: String,
+        password: String,
+    },
+    LongTerm {
+        username: String,
+        realm: String,
+        password: String,
+    },
+}
+
+impl Credentials {
+    pub fn username(&self) -> &str {
+        match self {
+            Self::ShortTerm { username, .. } | Self::LongTerm { username, .. } => username,
+        }
+    }
+
+    /// The HMAC key for MESSAGE-INTEGRITY.
+    // TODO: SASLprep the password, we assume it is already ASCII
+    pub fn key(&self) -> Vec<u8> {
+        match self {
+            Self::ShortTerm { password, .. } => password.as_bytes().to_vec(),
+            Self::LongTerm {
+                username,
+                realm,
+                password,
+            } => Md5::digest(format!("{}:{}:{}", username, realm, password)).to_vec(),
+        }
+    }
+}
+
+pub fn hmac_sha1(key: &[u8], data: &[u8]) -> [u8; 20] {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC takes a key of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+/// Compare in constant time
+pub fn hmac_sha1_verify(key: &[u8], data: &[u8], expected: &[u8]) -> bool {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC takes a key of any size");
+    mac.update(data);
+    mac.verify_slice(expected).is_ok()
+}
+
+/// Like [`hmac_sha1_verify`], over data in several pieces
+pub fn hmac_sha1_verify_parts(key: &[u8], parts: &[&[u8]], expected: &[u8]) -> bool {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC takes a key of any size");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.verify_slice(expected).is_ok()
+}
+
+pub fn fingerprint(data: &[u8]) -> u32 {
+    crc32fast::hash(data) ^ FINGERPRINT_XOR
+}
@@ -0,0 +1,16 @@
+pub mod attribure;
+pub mod demux;
+pub mod discovery;
+pub mod dns;
+pub mod error;
+pub mod inspect;
+pub mod integrity;
+pub mod message;
+pub mod message_ref;
+pub mod pcap;
+pub mod registry;
+pub mod server;
+pub mod tcp;
+pub mod transaction;
+pub mod turn;
+pub mod turn_server;
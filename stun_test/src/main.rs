@@ -1,28 +1,315 @@
-mod attribure;
-mod message;
-
 use std::{
-    io::{Read, Write},
-    net::{TcpStream, UdpSocket},
+    fmt::{self, Display, Formatter},
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    path::PathBuf,
+    sync::Arc,
 };
 
-use bytes::{BufMut, Bytes, BytesMut};
-use rand::{self, random};
+use clap::{Parser, Subcommand};
+use log::LevelFilter;
+use rand::random;
+use tokio::net::{TcpListener, UdpSocket};
 
-use crate::{
-    attribure::{Attribute, AttributeType},
+use stun_test::{
+    demux, discovery, dns,
+    error::TransactionError,
+    integrity::Credentials,
     message::{Message, MessageClass, MessageMethod},
+    pcap, server,
+    transaction::{AsyncClient, Response, TcpClient, TransactionConfig},
+    turn::TurnClient,
+    turn_server::{self, TurnServerConfig},
 };
 
 const SERVER: &str = "stun.mit.de:3478";
 
-fn main() -> anyhow::Result<()> {
-    simple_logger::SimpleLogger::new().env().init().unwrap();
+#[derive(Parser, Debug)]
+#[command()]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Ask STUN servers for our public address (the default). Several
+    /// servers are asked at once and their answers compared
+    Client {
+        /// host:port, stun.mit.de:3478 if no servers are given
+        #[arg(long)]
+        server: Vec<String>,
+
+        /// File of servers, one host:port per line
+        #[arg(long)]
+        servers: Option<PathBuf>,
+
+        /// Find servers through the _stun._udp (or _stun._tcp) SRV records
+        /// of this domain, trying each in turn
+        #[arg(long, conflicts_with_all = ["server", "servers"])]
+        domain: Option<String>,
+
+        /// DNS resolver for --domain, the system's by default
+        #[arg(long, requires = "domain")]
+        resolver: Option<SocketAddr>,
+
+        /// Use TCP rather than UDP
+        #[arg(long)]
+        tcp: bool,
+    },
+    /// Classify our NAT's mapping and filtering (RFC 5780)
+    Discover {
+        #[arg(long)]
+        server: String,
+    },
+    /// Allocate a relayed address on a TURN server and echo back anything
+    /// received on it
+    Turn {
+        #[arg(long)]
+        server: String,
+
+        #[arg(long)]
+        username: String,
+
+        #[arg(long)]
+        password: String,
+
+        /// Peer IPs allowed to send to us
+        #[arg(long)]
+        peer: Vec<IpAddr>,
+    },
+    /// Answer Binding requests
+    Server {
+        #[arg(long, default_value = "0.0.0.0:3478")]
+        bind: SocketAddr,
+
+        /// Also listen on this IP and port for RFC 5780 discovery
+        #[arg(long)]
+        alternate: Option<SocketAddr>,
+
+        /// Also listen on TCP at the bind address
+        #[arg(long)]
+        tcp: bool,
+    },
+    /// Relay UDP for TURN clients
+    TurnServer {
+        #[arg(long, default_value = "0.0.0.0:3478")]
+        bind: SocketAddr,
+
+        /// IP to allocate relayed addresses on, needed if bind is 0.0.0.0
+        #[arg(long)]
+        relay_ip: Option<IpAddr>,
+
+        #[arg(long, default_value = "stun_test")]
+        realm: String,
+
+        /// File of username:password lines
+        #[arg(long)]
+        users: PathBuf,
+    },
+    /// Decode messages and check their integrity and fingerprint. With no
+    /// input, hex is read from stdin, one message per line
+    Inspect {
+        /// Messages in hex, whitespace and colons ignored
+        hex: Vec<String>,
+
+        /// Files each holding one message
+        #[arg(long)]
+        file: Vec<PathBuf>,
+
+        /// pcap or pcapng captures, of which UDP payloads which look like STUN
+        /// are decoded
+        #[arg(long)]
+        pcap: Vec<PathBuf>,
+
+        /// Check MESSAGE-INTEGRITY with this password, a long-term key if
+        /// username and realm are also given
+        #[arg(long)]
+        password: Option<String>,
+
+        #[arg(long, requires = "realm")]
+        username: Option<String>,
+
+        #[arg(long, requires = "username")]
+        realm: Option<String>,
+
+        /// One JSON object per message
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    // Keep stdout for the decoded messages
+    let mut logger = simple_logger::SimpleLogger::new();
+    if matches!(args.command, Some(Command::Inspect { .. })) {
+        logger = logger.with_level(LevelFilter::Warn);
+    }
+    logger.env().init().unwrap();
     log::info!("Started");
+    log::info!("Args: {:?}", args);
+
+    match args.command.unwrap_or(Command::Client {
+        server: Vec::new(),
+        servers: None,
+        domain: None,
+        resolver: None,
+        tcp: false,
+    }) {
+        Command::Client {
+            domain: Some(domain),
+            resolver,
+            tcp,
+            ..
+        } => {
+            let resolver = match resolver {
+                Some(addr) => dns::Resolver::new(addr),
+                None => dns::Resolver::system()?,
+            };
+            srv_client(&resolver, &domain, tcp).await?
+        }
+        Command::Client {
+            mut server,
+            servers,
+            tcp,
+            ..
+        } => {
+            if let Some(path) = servers {
+                server.extend(discovery::load_servers(&path)?);
+            }
+            match server.as_slice() {
+                [] => client(SERVER, tcp).await?,
+                [server] => client(server, tcp).await?,
+                _ if tcp => anyhow::bail!("--tcp takes a single server"),
+                servers => consensus(servers).await?,
+            }
+        }
+        Command::Discover { server } => discover(&server).await?,
+        Command::Turn {
+            server,
+            username,
+            password,
+            peer,
+        } => turn(&server, &username, &password, &peer).await?,
+        Command::Server {
+            bind,
+            alternate,
+            tcp,
+        } => {
+            let udp = async {
+                match alternate {
+                    Some(alternate) => server::run_udp_rfc5780(bind, alternate).await,
+                    None => server::run_udp(UdpSocket::bind(bind).await?).await,
+                }
+            };
+            if tcp {
+                let tcp = async { server::run_tcp(TcpListener::bind(bind).await?).await };
+                tokio::try_join!(udp, tcp)?;
+            } else {
+                udp.await?;
+            }
+        }
+        Command::TurnServer {
+            bind,
+            relay_ip,
+            realm,
+            users,
+        } => {
+            let config = TurnServerConfig {
+                realm,
+                users: turn_server::load_users(&users)?,
+                relay_ip,
+                ..Default::default()
+            };
+            turn_server::run_udp(UdpSocket::bind(bind).await?, config).await?;
+        }
+        Command::Inspect {
+            hex,
+            file,
+            pcap,
+            password,
+            username,
+            realm,
+            json,
+        } => {
+            let key = password.map(|password| match (username, realm) {
+                (Some(username), Some(realm)) => Credentials::LongTerm {
+                    username,
+                    realm,
+                    password,
+                }
+                .key(),
+                _ => password.into_bytes(),
+            });
+            inspect(hex, &file, &pcap, key.as_deref(), json)?;
+        }
+    }
+
+    log::info!("Finished");
+    Ok(())
+}
+
+fn resolve(server: &str) -> anyhow::Result<SocketAddr> {
+    server
+        .to_socket_addrs()?
+        .find(|addr| addr.is_ipv4())
+        .ok_or_else(|| anyhow::anyhow!("failed to resolve {}", server))
+}
+
+async fn client(server: &str, tcp: bool) -> anyhow::Result<()> {
+    let server = resolve(server)?;
+    let config = TransactionConfig::default();
+
+    let response = match binding(server, tcp, config).await {
+        // Tell "UDP blocked" apart from "server down"
+        Err(TransactionError::Timeout) if !tcp => {
+            log::warn!("No response over UDP, trying TCP");
+            match binding(server, true, config).await {
+                Ok(response) => {
+                    log::warn!("UDP to {} appears to be blocked", server);
+                    response
+                }
+                Err(e) => {
+                    log::error!("No response over TCP either, is {} down?", server);
+                    return Err(e.into());
+                }
+            }
+        }
+        response => response?,
+    };
+    show_response(response);
+    Ok(())
+}
 
-    let socket = UdpSocket::bind("0.0.0.0:0")?;
-    socket.connect(SERVER)?;
+/// Try the servers of a domain's SRV records in order until one answers.
+async fn srv_client(resolver: &dns::Resolver, domain: &str, tcp: bool) -> anyhow::Result<()> {
+    let servers = resolver.stun_servers(domain, tcp).await?;
+    log::info!("Servers for {}: {:?}", domain, servers);
 
+    for server in &servers {
+        match binding(*server, tcp, TransactionConfig::default()).await {
+            Ok(response) => {
+                show_response(response);
+                return Ok(());
+            }
+            Err(e) => log::warn!("{}: {}, trying the next server", server, e),
+        }
+    }
+    anyhow::bail!(
+        "none of the {} servers for {} answered",
+        servers.len(),
+        domain
+    )
+}
+
+/// A Binding request over UDP or TCP
+async fn binding(
+    server: SocketAddr,
+    tcp: bool,
+    config: TransactionConfig,
+) -> Result<Response, TransactionError> {
     let msg = Message {
         method: MessageMethod::Binding,
         class: MessageClass::Request,
@@ -33,12 +320,247 @@ fn main() -> anyhow::Result<()> {
     let msg = msg.bytes();
     log::trace!("msg ({} bytes): {}", msg.len(), hex::encode(&msg));
 
-    socket.send(&msg)?;
+    if tcp {
+        TcpClient::connect(server, config)
+            .await?
+            .transaction(msg)
+            .await
+    } else {
+        let client = AsyncClient::new(UdpSocket::bind("0.0.0.0:0").await?, config);
+        client.transaction(msg, server).await
+    }
+}
 
-    let mut buf = [0; 1024];
-    let len = socket.recv(&mut buf)?;
-    log::info!("Received {} bytes: {}", len, hex::encode(&buf[..len]));
+fn show_response(response: Response) {
+    log::info!(
+        "Received {} bytes: {}",
+        response.raw.len(),
+        hex::encode(&response.raw)
+    );
 
-    log::info!("Finished");
+    let msg = response.message;
+    log::info!("Received {:?}", msg);
+
+    let unknown = msg.unknown_comprehension_required();
+    if !unknown.is_empty() {
+        log::warn!("Response has unknown required attributes: {:04x?}", unknown);
+    }
+
+    match msg.mapped_address() {
+        Some(addr) => log::info!("Public address: {}", addr),
+        None => log::warn!("No mapped address in response"),
+    }
+}
+
+/// Where an inspected message came from
+enum Source {
+    Arg(usize),
+    Stdin(usize),
+    File(PathBuf),
+    Packet(PathBuf, pcap::UdpPacket),
+}
+
+impl Source {
+    fn to_json(&self) -> serde_json::Value {
+        match self {
+            Self::Arg(i) => serde_json::json!({ "arg": i }),
+            Self::Stdin(line) => serde_json::json!({ "line": line }),
+            Self::File(path) => serde_json::json!({ "file": path }),
+            Self::Packet(path, packet) => serde_json::json!({
+                "file": path,
+                "packet": packet.number,
+                "time": packet.time.as_secs_f64(),
+                "source": packet.source.to_string(),
+                "destination": packet.destination.to_string(),
+            }),
+        }
+    }
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Arg(i) => write!(f, "argument {}", i),
+            Self::Stdin(line) => write!(f, "line {}", line),
+            Self::File(path) => write!(f, "{}", path.display()),
+            Self::Packet(path, packet) => write!(
+                f,
+                "{} #{} at {:.6}: {} -> {}",
+                path.display(),
+                packet.number,
+                packet.time.as_secs_f64(),
+                packet.source,
+                packet.destination
+            ),
+        }
+    }
+}
+
+fn inspect(
+    hex: Vec<String>,
+    files: &[PathBuf],
+    pcaps: &[PathBuf],
+    key: Option<&[u8]>,
+    json: bool,
+) -> anyhow::Result<()> {
+    let decode = |s: &str| {
+        let s: String = s
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != ':')
+            .collect();
+        hex::decode(s)
+    };
+
+    let mut messages = Vec::new();
+    if hex.is_empty() && files.is_empty() && pcaps.is_empty() {
+        for (i, line) in std::io::stdin().lines().enumerate() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                messages.push((Source::Stdin(i + 1), decode(&line)?));
+            }
+        }
+    }
+    for (i, s) in hex.iter().enumerate() {
+        messages.push((Source::Arg(i + 1), decode(s)?));
+    }
+    for path in files {
+        messages.push((Source::File(path.clone()), std::fs::read(path)?));
+    }
+    for path in pcaps {
+        let packets = pcap::udp_packets(&std::fs::read(path)?)?;
+        let total = packets.len();
+        for mut packet in packets.into_iter().filter(|p| demux::is_stun(&p.payload)) {
+            let payload = std::mem::take(&mut packet.payload);
+            messages.push((Source::Packet(path.clone(), packet), payload));
+        }
+        log::info!("{}: {} UDP packets", path.display(), total);
+    }
+
+    for (source, raw) in messages {
+        let inspection = stun_test::inspect::inspect(&raw, key);
+        if json {
+            let mut value = match &inspection {
+                Ok(inspection) => inspection.to_json(),
+                Err(e) => serde_json::json!({ "error": e.to_string(), "raw": hex::encode(&raw) }),
+            };
+            value["from"] = source.to_json();
+            println!("{}", value);
+        } else {
+            match inspection {
+                Ok(inspection) => println!("{}\n{}\n", source, inspection),
+                Err(e) => println!("{}\n  {}: {}\n", source, e, hex::encode(&raw)),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn consensus(servers: &[String]) -> anyhow::Result<()> {
+    // Servers which cannot be resolved are left out like those which do not
+    // answer
+    let addrs: Vec<SocketAddr> = servers
+        .iter()
+        .filter_map(|server| match resolve(server) {
+            Ok(addr) => Some(addr),
+            Err(e) => {
+                log::warn!("{}: {}", server, e);
+                None
+            }
+        })
+        .collect();
+    if addrs.is_empty() {
+        anyhow::bail!("none of the servers could be resolved");
+    }
+
+    let client = Arc::new(AsyncClient::new(
+        UdpSocket::bind("0.0.0.0:0").await?,
+        TransactionConfig::default(),
+    ));
+    let consensus = discovery::query_all(&client, &addrs).await;
+    for report in &consensus.reports {
+        match &report.result {
+            Ok(addr) => log::info!("{} sees {}", report.server, addr),
+            Err(e) => log::warn!("{}: {}", report.server, e),
+        }
+    }
+
+    let addresses = consensus.addresses();
+    let answered: usize = addresses.iter().map(|(_, count)| count).sum();
+    match addresses.as_slice() {
+        [] => anyhow::bail!("no server answered"),
+        [(addr, _)] => log::info!(
+            "Public address: {} ({} of {} servers agree)",
+            addr,
+            answered,
+            consensus.reports.len()
+        ),
+        _ => {
+            for (addr, count) in &addresses {
+                log::warn!("{} of {} servers see {}", count, answered, addr);
+            }
+            log::warn!("Servers disagree, the mapping depends on the destination address");
+        }
+    }
+    if let Some(mapping) = consensus.mapping() {
+        log::info!("Mapping: {:?}", mapping);
+    }
+
+    Ok(())
+}
+
+async fn discover(server: &str) -> anyhow::Result<()> {
+    let server = resolve(server)?;
+    let client = AsyncClient::new(
+        UdpSocket::bind("0.0.0.0:0").await?,
+        TransactionConfig::default(),
+    );
+
+    let discovery = discovery::discover(&client, server).await?;
+    log::info!("Public address: {}", discovery.mapped_address);
+    log::info!("Server other address: {}", discovery.other_address);
+    log::info!("Mapping: {:?}", discovery.mapping);
+    log::info!("Filtering: {:?}", discovery.filtering);
+
+    Ok(())
+}
+
+async fn turn(
+    server: &str,
+    username: &str,
+    password: &str,
+    peers: &[IpAddr],
+) -> anyhow::Result<()> {
+    let server = resolve(server)?;
+    let client = TurnClient::allocate(
+        UdpSocket::bind("0.0.0.0:0").await?,
+        server,
+        username,
+        password,
+        TransactionConfig::default(),
+    )
+    .await?;
+    log::info!("Relayed address: {}", client.relayed_addr());
+    if let Some(addr) = client.mapped_addr() {
+        log::info!("Public address: {}", addr);
+    }
+
+    for peer in peers {
+        client.create_permission(*peer).await?;
+        log::info!("Permitted {}", peer);
+    }
+
+    loop {
+        tokio::select! {
+            res = client.recv_from() => {
+                let (data, peer) = res?;
+                log::info!("Rx {} bytes from {}", data.len(), peer);
+                client.send_to(&data, peer).await?;
+            }
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    client.close().await?;
     Ok(())
 }
@@ -1,10 +1,57 @@
-use crate::attribure::Attribute;
-use bytes::{BufMut, Bytes, BytesMut};
+use std::net::SocketAddr;
 
-type MessageMethod = u16;
-const BINDING: MessageMethod = 0x0001;
+use crate::{
+    attribure::{Attribute, AttributeType},
+    error::ParseError,
+    integrity,
+    registry::CustomAttribute,
+};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 
-#[derive(PartialEq, Debug)]
+pub const MAGIC_COOKIE: u32 = 0x2112A442;
+pub const HEADER_LEN: usize = 20;
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum MessageMethod {
+    Binding,
+    // TURN (RFC 8656)
+    Allocate,
+    Refresh,
+    Send,
+    Data,
+    CreatePermission,
+    ChannelBind,
+}
+
+impl MessageMethod {
+    pub fn value(&self) -> u16 {
+        match self {
+            Self::Binding => 0x0001,
+            Self::Allocate => 0x0003,
+            Self::Refresh => 0x0004,
+            Self::Send => 0x0006,
+            Self::Data => 0x0007,
+            Self::CreatePermission => 0x0008,
+            Self::ChannelBind => 0x0009,
+        }
+    }
+
+    pub fn from_value(value: u16) -> Option<Self> {
+        [
+            Self::Binding,
+            Self::Allocate,
+            Self::Refresh,
+            Self::Send,
+            Self::Data,
+            Self::CreatePermission,
+            Self::ChannelBind,
+        ]
+        .into_iter()
+        .find(|x| x.value() == value)
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum MessageClass {
     Request,
     ResponseSuccess,
@@ -12,42 +59,82 @@ pub enum MessageClass {
     Indication,
 }
 
-#[derive(Debug)]
-pub struct MessageType {
-    bytes: Bytes,
+impl MessageClass {
+    pub fn value(&self) -> u16 {
+        match self {
+            Self::Request => 0b00,
+            Self::Indication => 0b01,
+            Self::ResponseSuccess => 0b10,
+            Self::ResponseFailure => 0b11,
+        }
+    }
+
+    pub fn from_value(value: u16) -> Self {
+        match value & 0b11 {
+            0b00 => Self::Request,
+            0b01 => Self::Indication,
+            0b10 => Self::ResponseSuccess,
+            _ => Self::ResponseFailure,
+        }
+    }
 }
 
+/// The 14 bit STUN message type. The method and class bits are interleaved:
+///
+/// ```text
+///  0                 1
+///  2  3  4 5 6 7 8 9 0 1 2 3 4 5
+/// +--+--+-+-+-+-+-+-+-+-+-+-+-+-+
+/// |M |M |M|M|M|C|M|M|M|C|M|M|M|M|
+/// |11|10|9|8|7|1|6|5|4|0|3|2|1|0|
+/// +--+--+-+-+-+-+-+-+-+-+-+-+-+-+
+/// ```
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct MessageType {
+    value: u16,
+}
 
 impl MessageType {
-
     pub fn new(method: MessageMethod, class: MessageClass) -> Self {
-        
+        Self::from_parts(method.value(), class)
     }
 
+    /// From the method's value, which we might not know
+    pub fn from_parts(method: u16, class: MessageClass) -> Self {
+        let m = method;
+        let c = class.value();
+        let value = (m & 0x000F)
+            | ((m & 0x0070) << 1)
+            | ((m & 0x0F80) << 2)
+            | ((c & 0b01) << 4)
+            | ((c & 0b10) << 7);
+        Self { value }
+    }
 
-    pub fn bytes(&self) -> &Bytes {
-        &self.bytes
+    pub fn bytes(&self) -> [u8; 2] {
+        self.value.to_be_bytes()
     }
 
-    pub fn from_bytes(bytes: Bytes) -> Self {
+    pub fn from_bytes(bytes: [u8; 2]) -> Self {
         Self {
-            bytes
+            value: u16::from_be_bytes(bytes) & 0x3FFF,
         }
     }
 
-    pub fn method() -> MessageMethod {
-        todo!()
+    pub fn method_value(&self) -> u16 {
+        let v = self.value;
+        (v & 0x000F) | ((v & 0x00E0) >> 1) | ((v & 0x3E00) >> 2)
     }
 
-    pub fn class() -> MessageClass {
-        todo!()
+    pub fn method(&self) -> Option<MessageMethod> {
+        MessageMethod::from_value(self.method_value())
     }
-}
-
-
-
-
 
+    pub fn class(&self) -> MessageClass {
+        let v = self.value;
+        MessageClass::from_value(((v & 0x0010) >> 4) | ((v & 0x0100) >> 7))
+    }
+}
 
 #[derive(Debug)]
 pub struct Message {
@@ -59,26 +146,28 @@ pub struct Message {
 
 impl Message {
     pub fn bytes(&self) -> Bytes {
+        self.encode(None, false)
+    }
+
+    /// Encode, appending MESSAGE-INTEGRITY when given a key (see
+    /// [`Credentials::key`]) and then FINGERPRINT if asked for.
+    pub fn encode(&self, key: Option<&[u8]>, fingerprint: bool) -> Bytes {
         let mut bytes = BytesMut::new();
 
         // Attributes
         let mut attribs = BytesMut::new();
         for a in &self.attributes {
-            attribs.put(a.bytes());
+            attribs.put(a.bytes(&self.id));
         }
 
         // Message Type
-        // Assert class is Request.
-        // Then we can ignore the class, as Request is zeros.
-        assert_eq!(self.class, MessageClass::Request);
-        bytes.put_u16(self.method.bytes());
+        bytes.put_slice(&MessageType::new(self.method, self.class).bytes());
 
         // Message Length
-        // TODO
         bytes.put_u16(attribs.len() as u16);
 
         // Magic Cookie
-        bytes.put_u32(0x2112A442);
+        bytes.put_u32(MAGIC_COOKIE);
 
         // Transaction ID
         bytes.put_slice(&self.id);
@@ -86,11 +175,383 @@ impl Message {
         // Attributes
         bytes.put(attribs);
 
+        // Message Integrity
+        // The HMAC covers the header, with a length that includes the
+        // MESSAGE-INTEGRITY attribute, and all attributes before it.
+        if let Some(key) = key {
+            set_length(&mut bytes, 4 + 20);
+            let hmac = integrity::hmac_sha1(key, &bytes);
+            bytes.put(Attribute::MessageIntegrity(hmac).bytes(&self.id));
+        }
+
+        // Fingerprint
+        if fingerprint {
+            set_length(&mut bytes, 4 + 4);
+            let crc = integrity::fingerprint(&bytes);
+            bytes.put(Attribute::Fingerprint(crc).bytes(&self.id));
+        }
+
         bytes.into()
     }
 
-    pub fn from_bytes(bytes: &mut Bytes) -> Self {
-        let method = MessageMethod::from_bytes
+    /// Decode, checking FINGERPRINT if present. Attributes after
+    /// MESSAGE-INTEGRITY, other than FINGERPRINT, are ignored.
+    pub fn from_bytes(bytes: &mut Bytes) -> Result<Self, ParseError> {
+        let raw = bytes.clone();
+        if bytes.len() < HEADER_LEN {
+            return Err(ParseError::Truncated);
+        }
+
+        // Message Type
+        // The two most significant bits of every STUN message are zero.
+        let msg_type = [bytes.get_u8(), bytes.get_u8()];
+        if msg_type[0] & 0xC0 != 0 {
+            return Err(ParseError::NotStun);
+        }
+        let msg_type = MessageType::from_bytes(msg_type);
+        let method = msg_type
+            .method()
+            .ok_or(ParseError::UnknownMethod(msg_type.method_value()))?;
+        let class = msg_type.class();
+
+        // Message Length
+        let len = bytes.get_u16() as usize;
+        if !len.is_multiple_of(4) {
+            return Err(ParseError::BadLength(len));
+        }
+
+        // Magic Cookie
+        let magic_cookie = bytes.get_u32();
+        if magic_cookie != MAGIC_COOKIE {
+            return Err(ParseError::BadMagicCookie(magic_cookie));
+        }
+
+        // Transaction ID
+        let mut id = [0; 12];
+        bytes.copy_to_slice(&mut id);
+
+        if bytes.len() != len {
+            return Err(ParseError::BadLength(len));
+        }
+
+        // Attributes
+        let mut attributes = Vec::new();
+        let mut integrity_seen = false;
+        while bytes.has_remaining() {
+            let offset = raw.len() - bytes.remaining();
+            match Attribute::from_bytes(bytes, &id)? {
+                Attribute::Fingerprint(crc) => {
+                    if bytes.has_remaining() {
+                        return Err(ParseError::InvalidAttribute(
+                            AttributeType::Fingerprint.value(),
+                        ));
+                    }
+                    if integrity::fingerprint(&raw[..offset]) != crc {
+                        return Err(ParseError::BadFingerprint);
+                    }
+                    attributes.push(Attribute::Fingerprint(crc));
+                }
+                a if !integrity_seen => {
+                    integrity_seen = a.attrib_type() == AttributeType::MessageIntegrity;
+                    attributes.push(a);
+                }
+                a => log::debug!("Ignoring {:?} after MESSAGE-INTEGRITY", a.attrib_type()),
+            }
+        }
+
+        Ok(Self {
+            method,
+            class,
+            id,
+            attributes,
+        })
+    }
+
+    /// Decode and check MESSAGE-INTEGRITY against `key`.
+    pub fn from_bytes_with_key(bytes: &mut Bytes, key: &[u8]) -> Result<Self, ParseError> {
+        if !Self::check_integrity(bytes, key) {
+            return Err(ParseError::BadIntegrity);
+        }
+        Self::from_bytes(bytes)
+    }
+
+    /// Check MESSAGE-INTEGRITY of an encoded message. False if it has no
+    /// MESSAGE-INTEGRITY attribute.
+    pub fn check_integrity(raw: &[u8], key: &[u8]) -> bool {
+        let Some((offset, value)) = find_attribute(raw, AttributeType::MessageIntegrity) else {
+            return false;
+        };
+
+        // Adjust the length as if MESSAGE-INTEGRITY were the last attribute
+        let mut bytes = BytesMut::from(&raw[..offset]);
+        set_length(&mut bytes, 4 + 20);
+        integrity::hmac_sha1_verify(key, &bytes, value)
+    }
+
+    pub fn attribute(&self, attrib_type: AttributeType) -> Option<&Attribute> {
+        self.attributes
+            .iter()
+            .find(|a| a.attrib_type() == attrib_type)
+    }
+
+    /// The value of the registered attribute `T`, if present
+    pub fn custom<T: CustomAttribute>(&self) -> Option<&T> {
+        self.attributes.iter().find_map(|a| a.as_custom())
+    }
+
+    /// Our address as seen by the server. XOR-MAPPED-ADDRESS is preferred,
+    /// some old servers only send MAPPED-ADDRESS.
+    pub fn mapped_address(&self) -> Option<SocketAddr> {
+        match self.attribute(AttributeType::XorMappedAddress) {
+            Some(Attribute::XorMappedAddress(addr)) => Some(*addr),
+            _ => match self.attribute(AttributeType::MappedAddress) {
+                Some(Attribute::MappedAddress(addr)) => Some(*addr),
+                _ => None,
+            },
+        }
+    }
+
+    /// Types of attributes we did not understand but were required to. A
+    /// request containing any of these should get a 420 (Unknown Attribute)
+    /// error response listing them in UNKNOWN-ATTRIBUTES.
+    pub fn unknown_comprehension_required(&self) -> Vec<u16> {
+        self.attributes
+            .iter()
+            .map(|a| a.attrib_type())
+            .filter(|t| matches!(t, AttributeType::Unknown(_)) && t.is_comprehension_required())
+            .map(|t| t.value())
+            .collect()
+    }
+}
+
+/// Total length of the encoded message at the start of `raw`, from its
+/// header. Used to split a stream into messages.
+pub fn message_len(raw: &[u8]) -> Option<usize> {
+    let len = u16::from_be_bytes(raw.get(2..4)?.try_into().ok()?);
+    Some(HEADER_LEN + len as usize)
+}
+
+/// Transaction ID of an encoded message, without decoding the rest of it
+pub fn transaction_id(raw: &[u8]) -> Option<[u8; 12]> {
+    raw.get(8..HEADER_LEN)?.try_into().ok()
+}
+
+/// Set the header length field to cover the attributes already in `bytes`,
+/// plus `extra` bytes.
+fn set_length(bytes: &mut BytesMut, extra: usize) {
+    let len = (bytes.len() - HEADER_LEN + extra) as u16;
+    bytes[2..4].copy_from_slice(&len.to_be_bytes());
+}
+
+/// Offset and value of the first attribute of a type in an encoded message
+fn find_attribute(raw: &[u8], attrib_type: AttributeType) -> Option<(usize, &[u8])> {
+    let mut offset = HEADER_LEN;
+    while offset + 4 <= raw.len() {
+        let t = AttributeType::from_bytes([raw[offset], raw[offset + 1]]);
+        let len = u16::from_be_bytes([raw[offset + 2], raw[offset + 3]]) as usize;
+        let value = raw.get(offset + 4..offset + 4 + len)?;
+        if t == attrib_type {
+            return Some((offset, value));
+        }
+        offset += 4 + len.next_multiple_of(4);
+    }
+    None
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+
+    pub(crate) const ID: [u8; 12] = [
+        0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae,
+    ];
+
+    /// RFC 5769 2.1, short-term credentials
+    pub(crate) const REQUEST: &str = "000100582112a442b7e7a701bc34d686fa87dfae\
+        802200105354554e20746573742063 6c69656e74\
+        002400046e0001ff\
+        80290008932ff9b151263b36\
+        000600096576746a3a68367659202020\
+        000800149aeaa70cbfd8cb56781ef2b5b2d3f249c1b571a2\
+        80280004e57a3bcf";
+    /// RFC 5769 2.2
+    pub(crate) const RESPONSE_V4: &str = "0101003c2112a442b7e7a701bc34d686fa87dfae\
+        8022000b7465737420766563746f7220\
+        002000080001a147e112a643\
+        000800142b91f599fd9e90c38c7489f92af9ba53f06be7d7\
+        80280004c07d4c96";
+    /// RFC 5769 2.3
+    pub(crate) const RESPONSE_V6: &str = "010100482112a442b7e7a701bc34d686fa87dfae\
+        8022000b7465737420766563746f7220\
+        002000140002a1470113a9faa5d3f179bc25f4b5bed2b9d9\
+        00080014a382954e4be67bf11784c97c8292c275bfe3ed41\
+        80280004c8fb0b4c";
+    pub(crate) const PASSWORD: &[u8] = b"VOkJxbRl1RmTxUk/WvJxBt";
+
+    pub(crate) fn vector(hex: &str) -> Bytes {
+        Bytes::from(hex::decode(hex.replace(' ', "")).unwrap())
+    }
+
+    fn message(method: MessageMethod, class: MessageClass) -> Message {
+        Message {
+            method,
+            class,
+            id: ID,
+            attributes: vec![
+                Attribute::Software(String::from("test")),
+                Attribute::XorMappedAddress("192.0.2.1:32853".parse().unwrap()),
+            ],
+        }
+    }
+
+    #[test]
+    fn round_trip_every_class() {
+        for class in [
+            MessageClass::Request,
+            MessageClass::Indication,
+            MessageClass::ResponseSuccess,
+            MessageClass::ResponseFailure,
+        ] {
+            let sent = message(MessageMethod::Binding, class);
+            let mut bytes = sent.encode(Some(PASSWORD), true);
+            let received = Message::from_bytes_with_key(&mut bytes, PASSWORD).unwrap();
+            assert_eq!(received.method, sent.method);
+            assert_eq!(received.class, class);
+            assert_eq!(received.id, ID);
+            assert_eq!(received.attributes[..2], sent.attributes[..]);
+            assert!(matches!(
+                received.attributes[2],
+                Attribute::MessageIntegrity(_)
+            ));
+            assert!(matches!(received.attributes[3], Attribute::Fingerprint(_)));
+        }
+    }
+
+    #[test]
+    fn interleaved_type_bits() {
+        // Method bits 0-3, class bit 0, method bits 4-6, class bit 1, method
+        // bits 7-11
+        let cases = [
+            (0x0001, MessageClass::Request, 0x0001),
+            (0x0001, MessageClass::Indication, 0x0011),
+            (0x0001, MessageClass::ResponseSuccess, 0x0101),
+            (0x0001, MessageClass::ResponseFailure, 0x0111),
+            (0x0009, MessageClass::ResponseFailure, 0x0119),
+            (0x0070, MessageClass::Request, 0x00e0),
+            (0x0f80, MessageClass::Request, 0x3e00),
+            (0x0fff, MessageClass::ResponseFailure, 0x3fff),
+        ];
+        for (method, class, value) in cases {
+            let msg_type = MessageType::from_parts(method, class);
+            assert_eq!(msg_type.bytes(), u16::to_be_bytes(value));
+            let msg_type = MessageType::from_bytes(msg_type.bytes());
+            assert_eq!(msg_type.method_value(), method);
+            assert_eq!(msg_type.class(), class);
+        }
+    }
+
+    #[test]
+    fn bad_magic_cookie() {
+        let mut bytes =
+            BytesMut::from(&message(MessageMethod::Binding, MessageClass::Request).bytes()[..]);
+        bytes[4] ^= 0xff;
+        assert_eq!(
+            Message::from_bytes(&mut bytes.freeze()).unwrap_err(),
+            ParseError::BadMagicCookie(MAGIC_COOKIE ^ 0xff00_0000)
+        );
+    }
+
+    #[test]
+    fn bad_length() {
+        let encoded = message(MessageMethod::Binding, MessageClass::Request).bytes();
+        let len = encoded.len() - HEADER_LEN;
+
+        // Not a multiple of 4
+        let mut bytes = BytesMut::from(&encoded[..]);
+        bytes[2..4].copy_from_slice(&(len as u16 - 1).to_be_bytes());
+        assert_eq!(
+            Message::from_bytes(&mut bytes.freeze()).unwrap_err(),
+            ParseError::BadLength(len - 1)
+        );
+
+        // Longer than what was received
+        let mut bytes = BytesMut::from(&encoded[..]);
+        bytes[2..4].copy_from_slice(&(len as u16 + 4).to_be_bytes());
+        assert_eq!(
+            Message::from_bytes(&mut bytes.freeze()).unwrap_err(),
+            ParseError::BadLength(len + 4)
+        );
+
+        // Shorter than the header
+        assert_eq!(
+            Message::from_bytes(&mut encoded.slice(..HEADER_LEN - 1)).unwrap_err(),
+            ParseError::Truncated
+        );
+    }
+
+    #[test]
+    fn rfc5769_request() {
+        let mut bytes = vector(REQUEST);
+        assert!(Message::check_integrity(&bytes, PASSWORD));
+        assert!(!Message::check_integrity(&bytes, b"wrong"));
+
+        let msg = Message::from_bytes_with_key(&mut bytes, PASSWORD).unwrap();
+        assert_eq!(msg.method, MessageMethod::Binding);
+        assert_eq!(msg.class, MessageClass::Request);
+        assert_eq!(msg.id, ID);
+        assert_eq!(
+            msg.attribute(AttributeType::Username),
+            Some(&Attribute::Username(String::from("evtj:h6vY")))
+        );
+        assert_eq!(
+            msg.attribute(AttributeType::Priority),
+            Some(&Attribute::Priority(0x6e0001ff))
+        );
+        assert_eq!(
+            msg.attribute(AttributeType::IceControlled),
+            Some(&Attribute::IceControlled(0x932ff9b151263b36))
+        );
+    }
+
+    #[test]
+    fn rfc5769_responses() {
+        for (hex, addr) in [
+            (RESPONSE_V4, "192.0.2.1:32853"),
+            (RESPONSE_V6, "[2001:db8:1234:5678:11:2233:4455:6677]:32853"),
+        ] {
+            let mut bytes = vector(hex);
+            let msg = Message::from_bytes_with_key(&mut bytes, PASSWORD).unwrap();
+            assert_eq!(msg.class, MessageClass::ResponseSuccess);
+            assert_eq!(
+                msg.mapped_address(),
+                Some(addr.parse::<SocketAddr>().unwrap())
+            );
+        }
+    }
+
+    #[test]
+    fn rfc5769_bad_fingerprint() {
+        let mut bytes = BytesMut::from(&vector(RESPONSE_V4)[..]);
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert_eq!(
+            Message::from_bytes(&mut bytes.freeze()).unwrap_err(),
+            ParseError::BadFingerprint
+        );
+    }
+
+    #[test]
+    fn integrity_covers_adjusted_length() {
+        // With FINGERPRINT after it, MESSAGE-INTEGRITY is still checked as if
+        // it were the last attribute
+        let encoded = message(MessageMethod::Binding, MessageClass::ResponseSuccess)
+            .encode(Some(PASSWORD), true);
+        assert!(Message::check_integrity(&encoded, PASSWORD));
 
+        let mut tampered = BytesMut::from(&encoded[..]);
+        tampered[HEADER_LEN + 4] ^= 1;
+        assert!(!Message::check_integrity(&tampered, PASSWORD));
     }
 }
@@ -0,0 +1,462 @@
+//! Messages borrowed from a receive buffer, for servers answering many
+//! requests
+//!
+//! [`MessageRef`] only checks the header up front. Attributes are found by
+//! walking the buffer each time and their values are slices of it, so
+//! nothing is allocated or copied. [`MessageWriter`] builds a response in a
+//! buffer which is kept from one request to the next.
+
+use std::net::SocketAddr;
+
+use bytes::{BufMut, Bytes};
+
+use crate::{
+    attribure::{self, Attribute, AttributeType},
+    error::ParseError,
+    integrity,
+    message::{Message, MessageClass, MessageMethod, MessageType, HEADER_LEN, MAGIC_COOKIE},
+};
+
+#[derive(Clone, Copy, Debug)]
+pub struct MessageRef<'a> {
+    raw: &'a [u8],
+}
+
+impl<'a> MessageRef<'a> {
+    /// Check the header: the two zero bits, the magic cookie and that the
+    /// length matches `raw`.
+    pub fn new(raw: &'a [u8]) -> Result<Self, ParseError> {
+        if raw.len() < HEADER_LEN {
+            return Err(ParseError::Truncated);
+        }
+        if raw[0] & 0xC0 != 0 {
+            return Err(ParseError::NotStun);
+        }
+        let len = u16::from_be_bytes([raw[2], raw[3]]) as usize;
+        if !len.is_multiple_of(4) || raw.len() != HEADER_LEN + len {
+            return Err(ParseError::BadLength(len));
+        }
+        let magic_cookie = u32::from_be_bytes(raw[4..8].try_into().expect("4 bytes"));
+        if magic_cookie != MAGIC_COOKIE {
+            return Err(ParseError::BadMagicCookie(magic_cookie));
+        }
+        Ok(Self { raw })
+    }
+
+    pub fn raw(&self) -> &'a [u8] {
+        self.raw
+    }
+
+    pub fn message_type(&self) -> MessageType {
+        MessageType::from_bytes([self.raw[0], self.raw[1]])
+    }
+
+    pub fn method(&self) -> Option<MessageMethod> {
+        self.message_type().method()
+    }
+
+    pub fn class(&self) -> MessageClass {
+        self.message_type().class()
+    }
+
+    pub fn id(&self) -> &'a [u8; 12] {
+        self.raw[8..HEADER_LEN].try_into().expect("12 bytes")
+    }
+
+    /// The attributes in order. A truncated one ends the iteration with an
+    /// error.
+    pub fn attributes(&self) -> Attributes<'a> {
+        Attributes {
+            raw: self.raw,
+            offset: HEADER_LEN,
+        }
+    }
+
+    /// The first attribute of a type, if all before it are well formed
+    pub fn attribute(&self, attrib_type: AttributeType) -> Option<AttributeRef<'a>> {
+        let value = attrib_type.value();
+        self.attributes()
+            .map_while(Result::ok)
+            .find(|a| a.type_value == value)
+    }
+
+    /// Check MESSAGE-INTEGRITY against `key`, without copying the message.
+    /// False if it has none.
+    pub fn check_integrity(&self, key: &[u8]) -> bool {
+        let Some(integrity) = self.attribute(AttributeType::MessageIntegrity) else {
+            return false;
+        };
+        // The length as if MESSAGE-INTEGRITY were the last attribute
+        let len = (integrity.offset - HEADER_LEN + 4 + 20) as u16;
+        integrity::hmac_sha1_verify_parts(
+            key,
+            &[
+                &self.raw[..2],
+                &len.to_be_bytes(),
+                &self.raw[4..integrity.offset],
+            ],
+            integrity.value,
+        )
+    }
+
+    /// False if there is a FINGERPRINT which is not the last attribute or
+    /// does not match. True if there is none.
+    pub fn check_fingerprint(&self) -> bool {
+        let Some(fingerprint) = self.attribute(AttributeType::Fingerprint) else {
+            return true;
+        };
+        match fingerprint.u32() {
+            Some(crc) => {
+                fingerprint.offset + 8 == self.raw.len()
+                    && integrity::fingerprint(&self.raw[..fingerprint.offset]) == crc
+            }
+            None => false,
+        }
+    }
+
+    /// Decode it all into an owned message
+    pub fn to_message(&self) -> Result<Message, ParseError> {
+        Message::from_bytes(&mut Bytes::copy_from_slice(self.raw))
+    }
+}
+
+pub struct Attributes<'a> {
+    raw: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for Attributes<'a> {
+    type Item = Result<AttributeRef<'a>, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.raw.len() {
+            return None;
+        }
+        let header = self.raw.get(self.offset..self.offset + 4);
+        let attribute = header.and_then(|header| {
+            let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+            let value = self.raw.get(self.offset + 4..self.offset + 4 + len)?;
+            Some(AttributeRef {
+                type_value: u16::from_be_bytes([header[0], header[1]]),
+                offset: self.offset,
+                value,
+                message: self.raw,
+            })
+        });
+        match attribute {
+            Some(attribute) => {
+                self.offset += 4 + attribute.value.len().next_multiple_of(4);
+                Some(Ok(attribute))
+            }
+            None => {
+                self.offset = self.raw.len();
+                Some(Err(ParseError::Truncated))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AttributeRef<'a> {
+    type_value: u16,
+    /// Where the attribute starts in the message
+    offset: usize,
+    value: &'a [u8],
+    message: &'a [u8],
+}
+
+impl<'a> AttributeRef<'a> {
+    pub fn attrib_type(&self) -> AttributeType {
+        AttributeType::from_bytes(self.type_value.to_be_bytes())
+    }
+
+    /// The value, without padding
+    pub fn value(&self) -> &'a [u8] {
+        self.value
+    }
+
+    /// USERNAME, REALM, NONCE, SOFTWARE, ...
+    pub fn str(&self) -> Option<&'a str> {
+        std::str::from_utf8(self.value).ok()
+    }
+
+    pub fn u32(&self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.value.try_into().ok()?))
+    }
+
+    pub fn u64(&self) -> Option<u64> {
+        Some(u64::from_be_bytes(self.value.try_into().ok()?))
+    }
+
+    /// The address of a MAPPED-ADDRESS style attribute, un-XOR'ed for the
+    /// XOR ones
+    pub fn address(&self) -> Option<SocketAddr> {
+        let addr = attribure::get_address(self.value)?;
+        match self.attrib_type() {
+            AttributeType::XorMappedAddress
+            | AttributeType::XorPeerAddress
+            | AttributeType::XorRelayedAddress => Some(attribure::xor_address(addr, &self.id())),
+            _ => Some(addr),
+        }
+    }
+
+    /// Decode into an owned attribute
+    pub fn to_attribute(&self) -> Result<Attribute, ParseError> {
+        let end = self.offset + 4 + self.value.len().next_multiple_of(4);
+        let raw = &self.message[self.offset..end.min(self.message.len())];
+        Attribute::from_bytes(&mut Bytes::copy_from_slice(raw), &self.id())
+    }
+
+    fn id(&self) -> [u8; 12] {
+        self.message[8..HEADER_LEN].try_into().expect("12 bytes")
+    }
+}
+
+/// Writes a message into a buffer which is cleared, not freed, between
+/// messages
+pub struct MessageWriter<'b> {
+    buf: &'b mut Vec<u8>,
+    id: [u8; 12],
+}
+
+impl<'b> MessageWriter<'b> {
+    pub fn new(buf: &'b mut Vec<u8>, msg_type: MessageType, id: [u8; 12]) -> Self {
+        buf.clear();
+        buf.put_slice(&msg_type.bytes());
+        buf.put_u16(0);
+        buf.put_u32(MAGIC_COOKIE);
+        buf.put_slice(&id);
+        Self { buf, id }
+    }
+
+    /// A response to `request`, with the same method and transaction ID
+    pub fn response(buf: &'b mut Vec<u8>, request: &MessageRef, class: MessageClass) -> Self {
+        let method = request.message_type().method_value();
+        Self::new(buf, MessageType::from_parts(method, class), *request.id())
+    }
+
+    /// Append an attribute with its value written by `value`, padded.
+    pub fn put_with(&mut self, attrib_type: AttributeType, value: impl FnOnce(&mut Vec<u8>)) {
+        let start = self.buf.len();
+        self.buf.put_slice(&attrib_type.bytes());
+        self.buf.put_u16(0);
+        value(self.buf);
+        let len = (self.buf.len() - start - 4) as u16;
+        self.buf[start + 2..start + 4].copy_from_slice(&len.to_be_bytes());
+        while !self.buf.len().is_multiple_of(4) {
+            self.buf.put_u8(0);
+        }
+        self.set_length(0);
+    }
+
+    pub fn put(&mut self, attrib_type: AttributeType, value: &[u8]) {
+        self.put_with(attrib_type, |buf| buf.put_slice(value));
+    }
+
+    /// A MAPPED-ADDRESS style attribute, XOR'ed for the XOR ones
+    pub fn put_address(&mut self, attrib_type: AttributeType, addr: SocketAddr) {
+        let addr = match attrib_type {
+            AttributeType::XorMappedAddress
+            | AttributeType::XorPeerAddress
+            | AttributeType::XorRelayedAddress => attribure::xor_address(addr, &self.id),
+            _ => addr,
+        };
+        self.put_with(attrib_type, |buf| attribure::put_address(buf, addr));
+    }
+
+    /// Any attribute, at the cost of encoding it separately first
+    pub fn put_attribute(&mut self, attribute: &Attribute) {
+        self.buf.put_slice(&attribute.bytes(&self.id));
+        self.set_length(0);
+    }
+
+    /// Append MESSAGE-INTEGRITY when given a key and then FINGERPRINT if
+    /// asked for, and return the message.
+    pub fn finish(mut self, key: Option<&[u8]>, fingerprint: bool) -> &'b [u8] {
+        if let Some(key) = key {
+            self.set_length(4 + 20);
+            let hmac = integrity::hmac_sha1(key, self.buf);
+            self.buf.put_slice(&AttributeType::MessageIntegrity.bytes());
+            self.buf.put_u16(20);
+            self.buf.put_slice(&hmac);
+        }
+        if fingerprint {
+            self.set_length(4 + 4);
+            let crc = integrity::fingerprint(self.buf);
+            self.buf.put_slice(&AttributeType::Fingerprint.bytes());
+            self.buf.put_u16(4);
+            self.buf.put_u32(crc);
+        }
+        self.set_length(0);
+        self.buf
+    }
+
+    /// Set the header length to cover what has been written plus `extra`
+    fn set_length(&mut self, extra: usize) {
+        let len = (self.buf.len() - HEADER_LEN + extra) as u16;
+        self.buf[2..4].copy_from_slice(&len.to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::tests::{vector, ID, PASSWORD, REQUEST, RESPONSE_V4, RESPONSE_V6};
+
+    #[test]
+    fn rfc5769_vectors() {
+        for hex in [REQUEST, RESPONSE_V4, RESPONSE_V6] {
+            let raw = vector(hex);
+            let owned = Message::from_bytes(&mut raw.clone()).unwrap();
+            let msg = MessageRef::new(&raw).unwrap();
+            assert_eq!(msg.method(), Some(owned.method));
+            assert_eq!(msg.class(), owned.class);
+            assert_eq!(msg.id(), &ID);
+
+            let attributes: Vec<_> = msg
+                .attributes()
+                .map(|a| a.unwrap().to_attribute().unwrap())
+                .collect();
+            assert_eq!(attributes, owned.attributes);
+            assert_eq!(msg.to_message().unwrap().attributes, owned.attributes);
+
+            assert!(msg.check_integrity(PASSWORD));
+            assert!(!msg.check_integrity(b"wrong"));
+            assert!(Message::check_integrity(&raw, PASSWORD));
+            assert!(msg.check_fingerprint());
+
+            let mapped = msg
+                .attribute(AttributeType::XorMappedAddress)
+                .and_then(|a| a.address());
+            assert_eq!(mapped, owned.mapped_address());
+        }
+
+        let raw = vector(REQUEST);
+        let msg = MessageRef::new(&raw).unwrap();
+        let username = msg.attribute(AttributeType::Username).unwrap();
+        assert_eq!(username.str(), Some("evtj:h6vY"));
+        let priority = msg.attribute(AttributeType::Priority).unwrap();
+        assert_eq!(priority.u32(), Some(0x6e0001ff));
+        let tie_breaker = msg.attribute(AttributeType::IceControlled).unwrap();
+        assert_eq!(tie_breaker.u64(), Some(0x932ff9b151263b36));
+    }
+
+    #[test]
+    fn header_rejected_like_owned() {
+        let raw = vector(RESPONSE_V4);
+        let len = raw.len() - HEADER_LEN;
+        let mut cases = Vec::new();
+
+        let mut bad = raw.to_vec();
+        bad[2..4].copy_from_slice(&(len as u16 + 4).to_be_bytes());
+        cases.push((bad, ParseError::BadLength(len + 4)));
+
+        let mut bad = raw.to_vec();
+        bad[2..4].copy_from_slice(&(len as u16 - 2).to_be_bytes());
+        cases.push((bad, ParseError::BadLength(len - 2)));
+
+        let mut bad = raw.to_vec();
+        bad[4] ^= 0xff;
+        cases.push((bad, ParseError::BadMagicCookie(MAGIC_COOKIE ^ 0xff00_0000)));
+
+        for bit in [0x80, 0x40] {
+            let mut bad = raw.to_vec();
+            bad[0] |= bit;
+            cases.push((bad, ParseError::NotStun));
+        }
+
+        cases.push((raw[..HEADER_LEN - 1].to_vec(), ParseError::Truncated));
+
+        for (bad, expected) in cases {
+            assert_eq!(MessageRef::new(&bad).unwrap_err(), expected);
+            assert_eq!(
+                Message::from_bytes(&mut Bytes::from(bad)).unwrap_err(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_last_attribute() {
+        // FINGERPRINT claiming 8 bytes where there are 4
+        let mut raw = vector(RESPONSE_V4).to_vec();
+        let at = raw.len() - 8;
+        raw[at + 2..at + 4].copy_from_slice(&8u16.to_be_bytes());
+
+        let msg = MessageRef::new(&raw).unwrap();
+        let attributes: Vec<_> = msg.attributes().collect();
+        assert_eq!(attributes.len(), 4);
+        assert!(attributes[..3].iter().all(Result::is_ok));
+        assert_eq!(attributes[3].as_ref().unwrap_err(), &ParseError::Truncated);
+        assert!(msg.attribute(AttributeType::Fingerprint).is_none());
+        assert!(msg.attribute(AttributeType::Software).is_some());
+        assert_eq!(msg.to_message().unwrap_err(), ParseError::Truncated);
+        assert_eq!(
+            Message::from_bytes(&mut Bytes::from(raw)).unwrap_err(),
+            ParseError::Truncated
+        );
+    }
+
+    #[test]
+    fn bad_fingerprint() {
+        let mut raw = vector(RESPONSE_V4).to_vec();
+        let last = raw.len() - 1;
+        raw[last] ^= 1;
+        let msg = MessageRef::new(&raw).unwrap();
+        assert!(!msg.check_fingerprint());
+        assert!(msg.check_integrity(PASSWORD));
+        assert_eq!(msg.to_message().unwrap_err(), ParseError::BadFingerprint);
+
+        // Covered by MESSAGE-INTEGRITY, but not by FINGERPRINT
+        let mut raw = vector(RESPONSE_V4).to_vec();
+        raw[HEADER_LEN + 4] ^= 1;
+        let msg = MessageRef::new(&raw).unwrap();
+        assert!(!msg.check_fingerprint());
+        assert!(!msg.check_integrity(PASSWORD));
+        assert!(!Message::check_integrity(&raw, PASSWORD));
+    }
+
+    #[test]
+    fn write_response() {
+        let request = vector(REQUEST);
+        let request = MessageRef::new(&request).unwrap();
+        let from: SocketAddr = "[2001:db8:1234:5678:11:2233:4455:6677]:32853"
+            .parse()
+            .unwrap();
+        let mut buf = Vec::new();
+
+        // The buffer is reused
+        for _ in 0..2 {
+            let mut writer =
+                MessageWriter::response(&mut buf, &request, MessageClass::ResponseSuccess);
+            writer.put(AttributeType::Software, b"test vector");
+            writer.put_address(AttributeType::XorMappedAddress, from);
+            writer.put_attribute(&Attribute::ErrorCode(420, String::from("Unknown")));
+            let raw = writer.finish(Some(PASSWORD), true);
+
+            let msg = MessageRef::new(raw).unwrap();
+            assert!(msg.check_integrity(PASSWORD));
+            assert!(msg.check_fingerprint());
+
+            let mut bytes = Bytes::copy_from_slice(raw);
+            let owned = Message::from_bytes_with_key(&mut bytes, PASSWORD).unwrap();
+            assert_eq!(owned.method, MessageMethod::Binding);
+            assert_eq!(owned.class, MessageClass::ResponseSuccess);
+            assert_eq!(owned.id, ID);
+            assert_eq!(owned.mapped_address(), Some(from));
+            assert_eq!(
+                owned.attributes[..3],
+                [
+                    Attribute::Software(String::from("test vector")),
+                    Attribute::XorMappedAddress(from),
+                    Attribute::ErrorCode(420, String::from("Unknown")),
+                ]
+            );
+            assert!(matches!(
+                owned.attributes[3],
+                Attribute::MessageIntegrity(_)
+            ));
+            assert!(matches!(owned.attributes[4], Attribute::Fingerprint(_)));
+        }
+    }
+}
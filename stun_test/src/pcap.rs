@@ -0,0 +1,570 @@
+//! UDP payloads from pcap and pcapng captures, e.g. from tcpdump or Wireshark
+//!
+//! Only what we need to look at STUN: Ethernet (with VLAN tags), Linux
+//! cooked, loopback and raw IP link types, IPv4 and IPv6 without extension
+//! headers, and no reassembly of fragments.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use crate::error::PcapError;
+
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW_OLD: u32 = 12;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LOOP: u32 = 108;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+const PCAPNG_SECTION_HEADER: u32 = 0x0A0D0D0A;
+const PCAPNG_INTERFACE: u32 = 1;
+const PCAPNG_SIMPLE_PACKET: u32 = 3;
+const PCAPNG_ENHANCED_PACKET: u32 = 6;
+const PCAPNG_BYTE_ORDER: u32 = 0x1A2B3C4D;
+
+const IPPROTO_UDP: u8 = 17;
+
+#[derive(Debug)]
+pub struct UdpPacket {
+    /// Position in the capture, from 1 as in Wireshark
+    pub number: usize,
+    /// Since the Unix epoch
+    pub time: Duration,
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub payload: Vec<u8>,
+}
+
+/// The UDP packets in a capture. A capture cut short gives the packets
+/// before the cut.
+pub fn udp_packets(data: &[u8]) -> Result<Vec<UdpPacket>, PcapError> {
+    let magic = data.get(..4).ok_or(PcapError::Truncated)?;
+    if magic == PCAPNG_SECTION_HEADER.to_le_bytes() {
+        pcapng(data)
+    } else {
+        pcap(data)
+    }
+}
+
+/// Reads integers in the capture's byte order
+#[derive(Clone, Copy)]
+struct Endian {
+    big: bool,
+}
+
+impl Endian {
+    fn u16(&self, data: &[u8], at: usize) -> Option<u16> {
+        let bytes = data.get(at..at + 2)?.try_into().ok()?;
+        Some(if self.big {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, data: &[u8], at: usize) -> Option<u32> {
+        let bytes = data.get(at..at + 4)?.try_into().ok()?;
+        Some(if self.big {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+}
+
+fn pcap(data: &[u8]) -> Result<Vec<UdpPacket>, PcapError> {
+    let magic = u32::from_le_bytes(data[..4].try_into().expect("4 bytes"));
+    let (endian, nanos) = match magic {
+        0xA1B2C3D4 => (Endian { big: false }, false),
+        0xD4C3B2A1 => (Endian { big: true }, false),
+        0xA1B23C4D => (Endian { big: false }, true),
+        0x4D3CB2A1 => (Endian { big: true }, true),
+        _ => return Err(PcapError::NotPcap),
+    };
+    let link_type = endian.u32(data, 20).ok_or(PcapError::Truncated)? & 0x0FFF_FFFF;
+
+    let mut packets = Vec::new();
+    let mut offset = 24;
+    let mut number = 0;
+    while offset < data.len() {
+        let record = (|| {
+            let secs = endian.u32(data, offset)?;
+            let frac = endian.u32(data, offset + 4)?;
+            let len = endian.u32(data, offset + 8)? as usize;
+            let frame = data.get(offset + 16..offset + 16 + len)?;
+            let time = if nanos {
+                Duration::new(secs as u64, frac)
+            } else {
+                Duration::new(secs as u64, 0) + Duration::from_micros(frac as u64)
+            };
+            Some((time, frame))
+        })();
+        let Some((time, frame)) = record else {
+            log::warn!("Capture cut short after {} packets", number);
+            break;
+        };
+        number += 1;
+        offset += 16 + frame.len();
+        if let Some(packet) = udp_packet(number, time, link_type, frame) {
+            packets.push(packet);
+        }
+    }
+    Ok(packets)
+}
+
+fn pcapng(data: &[u8]) -> Result<Vec<UdpPacket>, PcapError> {
+    let mut packets = Vec::new();
+    let mut endian = Endian { big: false };
+    // Link type and timestamp units per second of each interface in the
+    // current section
+    let mut interfaces: Vec<(u32, u64)> = Vec::new();
+    let mut offset = 0;
+    let mut number = 0;
+    while offset < data.len() {
+        if data.get(offset..offset + 4) == Some(&PCAPNG_SECTION_HEADER.to_le_bytes()) {
+            let byte_order = data
+                .get(offset + 8..offset + 12)
+                .ok_or(PcapError::Truncated)?;
+            endian = match byte_order.try_into().map(u32::from_le_bytes) {
+                Ok(PCAPNG_BYTE_ORDER) => Endian { big: false },
+                Ok(x) if x.swap_bytes() == PCAPNG_BYTE_ORDER => Endian { big: true },
+                _ => return Err(PcapError::NotPcap),
+            };
+            interfaces.clear();
+        }
+        let (Some(block_type), Some(len)) =
+            (endian.u32(data, offset), endian.u32(data, offset + 4))
+        else {
+            log::warn!("Capture cut short after {} packets", number);
+            break;
+        };
+        let len = len as usize;
+        let Some(body) = data.get(offset + 8..(offset + len).saturating_sub(4)) else {
+            log::warn!("Capture cut short after {} packets", number);
+            break;
+        };
+        if len < 12 {
+            return Err(PcapError::NotPcap);
+        }
+        offset += len;
+
+        match block_type {
+            PCAPNG_INTERFACE => {
+                let link_type = endian.u16(body, 0).unwrap_or(u16::MAX) as u32;
+                interfaces.push((link_type, ts_resolution(endian, body)));
+            }
+            PCAPNG_ENHANCED_PACKET => {
+                number += 1;
+                let packet = (|| {
+                    let (link_type, per_sec) = *interfaces.get(endian.u32(body, 0)? as usize)?;
+                    let ts = ((endian.u32(body, 4)? as u64) << 32) | endian.u32(body, 8)? as u64;
+                    // In u128, as the fraction overflows u64 at resolutions
+                    // finer than about 2^-34 s
+                    let nanos = (ts % per_sec) as u128 * 1_000_000_000 / per_sec as u128;
+                    let time =
+                        Duration::from_secs(ts / per_sec) + Duration::from_nanos(nanos as u64);
+                    let len = endian.u32(body, 12)? as usize;
+                    udp_packet(number, time, link_type, body.get(20..20 + len)?)
+                })();
+                packets.extend(packet);
+            }
+            PCAPNG_SIMPLE_PACKET => {
+                number += 1;
+                let packet = (|| {
+                    let (link_type, _) = *interfaces.first()?;
+                    let data = body.get(4..)?;
+                    let len = (endian.u32(body, 0)? as usize).min(data.len());
+                    udp_packet(number, Duration::ZERO, link_type, &data[..len])
+                })();
+                packets.extend(packet);
+            }
+            _ => {}
+        }
+    }
+    Ok(packets)
+}
+
+/// Timestamp units per second from an interface description's if_tsresol
+/// option, microseconds by default
+fn ts_resolution(endian: Endian, body: &[u8]) -> u64 {
+    let mut offset = 8;
+    while let (Some(code), Some(len)) = (endian.u16(body, offset), endian.u16(body, offset + 2)) {
+        if code == 0 {
+            break;
+        }
+        if code == 9 && len >= 1 {
+            if let Some(&x) = body.get(offset + 4) {
+                let exponent = (x & 0x7F) as u32;
+                let base: u64 = if x & 0x80 == 0 { 10 } else { 2 };
+                return base.checked_pow(exponent).unwrap_or(1_000_000).max(1);
+            }
+        }
+        offset += 4 + (len as usize).next_multiple_of(4);
+    }
+    1_000_000
+}
+
+fn udp_packet(number: usize, time: Duration, link_type: u32, frame: &[u8]) -> Option<UdpPacket> {
+    let ip = match link_type {
+        LINKTYPE_ETHERNET => {
+            let mut offset = 12;
+            let mut ether_type =
+                u16::from_be_bytes(frame.get(offset..offset + 2)?.try_into().ok()?);
+            // VLAN tags
+            while matches!(ether_type, 0x8100 | 0x88A8) {
+                offset += 4;
+                ether_type = u16::from_be_bytes(frame.get(offset..offset + 2)?.try_into().ok()?);
+            }
+            frame.get(offset + 2..)?
+        }
+        LINKTYPE_NULL | LINKTYPE_LOOP => frame.get(4..)?,
+        LINKTYPE_LINUX_SLL => frame.get(16..)?,
+        LINKTYPE_LINUX_SLL2 => frame.get(20..)?,
+        LINKTYPE_RAW | LINKTYPE_RAW_OLD | LINKTYPE_IPV4 | LINKTYPE_IPV6 => frame,
+        _ => {
+            log::debug!("Skipping packet {} with link type {}", number, link_type);
+            return None;
+        }
+    };
+
+    let (source, destination, udp) = match ip.first()? >> 4 {
+        4 => {
+            let header_len = ((ip[0] & 0x0F) as usize) * 4;
+            let total_len = u16::from_be_bytes(ip.get(2..4)?.try_into().ok()?) as usize;
+            let fragment = u16::from_be_bytes(ip.get(6..8)?.try_into().ok()?);
+            // More fragments, or not the first
+            if fragment & 0x3FFF != 0 || *ip.get(9)? != IPPROTO_UDP {
+                return None;
+            }
+            let source: [u8; 4] = ip.get(12..16)?.try_into().ok()?;
+            let destination: [u8; 4] = ip.get(16..20)?.try_into().ok()?;
+            (
+                IpAddr::V4(Ipv4Addr::from(source)),
+                IpAddr::V4(Ipv4Addr::from(destination)),
+                ip.get(header_len..total_len.min(ip.len()))?,
+            )
+        }
+        6 => {
+            if *ip.get(6)? != IPPROTO_UDP {
+                return None;
+            }
+            let payload_len = u16::from_be_bytes(ip.get(4..6)?.try_into().ok()?) as usize;
+            let source: [u8; 16] = ip.get(8..24)?.try_into().ok()?;
+            let destination: [u8; 16] = ip.get(24..40)?.try_into().ok()?;
+            (
+                IpAddr::V6(Ipv6Addr::from(source)),
+                IpAddr::V6(Ipv6Addr::from(destination)),
+                ip.get(40..(40 + payload_len).min(ip.len()))?,
+            )
+        }
+        _ => return None,
+    };
+
+    let source_port = u16::from_be_bytes(udp.get(0..2)?.try_into().ok()?);
+    let destination_port = u16::from_be_bytes(udp.get(2..4)?.try_into().ok()?);
+    let len = u16::from_be_bytes(udp.get(4..6)?.try_into().ok()?) as usize;
+    Some(UdpPacket {
+        number,
+        time,
+        source: SocketAddr::new(source, source_port),
+        destination: SocketAddr::new(destination, destination_port),
+        payload: udp.get(8..len.min(udp.len()))?.to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECS: u32 = 1_700_000_000;
+
+    /// Writes integers in a capture's byte order
+    struct Writer {
+        big: bool,
+        buf: Vec<u8>,
+    }
+
+    impl Writer {
+        fn new(big: bool) -> Self {
+            Self {
+                big,
+                buf: Vec::new(),
+            }
+        }
+
+        fn u16(&mut self, x: u16) -> &mut Self {
+            let bytes = if self.big {
+                x.to_be_bytes()
+            } else {
+                x.to_le_bytes()
+            };
+            self.buf.extend_from_slice(&bytes);
+            self
+        }
+
+        fn u32(&mut self, x: u32) -> &mut Self {
+            let bytes = if self.big {
+                x.to_be_bytes()
+            } else {
+                x.to_le_bytes()
+            };
+            self.buf.extend_from_slice(&bytes);
+            self
+        }
+
+        fn bytes(&mut self, x: &[u8]) -> &mut Self {
+            self.buf.extend_from_slice(x);
+            self
+        }
+
+        fn pad(&mut self) -> &mut Self {
+            while !self.buf.len().is_multiple_of(4) {
+                self.buf.push(0);
+            }
+            self
+        }
+    }
+
+    fn udp(payload: &[u8]) -> Vec<u8> {
+        let mut udp = vec![0x04, 0xd2, 0x0d, 0x96];
+        udp.extend_from_slice(&(8 + payload.len() as u16).to_be_bytes());
+        udp.extend_from_slice(&[0, 0]);
+        udp.extend_from_slice(payload);
+        udp
+    }
+
+    /// 192.0.2.1:1234 -> 198.51.100.2:3478
+    fn ipv4(payload: &[u8]) -> Vec<u8> {
+        let udp = udp(payload);
+        let mut ip = vec![0x45, 0];
+        ip.extend_from_slice(&(20 + udp.len() as u16).to_be_bytes());
+        ip.extend_from_slice(&[0, 0, 0x40, 0, 64, IPPROTO_UDP, 0, 0]);
+        ip.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 2]);
+        ip.extend_from_slice(&udp);
+        ip
+    }
+
+    /// [2001:db8::1]:1234 -> [2001:db8::2]:3478
+    fn ipv6(payload: &[u8]) -> Vec<u8> {
+        let udp = udp(payload);
+        let mut ip = vec![0x60, 0, 0, 0];
+        ip.extend_from_slice(&(udp.len() as u16).to_be_bytes());
+        ip.extend_from_slice(&[IPPROTO_UDP, 64]);
+        for last in [1, 2] {
+            let mut addr = [0; 16];
+            addr[..4].copy_from_slice(&[0x20, 0x01, 0x0d, 0xb8]);
+            addr[15] = last;
+            ip.extend_from_slice(&addr);
+        }
+        ip.extend_from_slice(&udp);
+        ip
+    }
+
+    fn ethernet(ip: &[u8], vlans: usize) -> Vec<u8> {
+        let mut frame = vec![0; 12];
+        for _ in 0..vlans {
+            frame.extend_from_slice(&[0x81, 0x00, 0x00, 0x2a]);
+        }
+        let ether_type: u16 = if ip[0] >> 4 == 6 { 0x86dd } else { 0x0800 };
+        frame.extend_from_slice(&ether_type.to_be_bytes());
+        frame.extend_from_slice(ip);
+        frame
+    }
+
+    fn pcap_file(big: bool, nanos: bool, link_type: u32, records: &[(u32, &[u8])]) -> Vec<u8> {
+        let mut w = Writer::new(big);
+        w.u32(if nanos { 0xA1B23C4D } else { 0xA1B2C3D4 })
+            .u16(2)
+            .u16(4)
+            .u32(0)
+            .u32(0)
+            .u32(65535)
+            .u32(link_type);
+        for (frac, frame) in records {
+            w.u32(SECS)
+                .u32(*frac)
+                .u32(frame.len() as u32)
+                .u32(frame.len() as u32)
+                .bytes(frame);
+        }
+        w.buf
+    }
+
+    fn assert_v4(packet: &UdpPacket, number: usize, payload: &[u8]) {
+        assert_eq!(packet.number, number);
+        assert_eq!(packet.source, "192.0.2.1:1234".parse().unwrap());
+        assert_eq!(packet.destination, "198.51.100.2:3478".parse().unwrap());
+        assert_eq!(packet.payload, payload);
+    }
+
+    #[test]
+    fn pcap_byte_orders_and_resolutions() {
+        for big in [false, true] {
+            for (nanos, frac, expected) in [
+                (false, 123_456, Duration::from_micros(123_456)),
+                (true, 123_456_789, Duration::from_nanos(123_456_789)),
+            ] {
+                let frame = ethernet(&ipv4(b"hello"), 0);
+                let file = pcap_file(big, nanos, LINKTYPE_ETHERNET, &[(frac, &frame)]);
+                let packets = udp_packets(&file).unwrap();
+                assert_eq!(packets.len(), 1);
+                assert_v4(&packets[0], 1, b"hello");
+                assert_eq!(packets[0].time, Duration::from_secs(SECS as u64) + expected);
+            }
+        }
+    }
+
+    #[test]
+    fn link_types() {
+        let v6 = |packets: &[UdpPacket]| {
+            assert_eq!(packets.len(), 1);
+            assert_eq!(packets[0].source, "[2001:db8::1]:1234".parse().unwrap());
+            assert_eq!(
+                packets[0].destination,
+                "[2001:db8::2]:3478".parse().unwrap()
+            );
+            assert_eq!(packets[0].payload, b"six");
+        };
+        for link_type in [LINKTYPE_RAW, LINKTYPE_IPV6] {
+            let file = pcap_file(false, false, link_type, &[(0, &ipv6(b"six"))]);
+            v6(&udp_packets(&file).unwrap());
+        }
+        let file = pcap_file(
+            false,
+            false,
+            LINKTYPE_ETHERNET,
+            &[(0, &ethernet(&ipv6(b"six"), 2))],
+        );
+        v6(&udp_packets(&file).unwrap());
+
+        let mut sll = vec![0; 14];
+        sll.extend_from_slice(&[0x08, 0x00]);
+        sll.extend_from_slice(&ipv4(b"cooked"));
+        let file = pcap_file(true, false, LINKTYPE_LINUX_SLL, &[(0, &sll)]);
+        assert_v4(&udp_packets(&file).unwrap()[0], 1, b"cooked");
+
+        // Not UDP, or a link type we do not know, still counts
+        let mut tcp = ipv4(b"tcp");
+        tcp[9] = 6;
+        let frames = [ethernet(&tcp, 0), ethernet(&ipv4(b"udp"), 1)];
+        let file = pcap_file(
+            false,
+            false,
+            LINKTYPE_ETHERNET,
+            &[(0, &frames[0]), (0, &frames[1])],
+        );
+        let packets = udp_packets(&file).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_v4(&packets[0], 2, b"udp");
+        let file = pcap_file(false, false, 147, &[(0, &ipv4(b"user"))]);
+        assert!(udp_packets(&file).unwrap().is_empty());
+    }
+
+    fn block(w: &mut Writer, block_type: u32, body: impl FnOnce(&mut Writer)) {
+        let mut inner = Writer::new(w.big);
+        body(&mut inner);
+        inner.pad();
+        let len = 12 + inner.buf.len() as u32;
+        w.u32(block_type).u32(len).bytes(&inner.buf).u32(len);
+    }
+
+    fn interface(w: &mut Writer, link_type: u16, tsresol: Option<u8>) {
+        block(w, PCAPNG_INTERFACE, |w| {
+            w.u16(link_type).u16(0).u32(65535);
+            if let Some(x) = tsresol {
+                w.u16(9).u16(1).bytes(&[x]).pad().u16(0).u16(0);
+            }
+        });
+    }
+
+    fn enhanced(w: &mut Writer, interface: u32, ts: u64, frame: &[u8]) {
+        block(w, PCAPNG_ENHANCED_PACKET, |w| {
+            w.u32(interface)
+                .u32((ts >> 32) as u32)
+                .u32(ts as u32)
+                .u32(frame.len() as u32)
+                .u32(frame.len() as u32)
+                .bytes(frame);
+        });
+    }
+
+    fn pcapng_file(big: bool) -> Vec<u8> {
+        let mut w = Writer::new(big);
+        block(&mut w, PCAPNG_SECTION_HEADER, |w| {
+            w.u32(PCAPNG_BYTE_ORDER)
+                .u16(1)
+                .u16(0)
+                .u32(u32::MAX)
+                .u32(u32::MAX);
+        });
+        interface(&mut w, LINKTYPE_ETHERNET as u16, None);
+        interface(&mut w, LINKTYPE_RAW as u16, Some(9));
+        interface(&mut w, LINKTYPE_RAW as u16, Some(0x80 | 10));
+
+        let frame = ethernet(&ipv4(b"micros"), 0);
+        enhanced(&mut w, 0, SECS as u64 * 1_000_000 + 5, &frame);
+        enhanced(&mut w, 1, SECS as u64 * 1_000_000_000 + 5, &ipv4(b"nanos"));
+        enhanced(&mut w, 2, SECS as u64 * 1024 + 512, &ipv6(b"binary"));
+        // Simple packets too short for a frame, or for their length field
+        block(&mut w, PCAPNG_SIMPLE_PACKET, |w| {
+            w.bytes(&[1, 2]);
+        });
+        block(&mut w, PCAPNG_SIMPLE_PACKET, |_| {});
+        let frame = ethernet(&ipv4(b"simple"), 0);
+        block(&mut w, PCAPNG_SIMPLE_PACKET, |w| {
+            w.u32(frame.len() as u32).bytes(&frame);
+        });
+        w.buf
+    }
+
+    #[test]
+    fn pcapng() {
+        for big in [false, true] {
+            let packets = udp_packets(&pcapng_file(big)).unwrap();
+            let secs = Duration::from_secs(SECS as u64);
+            let summary: Vec<_> = packets
+                .iter()
+                .map(|x| (x.number, x.time, &x.payload[..]))
+                .collect();
+            assert_eq!(
+                summary,
+                [
+                    (1, secs + Duration::from_micros(5), &b"micros"[..]),
+                    (2, secs + Duration::from_nanos(5), b"nanos"),
+                    (3, secs + Duration::from_millis(500), b"binary"),
+                    (6, Duration::ZERO, b"simple"),
+                ]
+            );
+            assert_v4(&packets[0], 1, b"micros");
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        let frames = [ethernet(&ipv4(b"one"), 0), ethernet(&ipv4(b"two"), 0)];
+        let file = pcap_file(
+            false,
+            false,
+            LINKTYPE_ETHERNET,
+            &[(0, &frames[0]), (0, &frames[1])],
+        );
+        for cut in [1, 10, 17] {
+            let packets = udp_packets(&file[..file.len() - cut]).unwrap();
+            assert_eq!(packets.len(), 1);
+            assert_v4(&packets[0], 1, b"one");
+        }
+        assert_eq!(udp_packets(&file[..20]).unwrap_err(), PcapError::Truncated);
+        assert_eq!(udp_packets(&file[..3]).unwrap_err(), PcapError::Truncated);
+        assert_eq!(udp_packets(b"GIF89a").unwrap_err(), PcapError::NotPcap);
+
+        let file = pcapng_file(false);
+        let packets = udp_packets(&file[..file.len() - 6]).unwrap();
+        assert_eq!(packets.len(), 3);
+        assert_eq!(udp_packets(&file[..10]).unwrap_err(), PcapError::Truncated);
+    }
+}
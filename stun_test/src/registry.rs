@@ -0,0 +1,225 @@
+//! Attributes defined by the application, e.g. a peer ID or session token in
+//! connectivity checks
+//!
+//! Register a type before messages carrying it are decoded and it round-trips
+//! through [`Message`](crate::message::Message) as [`Attribute::Custom`], or
+//! as [`Attribute::Unknown`] if its value is invalid. Only
+//! comprehension-optional types (0x8000-0xFFFF) can be registered, so
+//! receivers which do not know them ignore them.
+
+use std::{
+    any::Any,
+    fmt::Debug,
+    sync::{Arc, RwLock},
+};
+
+use bytes::BytesMut;
+
+use crate::{
+    attribure::{Attribute, AttributeType},
+    error::RegistryError,
+};
+
+pub trait CustomAttribute: Debug + Send + Sync + Sized + 'static {
+    /// 0x8000-0xFFFF
+    const TYPE: u16;
+
+    /// Write the value, without the type, length or padding.
+    fn encode(&self, value: &mut BytesMut);
+
+    fn decode(value: &[u8]) -> Option<Self>;
+
+    /// Checked after decoding. A value which fails is kept undecoded, like
+    /// one which cannot be decoded.
+    fn validate(&self) -> bool {
+        true
+    }
+}
+
+/// The value of a registered attribute, whatever its type
+pub trait CustomValue: Debug + Send + Sync {
+    fn attrib_type(&self) -> u16;
+    fn encode(&self, value: &mut BytesMut);
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<T: CustomAttribute> CustomValue for T {
+    fn attrib_type(&self) -> u16 {
+        T::TYPE
+    }
+
+    fn encode(&self, value: &mut BytesMut) {
+        CustomAttribute::encode(self, value)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Values are equal if they have the same type and encoding.
+impl PartialEq for dyn CustomValue {
+    fn eq(&self, other: &Self) -> bool {
+        let (mut a, mut b) = (BytesMut::new(), BytesMut::new());
+        self.encode(&mut a);
+        other.encode(&mut b);
+        self.attrib_type() == other.attrib_type() && a == b
+    }
+}
+
+type Decoder = fn(&[u8]) -> Option<Arc<dyn CustomValue>>;
+
+static REGISTRY: RwLock<Vec<(u16, Decoder)>> = RwLock::new(Vec::new());
+
+fn decode_as<T: CustomAttribute>(value: &[u8]) -> Option<Arc<dyn CustomValue>> {
+    let value = T::decode(value)?;
+    if !value.validate() {
+        return None;
+    }
+    Some(Arc::new(value))
+}
+
+/// Decode attributes of type `T::TYPE` as `T` from now on.
+pub fn register<T: CustomAttribute>() -> Result<(), RegistryError> {
+    if AttributeType::Unknown(T::TYPE).is_comprehension_required() {
+        return Err(RegistryError::ComprehensionRequired(T::TYPE));
+    }
+    let mut registry = REGISTRY.write().unwrap();
+    let known = !matches!(
+        AttributeType::builtin(T::TYPE.to_be_bytes()),
+        AttributeType::Unknown(_)
+    ) || registry.iter().any(|(x, _)| *x == T::TYPE);
+    if known {
+        return Err(RegistryError::AlreadyKnown(T::TYPE));
+    }
+    registry.push((T::TYPE, decode_as::<T>));
+    Ok(())
+}
+
+pub fn is_registered(attrib_type: u16) -> bool {
+    REGISTRY
+        .read()
+        .unwrap()
+        .iter()
+        .any(|(x, _)| *x == attrib_type)
+}
+
+/// Decode and validate the value of a registered attribute.
+pub(crate) fn decode(attrib_type: u16, value: &[u8]) -> Option<Attribute> {
+    let decoder = REGISTRY
+        .read()
+        .unwrap()
+        .iter()
+        .find(|(x, _)| *x == attrib_type)
+        .map(|(_, decoder)| *decoder)?;
+    decoder(value).map(Attribute::Custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Buf, BufMut, Bytes};
+
+    use super::*;
+    use crate::message::{Message, MessageClass, MessageMethod};
+
+    /// Each test registers its own types, as the registry is shared
+    #[derive(Debug, PartialEq)]
+    struct Session(u32);
+
+    impl CustomAttribute for Session {
+        const TYPE: u16 = 0xc001;
+
+        fn encode(&self, value: &mut BytesMut) {
+            value.put_u32(self.0);
+        }
+
+        fn decode(mut value: &[u8]) -> Option<Self> {
+            (value.len() == 4).then(|| Self(value.get_u32()))
+        }
+
+        fn validate(&self) -> bool {
+            self.0 != 0
+        }
+    }
+
+    #[derive(Debug)]
+    struct Required;
+
+    impl CustomAttribute for Required {
+        const TYPE: u16 = 0x7001;
+
+        fn encode(&self, _: &mut BytesMut) {}
+
+        fn decode(_: &[u8]) -> Option<Self> {
+            Some(Self)
+        }
+    }
+
+    #[derive(Debug)]
+    struct Builtin;
+
+    impl CustomAttribute for Builtin {
+        /// SOFTWARE
+        const TYPE: u16 = 0x8022;
+
+        fn encode(&self, _: &mut BytesMut) {}
+
+        fn decode(_: &[u8]) -> Option<Self> {
+            Some(Self)
+        }
+    }
+
+    fn round_trip(attributes: Vec<Attribute>) -> Message {
+        let message = Message {
+            method: MessageMethod::Binding,
+            class: MessageClass::Request,
+            id: [7; 12],
+            attributes,
+        };
+        Message::from_bytes(&mut message.encode(None, true)).unwrap()
+    }
+
+    #[test]
+    fn custom_round_trip() {
+        register::<Session>().unwrap();
+        assert!(is_registered(Session::TYPE));
+        assert!(matches!(
+            register::<Session>(),
+            Err(RegistryError::AlreadyKnown(Session::TYPE))
+        ));
+
+        let message = round_trip(vec![Attribute::custom(Session(42))]);
+        assert_eq!(message.custom::<Session>(), Some(&Session(42)));
+        assert_eq!(
+            message.attributes[0].attrib_type(),
+            AttributeType::Custom(Session::TYPE)
+        );
+
+        // Invalid values are kept as they are, without failing the message
+        for value in [&[0, 0, 0, 0][..], &[1, 2]] {
+            let message = round_trip(vec![Attribute::Unknown(
+                Session::TYPE,
+                Bytes::copy_from_slice(value),
+            )]);
+            assert_eq!(message.custom::<Session>(), None);
+            match &message.attributes[0] {
+                Attribute::Unknown(Session::TYPE, raw) => assert_eq!(raw, value),
+                x => panic!("{:?}", x),
+            }
+        }
+    }
+
+    #[test]
+    fn register_rejected() {
+        assert!(matches!(
+            register::<Required>(),
+            Err(RegistryError::ComprehensionRequired(Required::TYPE))
+        ));
+        assert!(matches!(
+            register::<Builtin>(),
+            Err(RegistryError::AlreadyKnown(Builtin::TYPE))
+        ));
+        assert!(!is_registered(Required::TYPE));
+        assert!(!is_registered(Builtin::TYPE));
+    }
+}
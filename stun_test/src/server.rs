@@ -0,0 +1,467 @@
+//! STUN Binding server (RFC 5389 section 7.3), optionally with the NAT
+//! behaviour discovery extensions of RFC 5780
+
+use std::{collections::HashMap, io, net::SocketAddr, sync::Arc};
+
+use bytes::Bytes;
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream, UdpSocket},
+    task::JoinSet,
+};
+
+use crate::{
+    attribure::{Attribute, AttributeType},
+    error::ParseError,
+    message::{self, Message, MessageClass, MessageMethod, MessageType, MAGIC_COOKIE},
+    message_ref::{MessageRef, MessageWriter},
+    tcp,
+};
+
+pub const SOFTWARE: &str = concat!("stun_test ", env!("CARGO_PKG_VERSION"));
+
+const MAX_MESSAGE_LEN: usize = 1500;
+
+/// Answer Binding requests on `socket` until a receive fails.
+pub async fn run_udp(socket: UdpSocket) -> io::Result<()> {
+    let local = socket.local_addr()?;
+    log::info!("Listening on {}", local);
+    let mut buf = [0; MAX_MESSAGE_LEN];
+    let mut response = Vec::with_capacity(MAX_MESSAGE_LEN);
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        let res = if let Some(bytes) = answer_binding(&buf[..len], from, &mut response) {
+            socket.send_to(bytes, from).await
+        } else if let Some(reply) = handle_message(&buf[..len], from, local, None) {
+            socket.send_to(&reply.bytes, from).await
+        } else {
+            continue;
+        };
+        // One client we cannot reach must not stop the server
+        if let Err(e) = res {
+            log::warn!("Failed to send to {}: {}", from, e);
+        }
+    }
+}
+
+/// Answer a plain Binding request without decoding it or allocating, writing
+/// the response into `buf`. None for anything else, which is left to
+/// [`handle_message`].
+pub fn answer_binding<'b>(raw: &[u8], from: SocketAddr, buf: &'b mut Vec<u8>) -> Option<&'b [u8]> {
+    let request = MessageRef::new(raw).ok()?;
+    if request.class() != MessageClass::Request || request.method() != Some(MessageMethod::Binding)
+    {
+        return None;
+    }
+    // Only attributes whose values can be checked here without decoding are
+    // answered. The rest, including unknown ones which get a 420 and
+    // CHANGE-REQUEST, are left to handle_message so both agree on what is a
+    // bad request.
+    for attribute in request.attributes() {
+        let attribute = attribute.ok()?;
+        match attribute.attrib_type() {
+            AttributeType::Software => {
+                attribute.str()?;
+            }
+            // Checked below
+            AttributeType::Fingerprint => {}
+            t @ AttributeType::Unknown(_) if !t.is_comprehension_required() => {}
+            _ => return None,
+        }
+    }
+    if !request.check_fingerprint() {
+        return None;
+    }
+
+    let mut response = MessageWriter::response(buf, &request, MessageClass::ResponseSuccess);
+    response.put_address(AttributeType::XorMappedAddress, from);
+    response.put(AttributeType::Software, SOFTWARE.as_bytes());
+    log::trace!("Binding request from {}", from);
+    Some(response.finish(None, true))
+}
+
+/// Answer Binding requests on each connection accepted by `listener` until
+/// an I/O error.
+pub async fn run_tcp(listener: TcpListener) -> io::Result<()> {
+    let local = listener.local_addr()?;
+    log::info!("Listening on {} (TCP)", local);
+    loop {
+        let (stream, from) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = tcp_connection_task(stream, from, local).await {
+                log::debug!("Connection from {} closed: {}", from, e);
+            }
+        });
+    }
+}
+
+async fn tcp_connection_task(
+    mut stream: TcpStream,
+    from: SocketAddr,
+    local: SocketAddr,
+) -> io::Result<()> {
+    loop {
+        let raw = tcp::read_message(&mut stream).await?;
+        if let Some(reply) = handle_message(&raw, from, local, None) {
+            stream.write_all(&reply.bytes).await?;
+        }
+    }
+}
+
+/// Answer Binding requests on the four combinations of the IPs and ports of
+/// `primary` and `alternate`, honouring CHANGE-REQUEST (RFC 5780). Both IPs
+/// must be real addresses of this host, e.g. 127.0.0.1 and 127.0.0.2.
+pub async fn run_udp_rfc5780(primary: SocketAddr, alternate: SocketAddr) -> io::Result<()> {
+    if primary.ip() == alternate.ip() || primary.port() == alternate.port() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "primary and alternate must differ in both IP and port",
+        ));
+    }
+
+    let mut sockets = HashMap::new();
+    for ip in [primary.ip(), alternate.ip()] {
+        for port in [primary.port(), alternate.port()] {
+            let addr = SocketAddr::new(ip, port);
+            sockets.insert(addr, Arc::new(UdpSocket::bind(addr).await?));
+            log::info!("Listening on {}", addr);
+        }
+    }
+    let sockets = Arc::new(sockets);
+
+    let mut join_set = JoinSet::new();
+    for local in sockets.keys() {
+        // The other address is the opposite IP and port to ours
+        let other = SocketAddr::new(
+            if local.ip() == primary.ip() {
+                alternate.ip()
+            } else {
+                primary.ip()
+            },
+            if local.port() == primary.port() {
+                alternate.port()
+            } else {
+                primary.port()
+            },
+        );
+        join_set.spawn(rfc5780_task(*local, other, sockets.clone()));
+    }
+
+    while let Some(res) = join_set.join_next().await {
+        res??;
+    }
+    Ok(())
+}
+
+async fn rfc5780_task(
+    local: SocketAddr,
+    other: SocketAddr,
+    sockets: Arc<HashMap<SocketAddr, Arc<UdpSocket>>>,
+) -> io::Result<()> {
+    let socket = &sockets[&local];
+    let mut buf = [0; MAX_MESSAGE_LEN];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        if let Some(reply) = handle_message(&buf[..len], from, local, Some(other)) {
+            if let Err(e) = sockets[&reply.from].send_to(&reply.bytes, from).await {
+                log::warn!("Failed to send to {} from {}: {}", from, reply.from, e);
+            }
+        }
+    }
+}
+
+pub struct Reply {
+    pub bytes: Bytes,
+    /// Which of our addresses to send the reply from
+    pub from: SocketAddr,
+}
+
+/// The reply to a message received on `local` from `from`, if any.
+/// `other` is the address to advertise in OTHER-ADDRESS, without it
+/// CHANGE-REQUEST is not supported.
+pub fn handle_message(
+    raw: &[u8],
+    from: SocketAddr,
+    local: SocketAddr,
+    other: Option<SocketAddr>,
+) -> Option<Reply> {
+    let reply = |bytes| Reply { bytes, from: local };
+
+    let request = match Message::from_bytes(&mut Bytes::copy_from_slice(raw)) {
+        Ok(msg) => msg,
+        Err(e) => {
+            log::debug!("Bad message from {}: {}", from, e);
+            return bad_request_response(raw, e).map(reply);
+        }
+    };
+
+    if request.class != MessageClass::Request {
+        log::debug!("Ignoring {:?} from {}", request.class, from);
+        return None;
+    }
+
+    let mut unknown = request.unknown_comprehension_required();
+    let change = match request.attribute(AttributeType::ChangeRequest) {
+        Some(Attribute::ChangeRequest {
+            change_ip,
+            change_port,
+        }) => Some((*change_ip, *change_port)),
+        _ => None,
+    };
+    if change.is_some() && other.is_none() {
+        unknown.push(AttributeType::ChangeRequest.value());
+    }
+    if !unknown.is_empty() {
+        log::debug!("Unknown attributes {:04x?} from {}", unknown, from);
+        let mut response = error_response(&request, 420, "Unknown Attribute");
+        response
+            .attributes
+            .insert(1, Attribute::UnknownAttributes(unknown));
+        return Some(reply(response.encode(None, true)));
+    }
+
+    // Where to send the response from
+    let (change_ip, change_port) = change.unwrap_or_default();
+    let origin = match other {
+        Some(other) => SocketAddr::new(
+            if change_ip { other.ip() } else { local.ip() },
+            if change_port {
+                other.port()
+            } else {
+                local.port()
+            },
+        ),
+        None => local,
+    };
+
+    let response = match request.method {
+        MessageMethod::Binding => {
+            let mut attributes = vec![Attribute::XorMappedAddress(from)];
+            if let Some(other) = other {
+                attributes.push(Attribute::ResponseOrigin(origin));
+                attributes.push(Attribute::OtherAddress(other));
+            }
+            attributes.push(Attribute::Software(String::from(SOFTWARE)));
+            Message {
+                method: request.method,
+                class: MessageClass::ResponseSuccess,
+                id: request.id,
+                attributes,
+            }
+        }
+        _ => {
+            log::debug!("Unsupported method {:?} from {}", request.method, from);
+            let response = error_response(&request, 400, "Bad Request");
+            return Some(reply(response.encode(None, true)));
+        }
+    };
+    log::trace!(
+        "Binding request from {} on {}, reply from {}",
+        from,
+        local,
+        origin
+    );
+    Some(Reply {
+        bytes: response.encode(None, true),
+        from: origin,
+    })
+}
+
+pub fn error_response(request: &Message, code: u16, reason: &str) -> Message {
+    Message {
+        method: request.method,
+        class: MessageClass::ResponseFailure,
+        id: request.id,
+        attributes: vec![
+            Attribute::ErrorCode(code, String::from(reason)),
+            Attribute::Software(String::from(SOFTWARE)),
+        ],
+    }
+}
+
+/// A 400 response to a request we could not decode. Nothing is sent for
+/// things which are not STUN requests, or which failed the fingerprint check.
+fn bad_request_response(raw: &[u8], e: ParseError) -> Option<Bytes> {
+    if matches!(
+        e,
+        ParseError::NotStun | ParseError::BadMagicCookie(_) | ParseError::BadFingerprint
+    ) {
+        return None;
+    }
+    if raw.len() < message::HEADER_LEN
+        || raw[4..8] != MAGIC_COOKIE.to_be_bytes()
+        || raw[0] & 0xC0 != 0
+    {
+        return None;
+    }
+    let msg_type = MessageType::from_bytes([raw[0], raw[1]]);
+    if msg_type.class() != MessageClass::Request {
+        return None;
+    }
+    // We cannot answer a method we do not know with the same method
+    let method = msg_type.method()?;
+
+    let request = Message {
+        method,
+        class: MessageClass::Request,
+        id: message::transaction_id(raw)?,
+        attributes: Vec::new(),
+    };
+    Some(error_response(&request, 400, "Bad Request").encode(None, true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FROM: &str = "192.0.2.1:32853";
+    const LOCAL: &str = "127.0.0.1:3478";
+
+    fn request(attributes: Vec<Attribute>) -> Bytes {
+        Message {
+            method: MessageMethod::Binding,
+            class: MessageClass::Request,
+            id: rand::random(),
+            attributes,
+        }
+        .encode(None, true)
+    }
+
+    /// What `handle_message` answers, and whether `answer_binding` agrees
+    fn answer(raw: &[u8]) -> Option<Message> {
+        let from = FROM.parse().unwrap();
+        let slow = handle_message(raw, from, LOCAL.parse().unwrap(), None)
+            .map(|reply| Message::from_bytes(&mut reply.bytes.clone()).unwrap());
+        let mut buf = Vec::new();
+        if let Some(fast) = answer_binding(raw, from, &mut buf) {
+            let fast = Message::from_bytes(&mut Bytes::copy_from_slice(fast)).unwrap();
+            let slow = slow.as_ref().expect("both paths answer");
+            assert_eq!(fast.class, slow.class);
+            assert_eq!(fast.attributes, slow.attributes);
+        }
+        slow
+    }
+
+    fn error_code(response: &Message) -> Option<u16> {
+        match response.attribute(AttributeType::ErrorCode) {
+            Some(Attribute::ErrorCode(code, _)) => Some(*code),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn fast_path_answers_plain_binding() {
+        let raw = request(vec![Attribute::Software(String::from("test"))]);
+        let mut buf = Vec::new();
+        assert!(answer_binding(&raw, FROM.parse().unwrap(), &mut buf).is_some());
+
+        let response = answer(&raw).unwrap();
+        assert_eq!(response.class, MessageClass::ResponseSuccess);
+        assert_eq!(response.mapped_address(), Some(FROM.parse().unwrap()));
+    }
+
+    #[test]
+    fn malformed_values_are_bad_requests_on_both_paths() {
+        let malformed = [
+            // USERNAME which is not UTF-8
+            Attribute::Unknown(AttributeType::Username.value(), Bytes::from_static(&[0xff])),
+            // PRIORITY of 3 bytes
+            Attribute::Unknown(AttributeType::Priority.value(), Bytes::from_static(&[0; 3])),
+            // ERROR-CODE of class 7
+            Attribute::Unknown(
+                AttributeType::ErrorCode.value(),
+                Bytes::from_static(&[0, 0, 7, 0]),
+            ),
+            // SOFTWARE which is not UTF-8
+            Attribute::Unknown(AttributeType::Software.value(), Bytes::from_static(&[0xff])),
+        ];
+        for attribute in malformed {
+            let raw = request(vec![attribute]);
+            let mut buf = Vec::new();
+            assert!(answer_binding(&raw, FROM.parse().unwrap(), &mut buf).is_none());
+
+            let response = answer(&raw).unwrap();
+            assert_eq!(response.class, MessageClass::ResponseFailure);
+            assert_eq!(error_code(&response), Some(400));
+        }
+    }
+
+    #[tokio::test]
+    async fn run_udp_on_loopback() {
+        use crate::transaction::{AsyncClient, TransactionConfig};
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = socket.local_addr().unwrap();
+        let task = tokio::spawn(run_udp(socket));
+
+        let config = TransactionConfig {
+            rto: std::time::Duration::from_millis(100),
+            rc: 3,
+            ..Default::default()
+        };
+        let client = AsyncClient::new(UdpSocket::bind("127.0.0.1:0").await.unwrap(), config);
+        let local = client.socket().local_addr().unwrap();
+
+        let response = client
+            .transaction(request(Vec::new()), server)
+            .await
+            .unwrap();
+        assert_eq!(response.from, server);
+        assert_eq!(response.message.class, MessageClass::ResponseSuccess);
+        assert_eq!(
+            response.message.attribute(AttributeType::XorMappedAddress),
+            Some(&Attribute::XorMappedAddress(local))
+        );
+
+        // A comprehension-required attribute we do not know
+        let unknown = Attribute::Unknown(0x0042, Bytes::from_static(b"abcd"));
+        let response = client
+            .transaction(request(vec![unknown]), server)
+            .await
+            .unwrap();
+        assert_eq!(response.message.class, MessageClass::ResponseFailure);
+        assert_eq!(error_code(&response.message), Some(420));
+        assert_eq!(
+            response.message.attribute(AttributeType::UnknownAttributes),
+            Some(&Attribute::UnknownAttributes(vec![0x0042]))
+        );
+
+        // CHANGE-REQUEST is only understood with an alternate address
+        let change = Attribute::ChangeRequest {
+            change_ip: true,
+            change_port: false,
+        };
+        let response = client
+            .transaction(request(vec![change]), server)
+            .await
+            .unwrap();
+        assert_eq!(error_code(&response.message), Some(420));
+
+        // PRIORITY of the wrong length, which the client refuses to send
+        let priority =
+            Attribute::Unknown(AttributeType::Priority.value(), Bytes::from_static(&[0; 3]));
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket
+            .send_to(&request(vec![priority]), server)
+            .await
+            .unwrap();
+        let mut buf = [0; MAX_MESSAGE_LEN];
+        let (len, _) = socket.recv_from(&mut buf).await.unwrap();
+        let response = Message::from_bytes(&mut Bytes::copy_from_slice(&buf[..len])).unwrap();
+        assert_eq!(response.class, MessageClass::ResponseFailure);
+        assert_eq!(error_code(&response), Some(400));
+
+        task.abort();
+    }
+
+    #[test]
+    fn well_formed_known_values_agree() {
+        let raw = request(vec![
+            Attribute::Username(String::from("evtj:h6vY")),
+            Attribute::Priority(0x6e0001ff),
+            Attribute::IceControlled(1),
+        ]);
+        let response = answer(&raw).unwrap();
+        assert_eq!(response.class, MessageClass::ResponseSuccess);
+    }
+}
@@ -0,0 +1,27 @@
+//! STUN over TCP (RFC 5389 section 7.2.2)
+//!
+//! There is no framing beyond the STUN header, the length field says where
+//! each message ends.
+
+use bytes::{Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::message::{self, HEADER_LEN, MAGIC_COOKIE};
+
+/// Read one message from a stream. Fails with `InvalidData` if the stream
+/// does not carry STUN, and `UnexpectedEof` if it closes part way through.
+pub async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<Bytes> {
+    let mut bytes = BytesMut::zeroed(HEADER_LEN);
+    reader.read_exact(&mut bytes).await?;
+    if bytes[0] & 0xC0 != 0 || bytes[4..8] != MAGIC_COOKIE.to_be_bytes() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "not a STUN stream",
+        ));
+    }
+
+    let len = message::message_len(&bytes).expect("have a full header");
+    bytes.resize(len, 0);
+    reader.read_exact(&mut bytes[HEADER_LEN..]).await?;
+    Ok(bytes.into())
+}
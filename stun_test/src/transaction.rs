@@ -0,0 +1,509 @@
+//! Client transactions over UDP (RFC 5389 section 7.2.1)
+//!
+//! A request is sent at 0, RTO, 3*RTO, 7*RTO, ... for Rc sends in total, and
+//! the transaction fails if no response arrives Rm*RTO after the last send.
+//! Responses are matched to requests by transaction ID, so any number of
+//! transactions can be outstanding on one socket.
+//!
+//! Over TCP nothing is retransmitted, a transaction fails after Ti.
+//!
+//! The tokio client can share its socket with application data: what is not
+//! a response to one of our transactions is passed on, split by
+//! [`Protocol`] if wanted.
+
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    net::{SocketAddr, UdpSocket},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bytes::{Bytes, BytesMut};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::{
+        mpsc::{self, error::TrySendError},
+        oneshot,
+    },
+    task::JoinHandle,
+    time::timeout,
+};
+
+use crate::{
+    demux::Protocol,
+    error::TransactionError,
+    message::{self, Message, MessageClass},
+    tcp,
+};
+
+const MAX_MESSAGE_LEN: usize = 1500;
+/// Application data on a shared socket can be larger than any STUN message
+const MAX_DATAGRAM_LEN: usize = 65535;
+/// Datagrams are received into a buffer of this size until it is used up
+const RECV_ARENA_LEN: usize = 4 * MAX_DATAGRAM_LEN;
+/// Datagrams waiting in each forwarding queue before more are dropped
+const FORWARD_QUEUE_LEN: usize = 1024;
+
+#[derive(Clone, Copy, Debug)]
+pub struct TransactionConfig {
+    /// Initial retransmission timeout
+    pub rto: Duration,
+    /// Total number of sends
+    pub rc: u32,
+    /// Multiple of RTO to wait after the last send
+    pub rm: u32,
+    /// Transaction timeout over TCP
+    pub ti: Duration,
+}
+
+impl Default for TransactionConfig {
+    fn default() -> Self {
+        Self {
+            rto: Duration::from_millis(500),
+            rc: 7,
+            rm: 16,
+            ti: Duration::from_millis(39500),
+        }
+    }
+}
+
+impl TransactionConfig {
+    /// How long to wait after the nth (1 based) send
+    pub fn wait_after(&self, sends: u32) -> Duration {
+        if sends >= self.rc {
+            self.rto * self.rm
+        } else {
+            self.rto * 2u32.pow(sends - 1)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub message: Message,
+    /// Where the response came from
+    pub from: SocketAddr,
+    /// The encoded response, for checking MESSAGE-INTEGRITY
+    pub raw: Bytes,
+}
+
+/// Decode a datagram if it is a STUN response.
+fn decode_response(raw: Bytes, from: SocketAddr) -> Option<Response> {
+    match Message::from_bytes(&mut raw.clone()) {
+        Ok(message)
+            if matches!(
+                message.class,
+                MessageClass::ResponseSuccess | MessageClass::ResponseFailure
+            ) =>
+        {
+            Some(Response { message, from, raw })
+        }
+        Ok(_) => None,
+        Err(e) => {
+            log::trace!("Not a STUN message from {}: {}", from, e);
+            None
+        }
+    }
+}
+
+/// Transaction ID of an encoded request
+fn request_id(request: &Bytes) -> Result<[u8; 12], TransactionError> {
+    match Message::from_bytes(&mut request.clone()) {
+        Ok(msg) if msg.class == MessageClass::Request => Ok(msg.id),
+        _ => Err(TransactionError::BadRequest),
+    }
+}
+
+struct Pending {
+    id: [u8; 12],
+    request: Bytes,
+    addr: SocketAddr,
+    sends: u32,
+    next: Instant,
+    result: Option<Result<Response, TransactionError>>,
+}
+
+/// Blocking client
+pub struct Client {
+    socket: UdpSocket,
+    config: TransactionConfig,
+}
+
+impl Client {
+    pub fn new(socket: UdpSocket, config: TransactionConfig) -> Self {
+        Self { socket, config }
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// Send an encoded request to `addr` and wait for its response.
+    pub fn transaction(
+        &self,
+        request: Bytes,
+        addr: SocketAddr,
+    ) -> Result<Response, TransactionError> {
+        self.transactions(vec![(request, addr)])
+            .pop()
+            .expect("one result per request")
+    }
+
+    /// Run several transactions at once. Results are in the same order as
+    /// the requests.
+    pub fn transactions(
+        &self,
+        requests: Vec<(Bytes, SocketAddr)>,
+    ) -> Vec<Result<Response, TransactionError>> {
+        let now = Instant::now();
+        let mut pending = Vec::with_capacity(requests.len());
+        for (request, addr) in requests {
+            let result = request_id(&request).err().map(Err);
+            pending.push(Pending {
+                id: message::transaction_id(&request).unwrap_or_default(),
+                request,
+                addr,
+                sends: 0,
+                next: now,
+                result,
+            });
+        }
+
+        let mut buf = [0; MAX_MESSAGE_LEN];
+        loop {
+            // (Re)transmit or time out
+            let now = Instant::now();
+            for p in pending.iter_mut().filter(|p| p.result.is_none()) {
+                if p.next > now {
+                    continue;
+                }
+                if p.sends >= self.config.rc {
+                    p.result = Some(Err(TransactionError::Timeout));
+                    continue;
+                }
+                if let Err(e) = self.socket.send_to(&p.request, p.addr) {
+                    p.result = Some(Err(e.into()));
+                    continue;
+                }
+                p.sends += 1;
+                p.next = now + self.config.wait_after(p.sends);
+                log::trace!("Sent {} (send {})", hex::encode(p.id), p.sends);
+            }
+
+            let Some(next) = pending
+                .iter()
+                .filter(|p| p.result.is_none())
+                .map(|p| p.next)
+                .min()
+            else {
+                break;
+            };
+
+            // Wait for a response until the next thing to do
+            let wait = next
+                .saturating_duration_since(Instant::now())
+                .max(Duration::from_millis(1));
+            if let Err(e) = self.socket.set_read_timeout(Some(wait)) {
+                return fail_all(pending, e.kind());
+            }
+            match self.socket.recv_from(&mut buf) {
+                Ok((len, from)) => {
+                    let raw = Bytes::copy_from_slice(&buf[..len]);
+                    if let Some(response) = decode_response(raw, from) {
+                        match pending
+                            .iter_mut()
+                            .find(|p| p.result.is_none() && p.id == response.message.id)
+                        {
+                            Some(p) => p.result = Some(Ok(response)),
+                            None => log::debug!("Unmatched response from {}", from),
+                        }
+                    }
+                }
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(e) => return fail_all(pending, e.kind()),
+            }
+        }
+
+        pending
+            .into_iter()
+            .map(|p| p.result.expect("all transactions finished"))
+            .collect()
+    }
+}
+
+fn fail_all(pending: Vec<Pending>, kind: ErrorKind) -> Vec<Result<Response, TransactionError>> {
+    pending
+        .into_iter()
+        .map(|p| {
+            p.result
+                .unwrap_or_else(|| Err(TransactionError::Io(kind.into())))
+        })
+        .collect()
+}
+
+type PendingMap = Arc<Mutex<HashMap<[u8; 12], oneshot::Sender<Response>>>>;
+
+/// Datagrams which were not responses to our transactions. Those which
+/// arrive while the queue is full are dropped.
+pub type Forwarded = mpsc::Receiver<(Bytes, SocketAddr)>;
+
+/// What [`AsyncClient::with_demux`] passes on
+pub struct Demuxed {
+    /// STUN messages other than responses to our transactions, e.g. requests
+    /// from a peer
+    pub stun: Forwarded,
+    /// Everything else: application data, ChannelData, DTLS, ...
+    pub data: Forwarded,
+}
+
+/// Where the receive task sends what it does not handle itself
+#[derive(Default)]
+struct Routes {
+    stun: Option<mpsc::Sender<(Bytes, SocketAddr)>>,
+    data: Option<mpsc::Sender<(Bytes, SocketAddr)>>,
+}
+
+/// Tokio client. Transactions can be run concurrently from many tasks.
+pub struct AsyncClient {
+    socket: Arc<tokio::net::UdpSocket>,
+    config: TransactionConfig,
+    pending: PendingMap,
+    recv_task: JoinHandle<()>,
+}
+
+impl AsyncClient {
+    /// Must be called from within a tokio runtime.
+    pub fn new(socket: tokio::net::UdpSocket, config: TransactionConfig) -> Self {
+        Self::spawn(socket, config, Routes::default())
+    }
+
+    /// Like [`new`](Self::new), but everything received which is not a
+    /// response to one of our transactions is passed on rather than dropped.
+    pub fn with_forwarding(
+        socket: tokio::net::UdpSocket,
+        config: TransactionConfig,
+    ) -> (Self, Forwarded) {
+        let (tx, rx) = mpsc::channel(FORWARD_QUEUE_LEN);
+        let routes = Routes {
+            stun: Some(tx.clone()),
+            data: Some(tx),
+        };
+        (Self::spawn(socket, config, routes), rx)
+    }
+
+    /// Like [`with_forwarding`](Self::with_forwarding), but STUN and
+    /// everything else arrive separately, so a STUN handler and a data
+    /// handler can share the socket. Nothing received is copied.
+    pub fn with_demux(socket: tokio::net::UdpSocket, config: TransactionConfig) -> (Self, Demuxed) {
+        let (stun_tx, stun) = mpsc::channel(FORWARD_QUEUE_LEN);
+        let (data_tx, data) = mpsc::channel(FORWARD_QUEUE_LEN);
+        let routes = Routes {
+            stun: Some(stun_tx),
+            data: Some(data_tx),
+        };
+        (Self::spawn(socket, config, routes), Demuxed { stun, data })
+    }
+
+    fn spawn(socket: tokio::net::UdpSocket, config: TransactionConfig, routes: Routes) -> Self {
+        let socket = Arc::new(socket);
+        let pending: PendingMap = Arc::new(Mutex::new(HashMap::new()));
+        let recv_task = tokio::spawn(Self::recv_task(socket.clone(), pending.clone(), routes));
+        Self {
+            socket,
+            config,
+            pending,
+            recv_task,
+        }
+    }
+
+    pub fn socket(&self) -> &Arc<tokio::net::UdpSocket> {
+        &self.socket
+    }
+
+    pub fn config(&self) -> TransactionConfig {
+        self.config
+    }
+
+    /// Send an encoded request to `addr` and wait for its response.
+    pub async fn transaction(
+        &self,
+        request: Bytes,
+        addr: SocketAddr,
+    ) -> Result<Response, TransactionError> {
+        self.transaction_with(request, addr, self.config).await
+    }
+
+    /// Like [`transaction`](Self::transaction), with other retransmission
+    /// settings than the client's.
+    pub async fn transaction_with(
+        &self,
+        request: Bytes,
+        addr: SocketAddr,
+        config: TransactionConfig,
+    ) -> Result<Response, TransactionError> {
+        let id = request_id(&request)?;
+        let (tx, mut rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, tx);
+
+        let mut sends = 0;
+        let result = loop {
+            if sends >= config.rc {
+                break Err(TransactionError::Timeout);
+            }
+            if let Err(e) = self.socket.send_to(&request, addr).await {
+                break Err(e.into());
+            }
+            sends += 1;
+            log::trace!("Sent {} (send {})", hex::encode(id), sends);
+
+            match timeout(config.wait_after(sends), &mut rx).await {
+                Ok(Ok(response)) => break Ok(response),
+                Ok(Err(_)) => break Err(TransactionError::Io(ErrorKind::BrokenPipe.into())),
+                Err(_) => continue,
+            }
+        };
+
+        self.pending.lock().unwrap().remove(&id);
+        result
+    }
+
+    /// Datagrams are received into an arena and split off it, so what is
+    /// passed on shares the arena rather than being copied.
+    async fn recv_task(socket: Arc<tokio::net::UdpSocket>, pending: PendingMap, routes: Routes) {
+        let mut buf = BytesMut::new();
+        loop {
+            // A new arena once what is left of the last cannot hold the
+            // largest datagram
+            if buf.capacity() < MAX_DATAGRAM_LEN {
+                buf.reserve(RECV_ARENA_LEN);
+            }
+            let from = match socket.recv_buf_from(&mut buf).await {
+                Ok((_, from)) => from,
+                Err(e) => {
+                    log::warn!("Receive failed: {}", e);
+                    buf.clear();
+                    continue;
+                }
+            };
+            let datagram = buf.split().freeze();
+
+            let route = match Protocol::of(&datagram) {
+                Protocol::Stun => {
+                    if let Some(response) = decode_response(datagram.clone(), from) {
+                        if let Some(tx) = pending.lock().unwrap().remove(&response.message.id) {
+                            let _ = tx.send(response);
+                            continue;
+                        }
+                    }
+                    &routes.stun
+                }
+                _ => &routes.data,
+            };
+            match route {
+                Some(tx) => {
+                    if let Err(TrySendError::Full(_)) = tx.try_send((datagram, from)) {
+                        log::debug!("Dropping datagram from {}: queue full", from);
+                    }
+                }
+                None => log::debug!("Ignoring {} bytes from {}", datagram.len(), from),
+            }
+        }
+    }
+}
+
+impl Drop for AsyncClient {
+    fn drop(&mut self) {
+        self.recv_task.abort();
+    }
+}
+
+/// Tokio client over a TCP connection
+pub struct TcpClient {
+    writer: tokio::sync::Mutex<OwnedWriteHalf>,
+    peer: SocketAddr,
+    config: TransactionConfig,
+    pending: PendingMap,
+    recv_task: JoinHandle<()>,
+}
+
+impl TcpClient {
+    /// Connect to `addr`, giving up after Ti.
+    pub async fn connect(
+        addr: SocketAddr,
+        config: TransactionConfig,
+    ) -> Result<Self, TransactionError> {
+        let stream = match timeout(config.ti, TcpStream::connect(addr)).await {
+            Ok(stream) => stream?,
+            Err(_) => return Err(TransactionError::Timeout),
+        };
+        let (reader, writer) = stream.into_split();
+        let pending: PendingMap = Arc::new(Mutex::new(HashMap::new()));
+        let recv_task = tokio::spawn(Self::recv_task(reader, addr, pending.clone()));
+        Ok(Self {
+            writer: tokio::sync::Mutex::new(writer),
+            peer: addr,
+            config,
+            pending,
+            recv_task,
+        })
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Send an encoded request and wait for its response.
+    pub async fn transaction(&self, request: Bytes) -> Result<Response, TransactionError> {
+        let id = request_id(&request)?;
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, tx);
+
+        let result = async {
+            self.writer.lock().await.write_all(&request).await?;
+            log::trace!("Sent {} over TCP", hex::encode(id));
+            match timeout(self.config.ti, rx).await {
+                Ok(Ok(response)) => Ok(response),
+                Ok(Err(_)) => Err(TransactionError::Io(ErrorKind::ConnectionReset.into())),
+                Err(_) => Err(TransactionError::Timeout),
+            }
+        }
+        .await;
+
+        self.pending.lock().unwrap().remove(&id);
+        result
+    }
+
+    /// Dropping the pending senders when the connection closes fails any
+    /// outstanding transactions.
+    async fn recv_task(mut reader: OwnedReadHalf, peer: SocketAddr, pending: PendingMap) {
+        loop {
+            let raw = match tcp::read_message(&mut reader).await {
+                Ok(raw) => raw,
+                Err(e) => {
+                    log::debug!("Connection to {} closed: {}", peer, e);
+                    pending.lock().unwrap().clear();
+                    return;
+                }
+            };
+            if let Some(response) = decode_response(raw, peer) {
+                match pending.lock().unwrap().remove(&response.message.id) {
+                    Some(tx) => {
+                        let _ = tx.send(response);
+                    }
+                    None => log::debug!("Unmatched response from {}", peer),
+                }
+            }
+        }
+    }
+}
+
+impl Drop for TcpClient {
+    fn drop(&mut self) {
+        self.recv_task.abort();
+    }
+}
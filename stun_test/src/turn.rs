@@ -0,0 +1,461 @@
+//! TURN client over UDP (RFC 8656)
+//!
+//! Allocates a relayed address on a TURN server, which can then be used like
+//! any other UDP endpoint with [`TurnClient::send_to`] and
+//! [`TurnClient::recv_from`]. The allocation, permissions and channel
+//! bindings are refreshed in the background until the client is closed or
+//! dropped.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use rand::random;
+use tokio::{net::UdpSocket, task::JoinHandle, time::sleep};
+
+use crate::{
+    attribure::{Attribute, AttributeType},
+    error::TurnError,
+    integrity::Credentials,
+    message::{Message, MessageClass, MessageMethod},
+    transaction::{AsyncClient, Forwarded, TransactionConfig},
+};
+
+pub const REQUESTED_TRANSPORT_UDP: u8 = 17;
+pub const CHANNEL_MIN: u16 = 0x4000;
+pub const CHANNEL_MAX: u16 = 0x4FFF;
+
+/// Permissions last 5 minutes, channel bindings 10 minutes
+const PERMISSION_REFRESH: Duration = Duration::from_secs(4 * 60);
+const CHANNEL_REFRESH: Duration = Duration::from_secs(9 * 60);
+/// How often the refresh task checks what needs refreshing
+const TICK: Duration = Duration::from_secs(15);
+/// Refresh the allocation this long before it expires
+const ALLOCATION_MARGIN: Duration = Duration::from_secs(60);
+
+/// Application data on a bound channel, with a 4 byte header instead of a
+/// 36 byte Send or Data indication.
+#[derive(PartialEq, Clone, Debug)]
+pub struct ChannelData {
+    pub channel: u16,
+    pub data: Bytes,
+}
+
+impl ChannelData {
+    /// Over UDP the data is not padded.
+    pub fn bytes(&self) -> Bytes {
+        let mut bytes = BytesMut::with_capacity(4 + self.data.len());
+        bytes.put_u16(self.channel);
+        bytes.put_u16(self.data.len() as u16);
+        bytes.put_slice(&self.data);
+        bytes.into()
+    }
+
+    pub fn from_bytes(raw: &[u8]) -> Option<Self> {
+        let channel = u16::from_be_bytes(raw.get(0..2)?.try_into().ok()?);
+        let len = u16::from_be_bytes(raw.get(2..4)?.try_into().ok()?) as usize;
+        if !(CHANNEL_MIN..=CHANNEL_MAX).contains(&channel) {
+            return None;
+        }
+        Some(Self {
+            channel,
+            data: Bytes::copy_from_slice(raw.get(4..4 + len)?),
+        })
+    }
+}
+
+/// Long-term credential state. The realm and nonce come from the server.
+struct Auth {
+    username: String,
+    password: String,
+    realm: Option<String>,
+    nonce: Option<String>,
+}
+
+impl Auth {
+    fn key(&self) -> Option<Vec<u8>> {
+        let realm = self.realm.clone()?;
+        let credentials = Credentials::LongTerm {
+            username: self.username.clone(),
+            realm,
+            password: self.password.clone(),
+        };
+        Some(credentials.key())
+    }
+
+    /// Take the realm and nonce from a 401 or 438 response.
+    fn update(&mut self, response: &Message) {
+        if let Some(Attribute::Realm(realm)) = response.attribute(AttributeType::Realm) {
+            self.realm = Some(realm.clone());
+        }
+        if let Some(Attribute::Nonce(nonce)) = response.attribute(AttributeType::Nonce) {
+            self.nonce = Some(nonce.clone());
+        }
+    }
+}
+
+struct Inner {
+    client: AsyncClient,
+    server: SocketAddr,
+    auth: Mutex<Auth>,
+    expires: Mutex<Instant>,
+    /// Peer IP and when its permission was last installed
+    permissions: Mutex<HashMap<IpAddr, Instant>>,
+    /// Peer address, channel number and when it was last bound, None while
+    /// the first ChannelBind is in flight
+    channels: Mutex<HashMap<SocketAddr, (u16, Option<Instant>)>>,
+}
+
+impl Inner {
+    /// Send an authenticated request, retrying on 401 (Unauthorized) or 438
+    /// (Stale Nonce) with the realm and nonce from the server. The first
+    /// request of all gets a 401, as we do not know the realm yet.
+    async fn request(
+        &self,
+        method: MessageMethod,
+        attributes: Vec<Attribute>,
+    ) -> Result<Message, TurnError> {
+        let mut retries = 0;
+        loop {
+            let (request, key) = {
+                let auth = self.auth.lock().unwrap();
+                let mut attributes = attributes.clone();
+                let key = auth.key();
+                if let (Some(realm), Some(nonce)) = (&auth.realm, &auth.nonce) {
+                    attributes.push(Attribute::Username(auth.username.clone()));
+                    attributes.push(Attribute::Realm(realm.clone()));
+                    attributes.push(Attribute::Nonce(nonce.clone()));
+                }
+                let request = Message {
+                    method,
+                    class: MessageClass::Request,
+                    id: random(),
+                    attributes,
+                };
+                (request.encode(key.as_deref(), false), key)
+            };
+
+            let response = self.client.transaction(request, self.server).await?;
+            let message = response.message;
+
+            if message.class == MessageClass::ResponseFailure {
+                let (code, reason) = match message.attribute(AttributeType::ErrorCode) {
+                    Some(Attribute::ErrorCode(code, reason)) => (*code, reason.clone()),
+                    _ => (0, String::new()),
+                };
+                if matches!(code, 401 | 438) && retries < 2 {
+                    log::debug!("{} {}, retrying with new nonce", code, reason);
+                    self.auth.lock().unwrap().update(&message);
+                    retries += 1;
+                    continue;
+                }
+                return Err(TurnError::ErrorResponse(code, reason));
+            }
+
+            if let Some(key) = key {
+                if !Message::check_integrity(&response.raw, &key) {
+                    return Err(TurnError::BadIntegrity);
+                }
+            }
+            return Ok(message);
+        }
+    }
+
+    async fn refresh(&self, lifetime: Option<u32>) -> Result<Duration, TurnError> {
+        let attributes = lifetime.map(Attribute::Lifetime).into_iter().collect();
+        let response = self.request(MessageMethod::Refresh, attributes).await?;
+        let lifetime = lifetime_of(&response);
+        *self.expires.lock().unwrap() = Instant::now() + lifetime;
+        Ok(lifetime)
+    }
+
+    async fn create_permission(&self, ip: IpAddr) -> Result<(), TurnError> {
+        // The port is ignored by the server
+        let attributes = vec![Attribute::XorPeerAddress(SocketAddr::new(ip, 0))];
+        self.request(MessageMethod::CreatePermission, attributes)
+            .await?;
+        self.permissions.lock().unwrap().insert(ip, Instant::now());
+        Ok(())
+    }
+
+    async fn channel_bind(&self, peer: SocketAddr, channel: u16) -> Result<(), TurnError> {
+        let attributes = vec![
+            Attribute::ChannelNumber(channel),
+            Attribute::XorPeerAddress(peer),
+        ];
+        self.request(MessageMethod::ChannelBind, attributes).await?;
+        let now = Instant::now();
+        self.channels
+            .lock()
+            .unwrap()
+            .insert(peer, (channel, Some(now)));
+        // A channel binding also installs a permission
+        self.permissions.lock().unwrap().insert(peer.ip(), now);
+        Ok(())
+    }
+
+    async fn refresh_task(self: Arc<Self>) {
+        loop {
+            sleep(TICK).await;
+            let now = Instant::now();
+
+            let expires = *self.expires.lock().unwrap();
+            if expires.saturating_duration_since(now) < ALLOCATION_MARGIN + TICK {
+                match self.refresh(None).await {
+                    Ok(lifetime) => log::debug!("Refreshed allocation for {:?}", lifetime),
+                    Err(e) => log::warn!("Failed to refresh allocation: {}", e),
+                }
+            }
+
+            let stale: Vec<_> = self
+                .channels
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, (_, at))| {
+                    at.is_some_and(|at| now.duration_since(at) >= CHANNEL_REFRESH)
+                })
+                .map(|(peer, (channel, _))| (*peer, *channel))
+                .collect();
+            for (peer, channel) in stale {
+                if let Err(e) = self.channel_bind(peer, channel).await {
+                    log::warn!("Failed to refresh channel to {}: {}", peer, e);
+                }
+            }
+
+            let stale: Vec<_> = self
+                .permissions
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, at)| now.duration_since(**at) >= PERMISSION_REFRESH)
+                .map(|(ip, _)| *ip)
+                .collect();
+            for ip in stale {
+                if let Err(e) = self.create_permission(ip).await {
+                    log::warn!("Failed to refresh permission for {}: {}", ip, e);
+                }
+            }
+        }
+    }
+}
+
+fn lifetime_of(response: &Message) -> Duration {
+    match response.attribute(AttributeType::Lifetime) {
+        Some(Attribute::Lifetime(x)) => Duration::from_secs(*x as u64),
+        // The default lifetime
+        _ => Duration::from_secs(600),
+    }
+}
+
+pub struct TurnClient {
+    inner: Arc<Inner>,
+    relayed_addr: SocketAddr,
+    mapped_addr: Option<SocketAddr>,
+    forwarded: tokio::sync::Mutex<Forwarded>,
+    refresh_task: JoinHandle<()>,
+}
+
+impl TurnClient {
+    /// Allocate a relayed UDP address on `server`.
+    pub async fn allocate(
+        socket: UdpSocket,
+        server: SocketAddr,
+        username: &str,
+        password: &str,
+        config: TransactionConfig,
+    ) -> Result<Self, TurnError> {
+        let (client, forwarded) = AsyncClient::with_forwarding(socket, config);
+        let inner = Arc::new(Inner {
+            client,
+            server,
+            auth: Mutex::new(Auth {
+                username: String::from(username),
+                password: String::from(password),
+                realm: None,
+                nonce: None,
+            }),
+            expires: Mutex::new(Instant::now()),
+            permissions: Mutex::new(HashMap::new()),
+            channels: Mutex::new(HashMap::new()),
+        });
+
+        let attributes = vec![Attribute::RequestedTransport(REQUESTED_TRANSPORT_UDP)];
+        let response = inner.request(MessageMethod::Allocate, attributes).await?;
+
+        let relayed_addr = match response.attribute(AttributeType::XorRelayedAddress) {
+            Some(Attribute::XorRelayedAddress(addr)) => *addr,
+            _ => {
+                return Err(TurnError::MissingAttribute(
+                    AttributeType::XorRelayedAddress,
+                ))
+            }
+        };
+        let mapped_addr = response.mapped_address();
+        let lifetime = lifetime_of(&response);
+        *inner.expires.lock().unwrap() = Instant::now() + lifetime;
+        log::info!("Allocated {} for {:?}", relayed_addr, lifetime);
+
+        let refresh_task = tokio::spawn(inner.clone().refresh_task());
+        Ok(Self {
+            inner,
+            relayed_addr,
+            mapped_addr,
+            forwarded: tokio::sync::Mutex::new(forwarded),
+            refresh_task,
+        })
+    }
+
+    /// Our address on the TURN server, to give to peers
+    pub fn relayed_addr(&self) -> SocketAddr {
+        self.relayed_addr
+    }
+
+    /// Our address as seen by the TURN server
+    pub fn mapped_addr(&self) -> Option<SocketAddr> {
+        self.mapped_addr
+    }
+
+    /// Let `ip` send to us through the relay.
+    pub async fn create_permission(&self, ip: IpAddr) -> Result<(), TurnError> {
+        self.inner.create_permission(ip).await
+    }
+
+    /// Bind a channel to `peer`, so data to and from it uses ChannelData.
+    pub async fn channel_bind(&self, peer: SocketAddr) -> Result<u16, TurnError> {
+        // Reserved before binding, so concurrent binds to other peers do not
+        // pick the same channel
+        let channel = {
+            let mut channels = self.inner.channels.lock().unwrap();
+            if let Some((channel, _)) = channels.get(&peer) {
+                return Ok(*channel);
+            }
+            let channel = (CHANNEL_MIN..=CHANNEL_MAX)
+                .find(|x| !channels.values().any(|(channel, _)| channel == x))
+                .ok_or(TurnError::NoChannels)?;
+            channels.insert(peer, (channel, None));
+            channel
+        };
+        if let Err(e) = self.inner.channel_bind(peer, channel).await {
+            self.inner.channels.lock().unwrap().remove(&peer);
+            return Err(e);
+        }
+        Ok(channel)
+    }
+
+    /// Send to `peer` through the relay, installing a permission first if
+    /// needed.
+    pub async fn send_to(&self, data: &[u8], peer: SocketAddr) -> Result<(), TurnError> {
+        if self.refresh_task.is_finished() {
+            return Err(TurnError::Closed);
+        }
+
+        let channel = self
+            .inner
+            .channels
+            .lock()
+            .unwrap()
+            .get(&peer)
+            .filter(|(_, bound)| bound.is_some())
+            .map(|(channel, _)| *channel);
+        let bytes = match channel {
+            Some(channel) => ChannelData {
+                channel,
+                data: Bytes::copy_from_slice(data),
+            }
+            .bytes(),
+            None => {
+                let permitted = self
+                    .inner
+                    .permissions
+                    .lock()
+                    .unwrap()
+                    .contains_key(&peer.ip());
+                if !permitted {
+                    self.create_permission(peer.ip()).await?;
+                }
+                Message {
+                    method: MessageMethod::Send,
+                    class: MessageClass::Indication,
+                    id: random(),
+                    attributes: vec![
+                        Attribute::XorPeerAddress(peer),
+                        Attribute::Data(Bytes::copy_from_slice(data)),
+                    ],
+                }
+                .bytes()
+            }
+        };
+
+        self.inner
+            .client
+            .socket()
+            .send_to(&bytes, self.inner.server)
+            .await
+            .map_err(|e| TurnError::Transaction(e.into()))?;
+        Ok(())
+    }
+
+    /// Receive data relayed from a peer.
+    pub async fn recv_from(&self) -> Result<(Bytes, SocketAddr), TurnError> {
+        let mut forwarded = self.forwarded.lock().await;
+        loop {
+            let (raw, from) = forwarded.recv().await.ok_or(TurnError::Closed)?;
+            if from != self.inner.server {
+                log::debug!("Ignoring {} bytes from {}", raw.len(), from);
+                continue;
+            }
+
+            if let Some(channel_data) = ChannelData::from_bytes(&raw) {
+                let peer = self
+                    .inner
+                    .channels
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .find(|(_, (channel, _))| *channel == channel_data.channel)
+                    .map(|(peer, _)| *peer);
+                match peer {
+                    Some(peer) => return Ok((channel_data.data, peer)),
+                    None => log::debug!("Data on unknown channel {:#06x}", channel_data.channel),
+                }
+                continue;
+            }
+
+            let Ok(msg) = Message::from_bytes(&mut raw.clone()) else {
+                log::debug!("Ignoring {} bytes from server", raw.len());
+                continue;
+            };
+            if msg.method != MessageMethod::Data || msg.class != MessageClass::Indication {
+                log::debug!("Ignoring {:?} {:?}", msg.method, msg.class);
+                continue;
+            }
+            match (
+                msg.attribute(AttributeType::XorPeerAddress),
+                msg.attribute(AttributeType::Data),
+            ) {
+                (Some(Attribute::XorPeerAddress(peer)), Some(Attribute::Data(data))) => {
+                    return Ok((data.clone(), *peer))
+                }
+                _ => log::debug!("Data indication without peer address or data"),
+            }
+        }
+    }
+
+    /// Delete the allocation on the server.
+    pub async fn close(self) -> Result<(), TurnError> {
+        self.refresh_task.abort();
+        self.inner.refresh(Some(0)).await?;
+        Ok(())
+    }
+}
+
+impl Drop for TurnClient {
+    fn drop(&mut self) {
+        self.refresh_task.abort();
+    }
+}
@@ -0,0 +1,747 @@
+//! TURN server over UDP (RFC 8656)
+//!
+//! Relays UDP for clients with long-term credentials from a user file, each
+//! allocation on its own relayed socket. Enough to test [`TurnClient`]
+//! against, or to self-host: there is no TCP relaying, DONT-FRAGMENT,
+//! EVEN-PORT or RESERVATION-TOKEN.
+//!
+//! [`TurnClient`]: crate::turn::TurnClient
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use rand::random;
+use tokio::{net::UdpSocket, task::JoinHandle, time::interval};
+
+use crate::{
+    attribure::{Attribute, AttributeType},
+    integrity::Credentials,
+    message::{Message, MessageClass, MessageMethod},
+    server::{self, error_response, SOFTWARE},
+    turn::{ChannelData, CHANNEL_MAX, CHANNEL_MIN, REQUESTED_TRANSPORT_UDP},
+};
+
+/// Largest datagram a client or peer can send us
+const MAX_DATAGRAM_LEN: usize = 65535;
+/// Largest UDP payload over IPv4, so the most we can send a client or peer
+const MAX_UDP_PAYLOAD: usize = 65507;
+const PERMISSION_LIFETIME: Duration = Duration::from_secs(5 * 60);
+const CHANNEL_LIFETIME: Duration = Duration::from_secs(10 * 60);
+/// A nonce older than this gets a 438 (Stale Nonce)
+const NONCE_LIFETIME: Duration = Duration::from_secs(60 * 60);
+/// How often expired allocations, permissions and channels are removed
+const TICK: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug)]
+pub struct TurnServerConfig {
+    pub realm: String,
+    /// Username to password
+    pub users: HashMap<String, String>,
+    /// The IP to allocate relayed addresses on, by default the one we listen
+    /// on
+    pub relay_ip: Option<IpAddr>,
+    pub max_allocations: usize,
+    pub default_lifetime: Duration,
+    pub max_lifetime: Duration,
+}
+
+impl Default for TurnServerConfig {
+    fn default() -> Self {
+        Self {
+            realm: String::from("stun_test"),
+            users: HashMap::new(),
+            relay_ip: None,
+            max_allocations: 1000,
+            default_lifetime: Duration::from_secs(600),
+            max_lifetime: Duration::from_secs(3600),
+        }
+    }
+}
+
+/// Read a user file of `username:password` lines. Blank lines and lines
+/// starting with `#` are skipped.
+pub fn load_users(path: &Path) -> io::Result<HashMap<String, String>> {
+    let mut users = HashMap::new();
+    for (i, line) in fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (username, password) = line.split_once(':').ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}:{}: expected username:password", path.display(), i + 1),
+            )
+        })?;
+        users.insert(String::from(username), String::from(password));
+    }
+    Ok(users)
+}
+
+/// Relay for TURN clients on `socket` until an I/O error.
+pub async fn run_udp(socket: UdpSocket, config: TurnServerConfig) -> io::Result<()> {
+    let local = socket.local_addr()?;
+    let relay_ip = config.relay_ip.unwrap_or(local.ip());
+    if relay_ip.is_unspecified() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("a relay IP is needed when listening on {}", local),
+        ));
+    }
+    log::info!("Listening on {} (TURN), relaying on {}", local, relay_ip);
+
+    let server = Arc::new(Server {
+        socket: Arc::new(socket),
+        local,
+        relay_ip,
+        config,
+        state: Mutex::new(State::default()),
+    });
+    let res = server.clone().serve().await;
+    // Stop the relay tasks, which hold references to the server
+    server.state.lock().unwrap().allocations.clear();
+    res
+}
+
+/// A response's attributes, or its error code and reason
+type Outcome = Result<Vec<Attribute>, (u16, &'static str)>;
+
+struct Allocation {
+    username: String,
+    /// The Allocate transaction, to answer retransmits of it
+    id: [u8; 12],
+    relay: Arc<UdpSocket>,
+    relayed_addr: SocketAddr,
+    expires: Instant,
+    /// Peer IP and when its permission expires
+    permissions: HashMap<IpAddr, Instant>,
+    /// Channel number, peer address and when the binding expires
+    channels: HashMap<u16, (SocketAddr, Instant)>,
+    relay_task: JoinHandle<()>,
+}
+
+impl Allocation {
+    fn permitted(&self, ip: IpAddr, now: Instant) -> bool {
+        self.permissions
+            .get(&ip)
+            .is_some_and(|expires| *expires > now)
+    }
+
+    fn channel_to(&self, peer: SocketAddr) -> Option<u16> {
+        self.channels
+            .iter()
+            .find(|(_, (addr, _))| *addr == peer)
+            .map(|(channel, _)| *channel)
+    }
+
+    fn attributes(&self, client: SocketAddr) -> Vec<Attribute> {
+        let lifetime = self.expires.saturating_duration_since(Instant::now());
+        vec![
+            Attribute::XorRelayedAddress(self.relayed_addr),
+            Attribute::Lifetime(lifetime.as_secs() as u32),
+            Attribute::XorMappedAddress(client),
+        ]
+    }
+}
+
+impl Drop for Allocation {
+    fn drop(&mut self) {
+        self.relay_task.abort();
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// By client address
+    allocations: HashMap<SocketAddr, Allocation>,
+    /// The nonce given to each client and when
+    nonces: HashMap<SocketAddr, (String, Instant)>,
+}
+
+struct Server {
+    socket: Arc<UdpSocket>,
+    local: SocketAddr,
+    relay_ip: IpAddr,
+    config: TurnServerConfig,
+    state: Mutex<State>,
+}
+
+impl Server {
+    async fn serve(self: Arc<Self>) -> io::Result<()> {
+        // Send indications and ChannelData can be as large as any datagram
+        let mut buf = vec![0; MAX_DATAGRAM_LEN];
+        let mut tick = interval(TICK);
+        loop {
+            tokio::select! {
+                res = self.socket.recv_from(&mut buf) => {
+                    let (len, from) = res?;
+                    if let Some(reply) = self.handle(&buf[..len], from).await {
+                        // One client we cannot reach must not stop the server
+                        if let Err(e) = self.socket.send_to(&reply, from).await {
+                            log::warn!("Failed to send to {}: {}", from, e);
+                        }
+                    }
+                }
+                _ = tick.tick() => self.expire(),
+            }
+        }
+    }
+
+    async fn handle(self: &Arc<Self>, raw: &[u8], from: SocketAddr) -> Option<Bytes> {
+        if let Some(channel_data) = ChannelData::from_bytes(raw) {
+            self.channel_data(channel_data, from).await;
+            return None;
+        }
+
+        let message = match Message::from_bytes(&mut Bytes::copy_from_slice(raw)) {
+            Ok(msg) => msg,
+            // Let the STUN server decide whether it deserves a 400
+            Err(_) => return server::handle_message(raw, from, self.local, None).map(|r| r.bytes),
+        };
+        match (message.method, message.class) {
+            (MessageMethod::Binding, _) => {
+                server::handle_message(raw, from, self.local, None).map(|r| r.bytes)
+            }
+            (MessageMethod::Send, MessageClass::Indication) => {
+                self.send_indication(&message, from).await;
+                None
+            }
+            (_, MessageClass::Request) => Some(self.request(&message, raw, from).await),
+            _ => {
+                log::debug!(
+                    "Ignoring {:?} {:?} from {}",
+                    message.method,
+                    message.class,
+                    from
+                );
+                None
+            }
+        }
+    }
+
+    async fn request(self: &Arc<Self>, request: &Message, raw: &[u8], from: SocketAddr) -> Bytes {
+        let (username, key) = match self.authenticate(request, raw, from) {
+            Ok(x) => x,
+            Err(response) => return response,
+        };
+
+        let unknown = request.unknown_comprehension_required();
+        if !unknown.is_empty() {
+            log::debug!("Unknown attributes {:04x?} from {}", unknown, from);
+            let mut response = error_response(request, 420, "Unknown Attribute");
+            response
+                .attributes
+                .insert(1, Attribute::UnknownAttributes(unknown));
+            return response.encode(Some(&key), true);
+        }
+
+        let outcome = match request.method {
+            MessageMethod::Allocate => self.allocate(request, from, &username).await,
+            MessageMethod::Refresh => self.refresh(request, from, &username),
+            MessageMethod::CreatePermission => self.create_permission(request, from, &username),
+            MessageMethod::ChannelBind => self.channel_bind(request, from, &username),
+            _ => Err((400, "Bad Request")),
+        };
+        let response = match outcome {
+            Ok(mut attributes) => {
+                attributes.push(Attribute::Software(String::from(SOFTWARE)));
+                Message {
+                    method: request.method,
+                    class: MessageClass::ResponseSuccess,
+                    id: request.id,
+                    attributes,
+                }
+            }
+            Err((code, reason)) => {
+                log::debug!(
+                    "{:?} from {} failed: {} {}",
+                    request.method,
+                    from,
+                    code,
+                    reason
+                );
+                error_response(request, code, reason)
+            }
+        };
+        response.encode(Some(&key), true)
+    }
+
+    /// The username and key of a request with valid long-term credentials,
+    /// or the response to send if it has none.
+    fn authenticate(
+        &self,
+        request: &Message,
+        raw: &[u8],
+        from: SocketAddr,
+    ) -> Result<(String, Vec<u8>), Bytes> {
+        if request.attribute(AttributeType::MessageIntegrity).is_none() {
+            return Err(self.challenge(request, from, 401, "Unauthorized"));
+        }
+        let (
+            Some(Attribute::Username(username)),
+            Some(Attribute::Realm(realm)),
+            Some(Attribute::Nonce(nonce)),
+        ) = (
+            request.attribute(AttributeType::Username),
+            request.attribute(AttributeType::Realm),
+            request.attribute(AttributeType::Nonce),
+        )
+        else {
+            return Err(error_response(request, 400, "Bad Request").encode(None, true));
+        };
+
+        let current = self.state.lock().unwrap().nonces.get(&from).cloned();
+        match current {
+            Some((current, issued)) if current == *nonce && issued.elapsed() < NONCE_LIFETIME => {}
+            _ => return Err(self.challenge(request, from, 438, "Stale Nonce")),
+        }
+
+        let key = match self.config.users.get(username) {
+            Some(password) if *realm == self.config.realm => Credentials::LongTerm {
+                username: username.clone(),
+                realm: realm.clone(),
+                password: password.clone(),
+            }
+            .key(),
+            _ => {
+                log::debug!("Unknown user {:?} from {}", username, from);
+                return Err(self.challenge(request, from, 401, "Unauthorized"));
+            }
+        };
+        if !Message::check_integrity(raw, &key) {
+            log::debug!("Bad integrity from {} ({})", from, username);
+            return Err(self.challenge(request, from, 401, "Unauthorized"));
+        }
+        Ok((username.clone(), key))
+    }
+
+    /// An error response with our realm and a nonce for the client to
+    /// authenticate with.
+    fn challenge(&self, request: &Message, from: SocketAddr, code: u16, reason: &str) -> Bytes {
+        let nonce = {
+            let mut state = self.state.lock().unwrap();
+            let entry = state
+                .nonces
+                .entry(from)
+                .or_insert_with(|| (hex::encode(random::<[u8; 12]>()), Instant::now()));
+            if entry.1.elapsed() >= NONCE_LIFETIME {
+                *entry = (hex::encode(random::<[u8; 12]>()), Instant::now());
+            }
+            entry.0.clone()
+        };
+        let mut response = error_response(request, code, reason);
+        response
+            .attributes
+            .insert(1, Attribute::Realm(self.config.realm.clone()));
+        response.attributes.insert(2, Attribute::Nonce(nonce));
+        response.encode(None, true)
+    }
+
+    async fn allocate(
+        self: &Arc<Self>,
+        request: &Message,
+        from: SocketAddr,
+        username: &str,
+    ) -> Outcome {
+        {
+            let state = self.state.lock().unwrap();
+            if let Some(allocation) = state.allocations.get(&from) {
+                // A retransmit gets the same answer
+                if allocation.id == request.id && allocation.username == username {
+                    return Ok(allocation.attributes(from));
+                }
+                return Err((437, "Allocation Mismatch"));
+            }
+            if state.allocations.len() >= self.config.max_allocations {
+                return Err((486, "Allocation Quota Reached"));
+            }
+        }
+        match request.attribute(AttributeType::RequestedTransport) {
+            Some(Attribute::RequestedTransport(REQUESTED_TRANSPORT_UDP)) => {}
+            Some(_) => return Err((442, "Unsupported Transport Protocol")),
+            None => return Err((400, "Bad Request")),
+        }
+
+        let relay = match UdpSocket::bind((self.relay_ip, 0)).await {
+            Ok(socket) => Arc::new(socket),
+            Err(e) => {
+                log::warn!("Failed to bind a relayed address: {}", e);
+                return Err((508, "Insufficient Capacity"));
+            }
+        };
+        let relayed_addr = relay
+            .local_addr()
+            .map_err(|_| (508, "Insufficient Capacity"))?;
+        let lifetime = self.lifetime(request);
+        let allocation = Allocation {
+            username: String::from(username),
+            id: request.id,
+            relay: relay.clone(),
+            relayed_addr,
+            expires: Instant::now() + lifetime,
+            permissions: HashMap::new(),
+            channels: HashMap::new(),
+            relay_task: tokio::spawn(self.clone().relay_task(relay, from)),
+        };
+        let attributes = allocation.attributes(from);
+        self.state
+            .lock()
+            .unwrap()
+            .allocations
+            .insert(from, allocation);
+        log::info!(
+            "Allocated {} to {} ({}) for {:?}",
+            relayed_addr,
+            from,
+            username,
+            lifetime
+        );
+        Ok(attributes)
+    }
+
+    fn refresh(&self, request: &Message, from: SocketAddr, username: &str) -> Outcome {
+        if let Some(Attribute::Lifetime(0)) = request.attribute(AttributeType::Lifetime) {
+            self.with_allocation(from, username, |_| Ok(Vec::new()))?;
+            self.state.lock().unwrap().allocations.remove(&from);
+            log::info!("Deleted allocation of {}", from);
+            return Ok(vec![Attribute::Lifetime(0)]);
+        }
+        let lifetime = self.lifetime(request);
+        self.with_allocation(from, username, |allocation| {
+            allocation.expires = Instant::now() + lifetime;
+            Ok(vec![Attribute::Lifetime(lifetime.as_secs() as u32)])
+        })
+    }
+
+    fn create_permission(&self, request: &Message, from: SocketAddr, username: &str) -> Outcome {
+        let peers: Vec<_> = request
+            .attributes
+            .iter()
+            .filter_map(|attribute| match attribute {
+                Attribute::XorPeerAddress(addr) => Some(*addr),
+                _ => None,
+            })
+            .collect();
+        if peers.is_empty() {
+            return Err((400, "Bad Request"));
+        }
+        self.with_allocation(from, username, |allocation| {
+            if peers
+                .iter()
+                .any(|peer| peer.is_ipv4() != allocation.relayed_addr.is_ipv4())
+            {
+                return Err((443, "Peer Address Family Mismatch"));
+            }
+            let expires = Instant::now() + PERMISSION_LIFETIME;
+            for peer in &peers {
+                allocation.permissions.insert(peer.ip(), expires);
+            }
+            Ok(Vec::new())
+        })
+    }
+
+    fn channel_bind(&self, request: &Message, from: SocketAddr, username: &str) -> Outcome {
+        let (Some(Attribute::ChannelNumber(channel)), Some(Attribute::XorPeerAddress(peer))) = (
+            request.attribute(AttributeType::ChannelNumber),
+            request.attribute(AttributeType::XorPeerAddress),
+        ) else {
+            return Err((400, "Bad Request"));
+        };
+        if !(CHANNEL_MIN..=CHANNEL_MAX).contains(channel) {
+            return Err((400, "Bad Request"));
+        }
+        self.with_allocation(from, username, |allocation| {
+            if peer.is_ipv4() != allocation.relayed_addr.is_ipv4() {
+                return Err((443, "Peer Address Family Mismatch"));
+            }
+            // A channel stays with one peer, and a peer with one channel
+            let bound = allocation.channels.get(channel).map(|(addr, _)| addr);
+            if bound.is_some_and(|addr| addr != peer)
+                || allocation.channel_to(*peer).is_some_and(|x| x != *channel)
+            {
+                return Err((400, "Bad Request"));
+            }
+            let now = Instant::now();
+            allocation
+                .channels
+                .insert(*channel, (*peer, now + CHANNEL_LIFETIME));
+            allocation
+                .permissions
+                .insert(peer.ip(), now + PERMISSION_LIFETIME);
+            Ok(Vec::new())
+        })
+    }
+
+    /// Run `f` on the allocation of `from`, which must belong to `username`.
+    fn with_allocation(
+        &self,
+        from: SocketAddr,
+        username: &str,
+        f: impl FnOnce(&mut Allocation) -> Outcome,
+    ) -> Outcome {
+        let mut state = self.state.lock().unwrap();
+        let allocation = state
+            .allocations
+            .get_mut(&from)
+            .ok_or((437, "Allocation Mismatch"))?;
+        if allocation.username != username {
+            return Err((441, "Wrong Credentials"));
+        }
+        f(allocation)
+    }
+
+    /// The requested lifetime within our limits
+    fn lifetime(&self, request: &Message) -> Duration {
+        match request.attribute(AttributeType::Lifetime) {
+            Some(Attribute::Lifetime(x)) => Duration::from_secs(*x as u64)
+                .clamp(self.config.default_lifetime, self.config.max_lifetime),
+            _ => self.config.default_lifetime,
+        }
+    }
+
+    async fn send_indication(&self, indication: &Message, from: SocketAddr) {
+        match (
+            indication.attribute(AttributeType::XorPeerAddress),
+            indication.attribute(AttributeType::Data),
+        ) {
+            (Some(Attribute::XorPeerAddress(peer)), Some(Attribute::Data(data))) => {
+                self.relay_to(from, *peer, data).await
+            }
+            _ => log::debug!("Send indication without peer address or data from {}", from),
+        }
+    }
+
+    async fn channel_data(&self, channel_data: ChannelData, from: SocketAddr) {
+        let peer = self
+            .state
+            .lock()
+            .unwrap()
+            .allocations
+            .get(&from)
+            .and_then(|allocation| allocation.channels.get(&channel_data.channel))
+            .map(|(peer, _)| *peer);
+        match peer {
+            Some(peer) => self.relay_to(from, peer, &channel_data.data).await,
+            None => log::debug!(
+                "Data on unknown channel {:#06x} from {}",
+                channel_data.channel,
+                from
+            ),
+        }
+    }
+
+    /// Send `data` to `peer` from the relayed address of `from`, if it has a
+    /// permission.
+    async fn relay_to(&self, from: SocketAddr, peer: SocketAddr, data: &[u8]) {
+        if data.len() > MAX_UDP_PAYLOAD {
+            log::debug!(
+                "Dropping {} bytes from {} to {}: too large to relay",
+                data.len(),
+                from,
+                peer
+            );
+            return;
+        }
+        let relay = match self.state.lock().unwrap().allocations.get(&from) {
+            Some(allocation) if allocation.permitted(peer.ip(), Instant::now()) => {
+                allocation.relay.clone()
+            }
+            Some(_) => {
+                log::debug!("No permission for {} to send to {}", from, peer);
+                return;
+            }
+            None => {
+                log::debug!("No allocation for {}", from);
+                return;
+            }
+        };
+        if let Err(e) = relay.send_to(data, peer).await {
+            log::debug!("Failed to relay to {}: {}", peer, e);
+        }
+    }
+
+    /// Pass data from permitted peers on to `client`. Runs until the
+    /// allocation is dropped.
+    async fn relay_task(self: Arc<Self>, relay: Arc<UdpSocket>, client: SocketAddr) {
+        // Large enough that nothing is truncated
+        let mut buf = vec![0; MAX_DATAGRAM_LEN];
+        loop {
+            let (len, peer) = match relay.recv_from(&mut buf).await {
+                Ok(x) => x,
+                Err(e) => {
+                    log::warn!("Relay for {} failed: {}", client, e);
+                    return;
+                }
+            };
+            let data = Bytes::copy_from_slice(&buf[..len]);
+
+            let bytes = {
+                let state = self.state.lock().unwrap();
+                // Not inserted yet
+                let Some(allocation) = state.allocations.get(&client) else {
+                    continue;
+                };
+                if !allocation.permitted(peer.ip(), Instant::now()) {
+                    log::trace!("Dropping {} bytes from {} to {}", len, peer, client);
+                    continue;
+                }
+                match allocation.channel_to(peer) {
+                    Some(channel) => ChannelData { channel, data }.bytes(),
+                    None => Message {
+                        method: MessageMethod::Data,
+                        class: MessageClass::Indication,
+                        id: random(),
+                        attributes: vec![Attribute::XorPeerAddress(peer), Attribute::Data(data)],
+                    }
+                    .bytes(),
+                }
+            };
+            if bytes.len() > MAX_UDP_PAYLOAD {
+                log::debug!(
+                    "Dropping {} bytes from {} to {}: too large to relay",
+                    len,
+                    peer,
+                    client
+                );
+                continue;
+            }
+            if let Err(e) = self.socket.send_to(&bytes, client).await {
+                log::debug!("Failed to send to {}: {}", client, e);
+            }
+        }
+    }
+
+    fn expire(&self) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.allocations.retain(|client, allocation| {
+            if allocation.expires <= now {
+                log::info!(
+                    "Allocation {} of {} expired",
+                    allocation.relayed_addr,
+                    client
+                );
+                return false;
+            }
+            allocation.permissions.retain(|_, expires| *expires > now);
+            allocation.channels.retain(|_, (_, expires)| *expires > now);
+            true
+        });
+        state
+            .nonces
+            .retain(|_, (_, issued)| now.duration_since(*issued) < NONCE_LIFETIME);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::TurnError, transaction::TransactionConfig, turn::TurnClient};
+
+    const USERNAME: &str = "alice";
+    const PASSWORD: &str = "secret";
+
+    fn config() -> TransactionConfig {
+        TransactionConfig {
+            rto: Duration::from_millis(100),
+            rc: 3,
+            ..Default::default()
+        }
+    }
+
+    async fn allocate(server: SocketAddr, password: &str) -> Result<TurnClient, TurnError> {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        TurnClient::allocate(socket, server, USERNAME, password, config()).await
+    }
+
+    #[tokio::test]
+    async fn client_against_server() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = socket.local_addr().unwrap();
+        let config = TurnServerConfig {
+            users: HashMap::from([(String::from(USERNAME), String::from(PASSWORD))]),
+            max_allocations: 1,
+            ..Default::default()
+        };
+        let task = tokio::spawn(run_udp(socket, config));
+
+        // The first Allocate gets a 401, the retry a 401 again with the
+        // wrong password
+        assert!(matches!(
+            allocate(server, "wrong").await,
+            Err(TurnError::ErrorResponse(401, _))
+        ));
+        let client = allocate(server, PASSWORD).await.unwrap();
+        assert_eq!(client.relayed_addr().ip(), server.ip());
+        assert_ne!(client.relayed_addr(), server);
+        assert_eq!(
+            client.mapped_addr().map(|addr| addr.ip()),
+            Some(server.ip())
+        );
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        let mut buf = [0; 1500];
+
+        // Nothing is relayed from the peer without a permission
+        client.create_permission(peer_addr.ip()).await.unwrap();
+
+        // Send indication out, Data indication back
+        client.send_to(b"hello", peer_addr).await.unwrap();
+        let (len, from) = peer.recv_from(&mut buf).await.unwrap();
+        assert_eq!((&buf[..len], from), (&b"hello"[..], client.relayed_addr()));
+        peer.send_to(b"hi", client.relayed_addr()).await.unwrap();
+        let (data, from) = client.recv_from().await.unwrap();
+        assert_eq!((&data[..], from), (&b"hi"[..], peer_addr));
+
+        // ChannelData both ways
+        let channel = client.channel_bind(peer_addr).await.unwrap();
+        assert!((CHANNEL_MIN..=CHANNEL_MAX).contains(&channel));
+        assert_eq!(client.channel_bind(peer_addr).await.unwrap(), channel);
+        client.send_to(b"over a channel", peer_addr).await.unwrap();
+        let (len, from) = peer.recv_from(&mut buf).await.unwrap();
+        assert_eq!(
+            (&buf[..len], from),
+            (&b"over a channel"[..], client.relayed_addr())
+        );
+        peer.send_to(b"back", client.relayed_addr()).await.unwrap();
+        let (data, from) = client.recv_from().await.unwrap();
+        assert_eq!((&data[..], from), (&b"back"[..], peer_addr));
+
+        // Larger than any STUN message, both ways
+        let large = vec![0x5a; 8000];
+        let mut buf = vec![0; 65535];
+        client.send_to(&large, peer_addr).await.unwrap();
+        let (len, _) = tokio::time::timeout(Duration::from_secs(1), peer.recv_from(&mut buf))
+            .await
+            .expect("not relayed")
+            .unwrap();
+        assert_eq!(&buf[..len], &large[..]);
+        peer.send_to(&large, client.relayed_addr()).await.unwrap();
+        let (data, _) = client.recv_from().await.unwrap();
+        assert_eq!(&data[..], &large[..]);
+
+        // Only one allocation is allowed, until a Refresh with lifetime 0
+        // deletes it
+        assert!(matches!(
+            allocate(server, PASSWORD).await,
+            Err(TurnError::ErrorResponse(486, _))
+        ));
+        client.close().await.unwrap();
+        allocate(server, PASSWORD)
+            .await
+            .unwrap()
+            .close()
+            .await
+            .unwrap();
+
+        task.abort();
+    }
+}
@@ -0,0 +1,131 @@
+//! Peer which connects to another with ICE, trickling candidates through the
+//! rendezvous server's API port.
+
+mod shared;
+
+use std::{net::SocketAddr, time::Duration};
+
+use clap::Parser;
+use tokio::{net::UdpSocket, time::timeout};
+use tracing::{info, warn};
+use udp_nat_trav::ice::{Agent, AgentConfig, Candidate, Role, END_OF_CANDIDATES};
+
+#[derive(Parser, Debug)]
+#[command()]
+struct Args {
+    #[arg(long)]
+    id: String,
+
+    #[arg(long)]
+    peer_id: String,
+
+    #[arg(long)]
+    server: String,
+
+    /// STUN servers for server-reflexive candidates
+    #[arg(long)]
+    stun: Vec<SocketAddr>,
+
+    /// Local addresses for host candidates
+    #[arg(long, default_value = "0.0.0.0:0")]
+    bind: Vec<SocketAddr>,
+
+    /// One peer has to be controlling
+    #[arg(long)]
+    controlling: bool,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    shared::setup_tracing()?;
+    info!("Started");
+
+    let args = Args::parse();
+    info!("Args: {:?}", args);
+
+    let config = AgentConfig {
+        bind: args.bind.clone(),
+        stun_servers: args.stun.clone(),
+        ..Default::default()
+    };
+    let role = if args.controlling {
+        Role::Controlling
+    } else {
+        Role::Controlled
+    };
+    let (agent, mut candidates) = Agent::trickle(config, role).await?;
+
+    let signal = UdpSocket::bind(("0.0.0.0", 0)).await?;
+    signal
+        .connect((args.server.as_str(), shared::API_PORT))
+        .await?;
+    let (ufrag, pwd) = agent.local_credentials();
+    let msg = shared::Message::IceCredentials {
+        id: args.id.clone(),
+        peer_id: args.peer_id.clone(),
+        ufrag,
+        pwd,
+    };
+    let mut request_id = 0;
+    signal
+        .send(&shared::Frame::new(request_id, msg).encode()?)
+        .await?;
+
+    // Trickle both ways while checking
+    let connect = agent.connect();
+    tokio::pin!(connect);
+    let mut gathered = false;
+    let mut buf = [0; shared::MAX_SIZE + 1];
+    let pair = loop {
+        tokio::select! {
+            res = &mut connect => break res?,
+            candidate = candidates.recv(), if !gathered => {
+                let candidate = match candidate.flatten() {
+                    Some(candidate) => candidate.to_sdp(),
+                    None => {
+                        gathered = true;
+                        String::from(END_OF_CANDIDATES)
+                    }
+                };
+                let msg = shared::Message::IceCandidate {
+                    id: args.id.clone(),
+                    peer_id: args.peer_id.clone(),
+                    candidate,
+                };
+                request_id += 1;
+                signal.send(&shared::Frame::new(request_id, msg).encode()?).await?;
+            }
+            len = signal.recv(&mut buf) => {
+                match shared::Frame::decode(&buf[0..len?]).map(|frame| frame.message) {
+                    Ok(shared::Message::IceCredentials { id, ufrag, pwd, .. }) if id == args.peer_id => {
+                        agent.set_remote_credentials(&ufrag, &pwd);
+                    }
+                    Ok(shared::Message::IceCandidate { id, candidate, .. }) if id == args.peer_id => {
+                        if candidate == END_OF_CANDIDATES {
+                            agent.end_of_remote_candidates();
+                        } else {
+                            match candidate.parse::<Candidate>() {
+                                Ok(candidate) => agent.add_remote_candidate(candidate),
+                                Err(e) => warn!("Bad candidate {:?}: {}", candidate, e),
+                            }
+                        }
+                    }
+                    _ => warn!("Unexpected message"),
+                }
+            }
+        }
+    };
+    info!(
+        "Connected: {} -> {}",
+        pair.local.address, pair.remote.address
+    );
+
+    agent
+        .send(format!("hello from {}", args.id).as_bytes())
+        .await?;
+    let (data, from) = timeout(Duration::from_secs(5), agent.recv()).await??;
+    info!("Rx from {}: {}", from, String::from_utf8_lossy(&data));
+
+    info!("Finished");
+    Ok(())
+}
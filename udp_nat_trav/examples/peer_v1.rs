@@ -5,8 +5,8 @@ mod shared;
 use std::time::Duration;
 
 use clap::Parser;
-use tokio::{net::UdpSocket, time::sleep};
-use tracing::info;
+use tokio::time::timeout;
+use tracing::{info, warn};
 
 #[derive(Parser, Debug)]
 #[command()]
@@ -29,38 +29,35 @@ async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
     info!("Args: {:?}", args);
 
-    // Alpha tests
-    let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
-    let buf = args.id.as_bytes();
-    for port in shared::ALPHA_PORT_BASE..(shared::ALPHA_PORT_BASE + shared::ALPHA_PORT_COUNT) {
-        let addr = (args.server.clone(), port);
-        socket.send_to(buf, addr).await?;
-    }
-
-    // Beta tests
-    for seq_num in 0..shared::BETA_COUNT {
-        let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+    let (socket, mut request_id) = shared::run_tests(&args.server, &args.id, 0).await?;
 
-        let buf = format!(
-            "{}#{}#{}",
-            args.id,
-            socket.local_addr().unwrap().port(),
-            seq_num
-        );
-        let buf = buf.as_bytes();
+    // Query peer, from the socket tested so the server sees its mapping
+    let server_addr = (args.server.clone(), shared::API_PORT);
+    request_id += 1;
+    let msg = shared::Message::QueryReq {
+        id: args.id.clone(),
+        peer_id: args.peer_id.clone(),
+        local_port: socket.local_addr()?.port(),
+    };
+    socket
+        .send_to(&shared::Frame::new(request_id, msg).encode()?, server_addr)
+        .await?;
 
-        let addr = (args.server.clone(), shared::BETA_PORT);
-        socket.send_to(buf, addr).await?;
+    let mut buf = [0; shared::MAX_SIZE + 1];
+    match timeout(Duration::from_millis(1000), socket.recv_from(&mut buf)).await {
+        Ok(res) => {
+            let (len, _) = res?;
+            let frame = shared::Frame::decode(&buf[..len])?;
+            if frame.request_id != request_id {
+                warn!("Response to request {}", frame.request_id);
+            }
+            info!("Rx: {:?}", frame.message);
+        }
+        Err(_) => warn!("No response to query"),
     }
 
-    // Query peer
-    sleep(Duration::from_millis(1000)).await;
-    let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
-    let msg = shared::Message::QueryReq(format!("{}#{}", args.id, args.peer_id));
-    let buf = serde_json::to_string(&msg)?;
-    let buf = buf.as_bytes();
-    let addr = (args.server.clone(), shared::API_PORT);
-    socket.send_to(buf, addr).await?;
+    request_id += 1;
+    shared::coordinated_punch(&socket, &args.server, request_id, &args.id, &args.peer_id).await?;
 
     info!("Finished");
     Ok(())
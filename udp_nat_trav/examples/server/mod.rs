@@ -1,5 +0,0 @@
-mod alpha;
-mod beta;
-
-pub use alpha::AlphaManager;
-pub use beta::BetaManager;
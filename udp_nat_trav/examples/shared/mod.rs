@@ -1,17 +1,32 @@
-use serde::{Deserialize, Serialize};
-use tracing::Level;
-
-pub const ALPHA_PORT_BASE: u16 = 4000;
-pub const ALPHA_PORT_COUNT: u16 = 10;
-pub const BETA_PORT: u16 = 4010;
-pub const BETA_COUNT: usize = 10;
-pub const API_PORT: u16 = 4011;
-
-#[derive(Debug, Serialize, Deserialize)]
-pub enum Message {
-    QueryReq(String), // id#peer_id
-    QueryRes(String), //
-}
+// Each example uses some of these
+#![allow(dead_code, unused_imports)]
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use rand::Rng;
+use tokio::{
+    net::{ToSocketAddrs, UdpSocket},
+    time::{interval, sleep, timeout},
+};
+use tracing::{info, trace, warn, Level};
+use udp_nat_trav::rendezvous::{unix_micros, until, Candidate, PortPrediction, PunchOutcome};
+
+pub use udp_nat_trav::rendezvous::{
+    Frame, Message, ALPHA_PORT_BASE, ALPHA_PORT_COUNT, API_PORT, BETA_COUNT, BETA_PORT, MAX_SIZE,
+};
+
+/// How long to wait for the peer to ask to punch too
+const SIGNAL_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often a punch request is sent until the server probes our clock
+const RESEND_INTERVAL: Duration = Duration::from_millis(500);
+/// How long to wait for the peer's punches after sending ours
+const PUNCH_TIMEOUT: Duration = Duration::from_secs(3);
+/// Most ports punched
+const PUNCH_PORTS: usize = 1000;
+const JUST_IN_CASE: usize = 3;
 
 pub fn setup_tracing() -> anyhow::Result<()> {
     // Set tracing level
@@ -22,3 +37,201 @@ pub fn setup_tracing() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Run the alpha and beta tests as `id`, with request ids from
+/// `request_id`. Returns the alpha test's socket, whose mapping the server
+/// has seen, and the last request id used.
+pub async fn run_tests(
+    server: &str,
+    id: &str,
+    request_id: u32,
+) -> anyhow::Result<(UdpSocket, u32)> {
+    let mut request_id = request_id;
+
+    // Alpha tests
+    let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+    let msg = Message::Alpha {
+        id: String::from(id),
+    };
+    let buf = Frame::new(request_id, msg).encode()?;
+    for port in ALPHA_PORT_BASE..(ALPHA_PORT_BASE + ALPHA_PORT_COUNT) {
+        socket.send_to(&buf, (server, port)).await?;
+    }
+
+    // Beta tests
+    for seq_num in 0..BETA_COUNT {
+        let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+
+        request_id += 1;
+        let msg = Message::Beta {
+            id: String::from(id),
+            local_port: socket.local_addr()?.port(),
+            seq_num: seq_num as u16,
+        };
+        let buf = Frame::new(request_id, msg).encode()?;
+        socket.send_to(&buf, (server, BETA_PORT)).await?;
+    }
+
+    // For the datagrams to arrive
+    sleep(Duration::from_millis(1000)).await;
+    Ok((socket, request_id))
+}
+
+/// Ask the server to have `id` and `peer_id` punch at the same time, punch
+/// from `socket` when told, and report the outcome to the server. None if
+/// there was nothing to punch.
+pub async fn coordinated_punch(
+    socket: &UdpSocket,
+    server: &str,
+    request_id: u32,
+    id: &str,
+    peer_id: &str,
+) -> anyhow::Result<Option<PunchOutcome>> {
+    let server_addr = (server, API_PORT);
+
+    // Wait for the peer to ask too, and for the time to punch
+    let msg = Message::PunchReq {
+        id: String::from(id),
+        peer_id: String::from(peer_id),
+        local_port: socket.local_addr()?.port(),
+    };
+    let request = Frame::new(request_id, msg).encode()?;
+    let instruction = punch_instruction(socket, server_addr, &request, id);
+    let Ok(instruction) = timeout(SIGNAL_TIMEOUT, instruction).await else {
+        warn!("No punch instruction");
+        return Ok(None);
+    };
+    let Some((at, public_ip, ports)) = instruction? else {
+        return Ok(None);
+    };
+
+    sleep(until(at)).await;
+    punch(socket, id, public_ip, &ports).await?;
+    let outcome = match timeout(PUNCH_TIMEOUT, wait_for_peer(socket, id, peer_id)).await {
+        Ok(addr) => PunchOutcome::Success(addr?),
+        Err(_) => PunchOutcome::Failure,
+    };
+    info!("Punch outcome: {:?}", outcome);
+
+    let msg = Message::PunchReport {
+        id: String::from(id),
+        peer_id: String::from(peer_id),
+        outcome: outcome.clone(),
+    };
+    socket
+        .send_to(&Frame::new(request_id + 1, msg).encode()?, server_addr)
+        .await?;
+
+    Ok(Some(outcome))
+}
+
+/// Send `request`, a punch request, until the server probes our clock, then
+/// answer probes until told when to punch. None if the server cannot say how
+/// to reach the peer.
+async fn punch_instruction(
+    socket: &UdpSocket,
+    server_addr: impl ToSocketAddrs + Clone,
+    request: &[u8],
+    id: &str,
+) -> anyhow::Result<Option<(u64, IpAddr, Vec<Candidate>)>> {
+    let mut buf = [0; MAX_SIZE + 1];
+    let mut resend = interval(RESEND_INTERVAL);
+    let mut probed = false;
+    loop {
+        let (len, from) = tokio::select! {
+            _ = resend.tick(), if !probed => {
+                socket.send_to(request, server_addr.clone()).await?;
+                continue;
+            }
+            res = socket.recv_from(&mut buf) => res?,
+        };
+        let frame = match Frame::decode(&buf[..len]) {
+            Ok(frame) => frame,
+            Err(e) => {
+                warn!("Unable to decode message from {}: {}", from, e);
+                continue;
+            }
+        };
+        match frame.message {
+            Message::ClockProbe { server_time } => {
+                probed = true;
+                let echo = Message::ClockEcho {
+                    id: String::from(id),
+                    server_time,
+                    peer_time: unix_micros(),
+                };
+                let echo = Frame::new(frame.request_id, echo);
+                socket.send_to(&echo.encode()?, from).await?;
+            }
+            Message::PunchAt {
+                at,
+                public_ip,
+                ports,
+                strategy,
+                ..
+            } => {
+                info!("Punch in {:?} with {:?}", until(at), strategy);
+                return Ok(Some((at, public_ip, ports)));
+            }
+            Message::QueryRes(response) => {
+                warn!("Unable to punch: {:?}", response);
+                return Ok(None);
+            }
+            msg => warn!("Unexpected message from {}: {:?}", from, msg),
+        }
+    }
+}
+
+/// Send to the most probable ports first, up to `PUNCH_PORTS` of them
+async fn punch(
+    socket: &UdpSocket,
+    id: &str,
+    ip: IpAddr,
+    candidates: &[Candidate],
+) -> anyhow::Result<()> {
+    let msg = Message::Punch {
+        id: String::from(id),
+    };
+    let msg = Frame::new(0, msg).encode()?;
+
+    let mut ports: Vec<u16> = Vec::with_capacity(PUNCH_PORTS);
+    for candidate in candidates {
+        let range = candidate.port.ports();
+        let budget = PUNCH_PORTS - ports.len();
+        match candidate.port {
+            PortPrediction::Random(..) => {
+                ports.extend((0..budget).map(|_| rand::thread_rng().gen_range(range.clone())))
+            }
+            _ => ports.extend(range.take(budget)),
+        }
+    }
+
+    for port in &ports {
+        for _ in 0..JUST_IN_CASE {
+            socket.send_to(&msg, (ip, *port)).await?;
+        }
+    }
+    info!("Punched {} ports of {}", ports.len(), ip);
+
+    Ok(())
+}
+
+/// Where the peer's first punch came from. It is answered once so the peer
+/// hears from us even if its NAT dropped our punches.
+async fn wait_for_peer(socket: &UdpSocket, id: &str, peer_id: &str) -> anyhow::Result<SocketAddr> {
+    let mut buf = [0; MAX_SIZE + 1];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        match Frame::decode(&buf[..len]).map(|frame| frame.message) {
+            Ok(Message::Punch { id: from_id }) if from_id == peer_id => {
+                let msg = Message::Punch {
+                    id: String::from(id),
+                };
+                let msg = Frame::new(0, msg);
+                socket.send_to(&msg.encode()?, from).await?;
+                return Ok(from);
+            }
+            _ => trace!("Ignoring {} bytes from {}", len, from),
+        }
+    }
+}
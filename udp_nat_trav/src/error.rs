@@ -0,0 +1,140 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    io,
+};
+
+use stun_test::error::TurnError;
+
+#[derive(Debug)]
+pub enum IceError {
+    Io(io::Error),
+    Turn(TurnError),
+    /// No local candidates could be gathered
+    NoCandidates,
+    /// Every candidate pair failed its connectivity checks
+    Failed,
+    /// No pair was selected in time
+    Timeout,
+    /// No pair has been selected yet
+    NotConnected,
+    /// The peer stopped answering consent checks on the selected pair
+    ConsentExpired,
+    Closed,
+}
+
+impl Display for IceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(_) => write!(f, "I/O error"),
+            Self::Turn(_) => write!(f, "TURN error"),
+            Self::NoCandidates => write!(f, "no local candidates"),
+            Self::Failed => write!(f, "all candidate pairs failed"),
+            Self::Timeout => write!(f, "timed out"),
+            Self::NotConnected => write!(f, "no candidate pair selected"),
+            Self::ConsentExpired => write!(f, "consent expired"),
+            Self::Closed => write!(f, "agent closed"),
+        }
+    }
+}
+
+impl std::error::Error for IceError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Turn(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for IceError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<TurnError> for IceError {
+    fn from(e: TurnError) -> Self {
+        Self::Turn(e)
+    }
+}
+
+/// A candidate attribute that could not be parsed
+#[derive(Debug)]
+pub enum CandidateError {
+    /// Not an `a=candidate:` line
+    NotCandidate,
+    Missing(&'static str),
+    Invalid(&'static str),
+    /// Only UDP candidates are supported
+    UnsupportedTransport(String),
+}
+
+impl Display for CandidateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotCandidate => write!(f, "not a candidate attribute"),
+            Self::Missing(field) => write!(f, "missing {}", field),
+            Self::Invalid(field) => write!(f, "invalid {}", field),
+            Self::UnsupportedTransport(transport) => {
+                write!(f, "unsupported transport {}", transport)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CandidateError {}
+
+/// A datagram that is not a valid [`Frame`](crate::rendezvous::Frame), or a
+/// frame that does not fit in one
+#[derive(Debug)]
+pub enum WireError {
+    /// Shorter than the header
+    Truncated,
+    /// Larger than [`MAX_SIZE`](crate::rendezvous::MAX_SIZE)
+    TooLarge(usize),
+    BadMagic(u8),
+    UnsupportedVersion(u8),
+    UnknownType(u8),
+    /// Bytes left over after the body
+    TrailingBytes(usize),
+    Encode(ciborium::ser::Error<io::Error>),
+    Decode(ciborium::de::Error<io::Error>),
+}
+
+impl Display for WireError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated => write!(f, "truncated frame"),
+            Self::TooLarge(len) => write!(f, "frame of {} bytes is too large", len),
+            Self::BadMagic(magic) => write!(f, "bad magic byte {:#04x}", magic),
+            Self::UnsupportedVersion(version) => write!(f, "unsupported version {}", version),
+            Self::UnknownType(kind) => write!(f, "unknown message type {}", kind),
+            Self::TrailingBytes(len) => write!(f, "{} bytes after the body", len),
+            Self::Encode(_) => write!(f, "unable to encode message"),
+            Self::Decode(_) => write!(f, "unable to decode message"),
+        }
+    }
+}
+
+impl std::error::Error for WireError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Encode(e) => Some(e),
+            Self::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ciborium::ser::Error<io::Error>> for WireError {
+    fn from(e: ciborium::ser::Error<io::Error>) -> Self {
+        Self::Encode(e)
+    }
+}
+
+impl From<ciborium::de::Error<io::Error>> for WireError {
+    fn from(e: ciborium::de::Error<io::Error>) -> Self {
+        Self::Decode(e)
+    }
+}
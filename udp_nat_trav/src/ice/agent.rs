@@ -0,0 +1,1326 @@
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use rand::{distributions::Alphanumeric, random, Rng};
+use stun_test::{
+    attribure::{Attribute, AttributeType},
+    demux,
+    message::{Message, MessageClass, MessageMethod},
+    server::error_response,
+    transaction::{Demuxed, TransactionConfig},
+    turn::TurnClient,
+};
+use tokio::{
+    sync::mpsc,
+    task::JoinHandle,
+    time::{interval, sleep_until},
+};
+use tracing::{debug, info, warn};
+
+use super::{
+    candidate::{self, Candidate, CandidateType, COMPONENT},
+    check_list::{Check, CheckList, Pair, PairState},
+    gather::{self, Base, Found, Transport},
+};
+use crate::error::IceError;
+
+/// Data from the peer waiting for `Agent::recv`, beyond which it is dropped
+const FORWARD_QUEUE_LEN: usize = 1024;
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Role {
+    /// Decides which pair is used
+    Controlling,
+    Controlled,
+}
+
+#[derive(Clone, Debug)]
+pub struct TurnServer {
+    pub addr: SocketAddr,
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct AgentConfig {
+    /// Local addresses for host candidates. An unspecified IP is replaced by
+    /// the IP of the interface with the default route.
+    pub bind: Vec<SocketAddr>,
+    pub stun_servers: Vec<SocketAddr>,
+    pub turn_servers: Vec<TurnServer>,
+    /// How long to wait for each server while gathering
+    pub gather_timeout: Duration,
+    /// Retransmission of gathering requests and connectivity checks
+    pub transaction: TransactionConfig,
+    /// Pacing of connectivity checks (Ta)
+    pub ta: Duration,
+    /// How long the controlling agent waits for a better pair after the
+    /// first one succeeds, before nominating
+    pub nomination_delay: Duration,
+    pub connect_timeout: Duration,
+    /// Most candidate pairs to form
+    pub max_pairs: usize,
+    /// Mean time between consent checks on the selected pair, which also
+    /// keep the NAT mapping alive. Each is jittered by up to 20%.
+    pub consent_interval: Duration,
+    /// The path is lost when no consent check is answered for this long
+    /// (RFC 7675 section 5.1)
+    pub consent_timeout: Duration,
+    /// The NAT's mapping lifetime, if measured, e.g. with `nat_test`.
+    /// Consent checks are then sent at least twice per lifetime.
+    pub mapping_lifetime: Option<Duration>,
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        Self {
+            bind: vec![SocketAddr::from(([0, 0, 0, 0], 0))],
+            stun_servers: Vec::new(),
+            turn_servers: Vec::new(),
+            gather_timeout: Duration::from_secs(5),
+            transaction: TransactionConfig::default(),
+            ta: Duration::from_millis(50),
+            nomination_delay: Duration::from_millis(500),
+            connect_timeout: Duration::from_secs(30),
+            max_pairs: 100,
+            consent_interval: Duration::from_secs(5),
+            consent_timeout: Duration::from_secs(30),
+            mapping_lifetime: None,
+        }
+    }
+}
+
+impl AgentConfig {
+    /// The mean time between consent checks
+    pub fn keepalive_interval(&self) -> Duration {
+        match self.mapping_lifetime {
+            Some(lifetime) => self.consent_interval.min(lifetime / 2),
+            None => self.consent_interval,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CandidatePair {
+    pub local: Candidate,
+    pub remote: Candidate,
+}
+
+struct RemoteCredentials {
+    ufrag: String,
+    pwd: String,
+}
+
+/// Consent freshness on the selected pair (RFC 7675)
+struct Consent {
+    /// When a consent check was last answered
+    last: Instant,
+    /// Consent checks sent, which are not retransmitted
+    pending: VecDeque<([u8; 12], Instant)>,
+    lost: bool,
+}
+
+struct State {
+    role: Role,
+    local: Vec<Candidate>,
+    /// Grows as relays are allocated
+    bases: Vec<Arc<Base>>,
+    /// All servers have answered or timed out
+    local_complete: bool,
+    remote_credentials: Option<RemoteCredentials>,
+    remote: Vec<Candidate>,
+    /// The peer has sent end-of-candidates
+    remote_complete: bool,
+    check_list: CheckList,
+    selected: Option<usize>,
+    /// When the first pair succeeded, to nominate after a while
+    first_success: Option<Instant>,
+    /// Set once connected
+    consent: Option<Consent>,
+}
+
+impl State {
+    fn pair_priority(&self, pair: &Pair) -> u64 {
+        let local = self.local[pair.local].priority;
+        let remote = self.remote[pair.remote].priority;
+        match self.role {
+            Role::Controlling => candidate::pair_priority(local, remote),
+            Role::Controlled => candidate::pair_priority(remote, local),
+        }
+    }
+
+    fn set_role(&mut self, role: Role) {
+        info!("Switching role to {:?}", role);
+        self.role = role;
+        for i in 0..self.check_list.pairs.len() {
+            let priority = self.pair_priority(&self.check_list.pairs[i]);
+            self.check_list.pairs[i].priority = priority;
+        }
+    }
+
+    /// Add a Frozen pair of `base` and `remote` unless there is one already,
+    /// or there are enough
+    fn form_pair(&mut self, base: usize, remote: usize, max_pairs: usize) {
+        let candidate = self.bases[base].candidate;
+        let (local, theirs) = (&self.local[candidate], &self.remote[remote]);
+        if local.address.is_ipv4() != theirs.address.is_ipv4()
+            || self.check_list.find(base, remote).is_some()
+        {
+            return;
+        }
+        if self.check_list.pairs.len() >= max_pairs {
+            debug!("Not pairing with {}, too many pairs", theirs.address);
+            return;
+        }
+        let foundation = format!("{}:{}", local.foundation, theirs.foundation);
+        let mut pair = Pair::new(candidate, remote, base, foundation);
+        pair.priority = self.pair_priority(&pair);
+        self.check_list.add(pair);
+    }
+
+    /// The pair of `base` and `remote`, added Waiting if it is new
+    fn pair(&mut self, base: usize, remote: usize, candidate: usize) -> usize {
+        if let Some(i) = self.check_list.find(base, remote) {
+            return i;
+        }
+        let foundation = format!(
+            "{}:{}",
+            self.local[candidate].foundation, self.remote[remote].foundation
+        );
+        let mut pair = Pair::new(candidate, remote, base, foundation);
+        pair.priority = self.pair_priority(&pair);
+        pair.state = PairState::Waiting;
+        self.check_list.add(pair)
+    }
+
+    fn nominate(&mut self, i: usize) {
+        let better = match self.selected {
+            Some(j) => self.check_list.pairs[i].priority > self.check_list.pairs[j].priority,
+            None => true,
+        };
+        if better {
+            let pair = &self.check_list.pairs[i];
+            info!(
+                "Selected {} -> {}",
+                self.local[pair.local].address, self.remote[pair.remote].address
+            );
+            self.selected = Some(i);
+        }
+    }
+
+    fn candidate_pair(&self, i: usize) -> CandidatePair {
+        let pair = &self.check_list.pairs[i];
+        CandidatePair {
+            local: self.local[pair.local].clone(),
+            remote: self.remote[pair.remote].clone(),
+        }
+    }
+}
+
+/// A STUN message or data to send from a base
+struct Outgoing {
+    base: usize,
+    bytes: Bytes,
+    to: SocketAddr,
+}
+
+struct Inner {
+    config: AgentConfig,
+    local_ufrag: String,
+    local_pwd: String,
+    tie_breaker: u64,
+    state: Mutex<State>,
+    data: mpsc::Sender<(Bytes, SocketAddr)>,
+    /// Receiving on each base, gathering, and keeping consent
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+    path_lost: Mutex<Option<PathLost>>,
+}
+
+impl Inner {
+    async fn send_all(&self, outgoing: Vec<Outgoing>) {
+        for x in outgoing {
+            let base = self.state.lock().unwrap().bases[x.base].clone();
+            if let Err(e) = base.send_to(&x.bytes, x.to).await {
+                debug!("Failed to send to {}: {}", x.to, e);
+            }
+        }
+    }
+
+    /// Handle STUN and data arriving on a socket base, each in its own task
+    fn spawn_demuxed(self: &Arc<Self>, base: usize, demuxed: Demuxed) {
+        let Demuxed { mut stun, mut data } = demuxed;
+        let inner = self.clone();
+        let stun_task = tokio::spawn(async move {
+            while let Some((raw, from)) = stun.recv().await {
+                inner.handle_stun(base, raw, from).await;
+            }
+        });
+        let inner = self.clone();
+        let data_task = tokio::spawn(async move {
+            while let Some((raw, from)) = data.recv().await {
+                inner.handle_data(raw, from);
+            }
+        });
+        self.tasks.lock().unwrap().extend([stun_task, data_task]);
+    }
+
+    /// Pass what arrives through a relay base to `handle_datagram`
+    fn spawn_relayed(self: &Arc<Self>, base: usize, turn: Arc<TurnClient>) {
+        let inner = self.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                match turn.recv_from().await {
+                    Ok((raw, from)) => inner.handle_datagram(base, raw, from).await,
+                    Err(e) => {
+                        warn!("Relay closed: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+        self.tasks.lock().unwrap().push(task);
+    }
+
+    /// Add a candidate from a server. A relay is a new base, paired with the
+    /// remote candidates so far. Returns the candidate unless we had it.
+    fn add_found(self: &Arc<Self>, found: Found) -> Option<Candidate> {
+        let mut state = self.state.lock().unwrap();
+        let candidate = match &found {
+            Found::Reflexive(candidate) | Found::Relayed(candidate, _) => candidate.clone(),
+        };
+        if state.local.iter().any(|x| x.address == candidate.address) {
+            return None;
+        }
+        info!("Gathered {:?} {}", candidate.kind, candidate.address);
+        state.local.push(candidate.clone());
+        if let Found::Relayed(_, turn) = found {
+            let turn = Arc::new(turn);
+            let candidate = state.local.len() - 1;
+            state.bases.push(Arc::new(Base {
+                transport: Transport::Relay(turn.clone()),
+                candidate,
+            }));
+            let base = state.bases.len() - 1;
+            self.spawn_relayed(base, turn);
+            for remote in 0..state.remote.len() {
+                state.form_pair(base, remote, self.config.max_pairs);
+            }
+            state.check_list.unfreeze_initial();
+        }
+        Some(candidate)
+    }
+
+    async fn handle_datagram(&self, base: usize, raw: Bytes, from: SocketAddr) {
+        if demux::is_stun(&raw) {
+            self.handle_stun(base, raw, from).await;
+        } else {
+            self.handle_data(raw, from);
+        }
+    }
+
+    /// Pass on data from the peer, but nothing from anyone else
+    fn handle_data(&self, raw: Bytes, from: SocketAddr) {
+        let known = self
+            .state
+            .lock()
+            .unwrap()
+            .remote
+            .iter()
+            .any(|candidate| candidate.address == from);
+        if !known {
+            debug!("Ignoring {} bytes from {}", raw.len(), from);
+        } else if let Err(mpsc::error::TrySendError::Full((raw, _))) =
+            self.data.try_send((raw, from))
+        {
+            debug!("Dropping {} bytes from {}, queue full", raw.len(), from);
+        }
+    }
+
+    async fn handle_stun(&self, base: usize, raw: Bytes, from: SocketAddr) {
+        let message = match Message::from_bytes(&mut raw.clone()) {
+            Ok(msg) => msg,
+            Err(e) => {
+                debug!("Bad STUN message from {}: {}", from, e);
+                return;
+            }
+        };
+        match (message.method, message.class) {
+            (MessageMethod::Binding, MessageClass::Request) => {
+                let outgoing = self.handle_request(base, &message, &raw, from);
+                self.send_all(vec![outgoing]).await;
+            }
+            (
+                MessageMethod::Binding,
+                MessageClass::ResponseSuccess | MessageClass::ResponseFailure,
+            ) => self.handle_response(&message, &raw, from),
+            _ => debug!(
+                "Ignoring {:?} {:?} from {}",
+                message.method, message.class, from
+            ),
+        }
+    }
+
+    /// Answer a connectivity check, and check the pair it came in on
+    /// ourselves (RFC 8445 section 7.3)
+    fn handle_request(
+        &self,
+        base: usize,
+        request: &Message,
+        raw: &[u8],
+        from: SocketAddr,
+    ) -> Outgoing {
+        let key = self.local_pwd.as_bytes();
+        let reply = |response: Message, key: Option<&[u8]>| Outgoing {
+            base,
+            bytes: response.encode(key, true),
+            to: from,
+        };
+
+        let ours = match request.attribute(AttributeType::Username) {
+            Some(Attribute::Username(username)) => {
+                username.split(':').next() == Some(self.local_ufrag.as_str())
+            }
+            _ => false,
+        };
+        if !ours || request.attribute(AttributeType::MessageIntegrity).is_none() {
+            debug!("Check without our username from {}", from);
+            return reply(error_response(request, 400, "Bad Request"), None);
+        }
+        if !Message::check_integrity(raw, key) {
+            debug!("Check with bad integrity from {}", from);
+            return reply(error_response(request, 401, "Unauthorized"), None);
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        // Role conflicts (RFC 8445 section 7.3.1.1)
+        match (
+            state.role,
+            request.attribute(AttributeType::IceControlling),
+            request.attribute(AttributeType::IceControlled),
+        ) {
+            (Role::Controlling, Some(Attribute::IceControlling(theirs)), _) => {
+                if self.tie_breaker >= *theirs {
+                    return reply(error_response(request, 487, "Role Conflict"), Some(key));
+                }
+                state.set_role(Role::Controlled);
+            }
+            (Role::Controlled, _, Some(Attribute::IceControlled(theirs))) => {
+                if self.tie_breaker < *theirs {
+                    return reply(error_response(request, 487, "Role Conflict"), Some(key));
+                }
+                state.set_role(Role::Controlling);
+            }
+            _ => {}
+        }
+
+        // A source we do not know is a peer-reflexive candidate
+        let remote = match state.remote.iter().position(|x| x.address == from) {
+            Some(i) => i,
+            None => {
+                let priority = match request.attribute(AttributeType::Priority) {
+                    Some(Attribute::Priority(x)) => *x,
+                    _ => 0,
+                };
+                info!("Discovered remote peer-reflexive candidate {}", from);
+                state.remote.push(Candidate {
+                    foundation: random::<u32>().to_string(),
+                    component: COMPONENT,
+                    priority,
+                    address: from,
+                    kind: CandidateType::PeerReflexive,
+                    related: None,
+                });
+                state.remote.len() - 1
+            }
+        };
+
+        let candidate = state.bases[base].candidate;
+        let i = state.pair(base, remote, candidate);
+        let use_candidate = request.attribute(AttributeType::UseCandidate).is_some();
+        match state.check_list.pairs[i].state {
+            PairState::Succeeded => {
+                if use_candidate && state.role == Role::Controlled {
+                    state.nominate(i);
+                }
+            }
+            PairState::InProgress => {
+                if use_candidate {
+                    state.check_list.pairs[i].nominate_on_success = true;
+                }
+            }
+            _ => {
+                let pair = &mut state.check_list.pairs[i];
+                pair.state = PairState::Waiting;
+                pair.nominate_on_success |= use_candidate;
+                state.check_list.trigger(i);
+            }
+        }
+
+        let response = Message {
+            method: MessageMethod::Binding,
+            class: MessageClass::ResponseSuccess,
+            id: request.id,
+            attributes: vec![Attribute::XorMappedAddress(from)],
+        };
+        reply(response, Some(key))
+    }
+
+    fn handle_response(&self, response: &Message, raw: &[u8], from: SocketAddr) {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        if let Some(consent) = &mut state.consent {
+            if let Some(i) = consent
+                .pending
+                .iter()
+                .position(|(id, _)| *id == response.id)
+            {
+                let selected = &state.check_list.pairs[state.selected.expect("consent is for it")];
+                let fresh = response.class == MessageClass::ResponseSuccess
+                    && from == state.remote[selected.remote].address
+                    && state
+                        .remote_credentials
+                        .as_ref()
+                        .is_some_and(|credentials| {
+                            Message::check_integrity(raw, credentials.pwd.as_bytes())
+                        });
+                if fresh && !consent.lost {
+                    let (_, sent) = consent.pending.remove(i).expect("found above");
+                    consent.last = sent.max(consent.last);
+                } else {
+                    debug!("Consent check from {} not answered", from);
+                }
+                return;
+            }
+        }
+        let Some(i) = state.check_list.pairs.iter().position(|pair| {
+            pair.check
+                .as_ref()
+                .is_some_and(|check| check.id == response.id)
+        }) else {
+            debug!("Response from {} to no check of ours", from);
+            return;
+        };
+        let Some(credentials) = &state.remote_credentials else {
+            return;
+        };
+        if !Message::check_integrity(raw, credentials.pwd.as_bytes()) {
+            debug!("Response with bad integrity from {}", from);
+            return;
+        }
+
+        let remote = state.check_list.pairs[i].remote;
+        let check = state.check_list.pairs[i]
+            .check
+            .take()
+            .expect("matched above");
+        if from != state.remote[remote].address {
+            debug!(
+                "Response from {}, not {}",
+                from, state.remote[remote].address
+            );
+            state.check_list.pairs[i].state = PairState::Failed;
+            return;
+        }
+
+        if response.class == MessageClass::ResponseFailure {
+            let code = match response.attribute(AttributeType::ErrorCode) {
+                Some(Attribute::ErrorCode(code, _)) => *code,
+                _ => 0,
+            };
+            if code == 487 {
+                // Unless we already switched since sending
+                if check.controlling == (state.role == Role::Controlling) {
+                    let role = match state.role {
+                        Role::Controlling => Role::Controlled,
+                        Role::Controlled => Role::Controlling,
+                    };
+                    state.set_role(role);
+                }
+                state.check_list.pairs[i].state = PairState::Waiting;
+                state.check_list.trigger(i);
+            } else {
+                debug!("Check to {} failed with {}", from, code);
+                state.check_list.pairs[i].state = PairState::Failed;
+            }
+            return;
+        }
+
+        // Learn our peer-reflexive candidate, in case the peer asks
+        if let Some(mapped) = response.mapped_address() {
+            if !state.local.iter().any(|x| x.address == mapped) {
+                let base = state.check_list.pairs[i].base;
+                let host = &state.local[state.bases[base].candidate];
+                let candidate = Candidate::new(
+                    CandidateType::PeerReflexive,
+                    mapped,
+                    host.address.ip(),
+                    None,
+                    Some(host.address),
+                    host.local_preference(),
+                );
+                info!("Discovered local peer-reflexive candidate {}", mapped);
+                state.local.push(candidate);
+            }
+        }
+
+        let pair = &mut state.check_list.pairs[i];
+        pair.state = PairState::Succeeded;
+        let nominated = (check.use_candidate && state.role == Role::Controlling)
+            || (pair.nominate_on_success && state.role == Role::Controlled);
+        let foundation = pair.foundation.clone();
+        info!(
+            "Check {} -> {} succeeded",
+            state.local[pair.local].address, from
+        );
+        state.check_list.unfreeze_foundation(&foundation);
+        state.first_success.get_or_insert_with(Instant::now);
+        if nominated {
+            state.nominate(i);
+        }
+    }
+
+    /// One pacing interval: retransmit or time out checks, start the next
+    /// one, and nominate. Returns the outcome once there is one.
+    fn tick(&self, now: Instant) -> (Vec<Outgoing>, Option<Result<CandidatePair, IceError>>) {
+        let mut state = self.state.lock().unwrap();
+        let mut outgoing = Vec::new();
+        if let Some(i) = state.selected {
+            return (outgoing, Some(Ok(state.candidate_pair(i))));
+        }
+        if state.remote_credentials.is_none() {
+            return (outgoing, None);
+        }
+
+        let config = &self.config.transaction;
+        for i in 0..state.check_list.pairs.len() {
+            let to = state.remote[state.check_list.pairs[i].remote].address;
+            let pair = &mut state.check_list.pairs[i];
+            let Some(check) = &mut pair.check else {
+                continue;
+            };
+            if check.next > now {
+                continue;
+            }
+            if check.sends >= config.rc {
+                debug!("Check to {} timed out", to);
+                pair.check = None;
+                pair.state = PairState::Failed;
+                continue;
+            }
+            check.sends += 1;
+            check.next = now + config.wait_after(check.sends);
+            outgoing.push(Outgoing {
+                base: pair.base,
+                bytes: check.request.clone(),
+                to,
+            });
+        }
+
+        if let Some(i) = state.check_list.next() {
+            outgoing.push(self.start_check(&mut state, i, now));
+        }
+
+        // Regular nomination of the best pair so far
+        let nominating = state
+            .check_list
+            .pairs
+            .iter()
+            .any(|pair| pair.use_candidate && pair.state != PairState::Failed);
+        let waited = state
+            .first_success
+            .is_some_and(|at| now.duration_since(at) >= self.config.nomination_delay);
+        if state.role == Role::Controlling && !nominating && (waited || state.check_list.is_done())
+        {
+            if let Some(i) = state.check_list.highest(PairState::Succeeded) {
+                debug!("Nominating pair {}", i);
+                state.check_list.pairs[i].use_candidate = true;
+                state.check_list.triggered.push_front(i);
+            }
+        }
+
+        // More candidates could still come
+        let failed = state.local_complete
+            && state.remote_complete
+            && state.check_list.is_done()
+            && state.check_list.highest(PairState::Succeeded).is_none();
+        (outgoing, failed.then_some(Err(IceError::Failed)))
+    }
+
+    fn start_check(&self, state: &mut State, i: usize, now: Instant) -> Outgoing {
+        let pair = &state.check_list.pairs[i];
+        let local = &state.local[pair.local];
+        let use_candidate = state.role == Role::Controlling && pair.use_candidate;
+        let (id, bytes) = self.binding_request(state, pair.local, use_candidate);
+        let to = state.remote[pair.remote].address;
+        debug!("Check {} -> {}", local.address, to);
+
+        let pair = &mut state.check_list.pairs[i];
+        pair.state = PairState::InProgress;
+        pair.check = Some(Check {
+            id,
+            request: bytes.clone(),
+            sends: 1,
+            next: now + self.config.transaction.wait_after(1),
+            use_candidate,
+            controlling: state.role == Role::Controlling,
+        });
+        Outgoing {
+            base: pair.base,
+            bytes,
+            to,
+        }
+    }
+
+    /// A connectivity or consent check from local candidate `local`
+    fn binding_request(
+        &self,
+        state: &State,
+        local: usize,
+        use_candidate: bool,
+    ) -> ([u8; 12], Bytes) {
+        let credentials = state
+            .remote_credentials
+            .as_ref()
+            .expect("checks need remote credentials");
+        let local = &state.local[local];
+        let mut attributes = vec![
+            Attribute::Username(format!("{}:{}", credentials.ufrag, self.local_ufrag)),
+            Attribute::Priority(candidate::priority(
+                CandidateType::PeerReflexive,
+                local.local_preference(),
+                COMPONENT,
+            )),
+            match state.role {
+                Role::Controlling => Attribute::IceControlling(self.tie_breaker),
+                Role::Controlled => Attribute::IceControlled(self.tie_breaker),
+            },
+        ];
+        if use_candidate {
+            attributes.push(Attribute::UseCandidate);
+        }
+        let request = Message {
+            method: MessageMethod::Binding,
+            class: MessageClass::Request,
+            id: random(),
+            attributes,
+        };
+        (
+            request.id,
+            request.encode(Some(credentials.pwd.as_bytes()), true),
+        )
+    }
+
+    /// Start consent checks once a pair is selected
+    fn start_consent(self: &Arc<Self>) {
+        let mut state = self.state.lock().unwrap();
+        if state.consent.is_some() {
+            return;
+        }
+        state.consent = Some(Consent {
+            last: Instant::now(),
+            pending: VecDeque::new(),
+            lost: false,
+        });
+        let inner = self.clone();
+        let task = tokio::spawn(async move { inner.keep_consent().await });
+        self.tasks.lock().unwrap().push(task);
+    }
+
+    /// Send consent checks at jittered intervals until consent expires
+    /// (RFC 7675 section 5.1)
+    async fn keep_consent(&self) {
+        let interval = self.config.keepalive_interval();
+        let jittered = || interval.mul_f64(rand::thread_rng().gen_range(0.8..1.2));
+        let mut next = Instant::now() + jittered();
+        loop {
+            let expiry = {
+                let state = self.state.lock().unwrap();
+                state.consent.as_ref().expect("started").last + self.config.consent_timeout
+            };
+            sleep_until(next.min(expiry).into()).await;
+            let now = Instant::now();
+            if now < next {
+                // Maybe answered meanwhile
+                if let Some(pair) = self.consent_expired(now) {
+                    warn!(
+                        "Consent expired on {} -> {}",
+                        pair.local.address, pair.remote.address
+                    );
+                    if let Some(callback) = self.path_lost.lock().unwrap().take() {
+                        callback(pair);
+                    }
+                    return;
+                }
+                continue;
+            }
+            let outgoing = self.consent_check(now);
+            self.send_all(vec![outgoing]).await;
+            next = now + jittered();
+        }
+    }
+
+    /// The selected pair, if it has just lost consent
+    fn consent_expired(&self, now: Instant) -> Option<CandidatePair> {
+        let mut state = self.state.lock().unwrap();
+        let consent = state.consent.as_mut().expect("started");
+        if now.duration_since(consent.last) < self.config.consent_timeout {
+            return None;
+        }
+        consent.lost = true;
+        consent.pending.clear();
+        Some(state.candidate_pair(state.selected.expect("consent is for it")))
+    }
+
+    fn consent_check(&self, now: Instant) -> Outgoing {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let pair = &state.check_list.pairs[state.selected.expect("consent is for it")];
+        let (id, bytes) = self.binding_request(state, pair.local, false);
+        let (base, to) = (pair.base, state.remote[pair.remote].address);
+        debug!("Consent check to {}", to);
+
+        let consent = state.consent.as_mut().expect("started");
+        let timeout = self.config.consent_timeout;
+        consent
+            .pending
+            .retain(|(_, sent)| now.duration_since(*sent) < timeout);
+        consent.pending.push_back((id, now));
+        Outgoing { base, bytes, to }
+    }
+}
+
+/// Called with the selected pair when consent for it expires
+pub type PathLost = Box<dyn FnOnce(CandidatePair) + Send>;
+
+/// Our candidates as they are gathered, then `None` for end-of-candidates
+pub type LocalCandidates = mpsc::UnboundedReceiver<Option<Candidate>>;
+
+/// ICE agent for one component. Gather, swap credentials and candidates with
+/// the peer, then connect. Candidates can be trickled both ways while
+/// connecting.
+pub struct Agent {
+    inner: Arc<Inner>,
+    data: tokio::sync::Mutex<mpsc::Receiver<(Bytes, SocketAddr)>>,
+}
+
+impl Agent {
+    /// Gather all local candidates. Must be called from within a tokio
+    /// runtime.
+    pub async fn gather(config: AgentConfig, role: Role) -> Result<Self, IceError> {
+        let (agent, mut candidates) = Self::trickle(config, role).await?;
+        while let Some(Some(_)) = candidates.recv().await {}
+        Ok(agent)
+    }
+
+    /// Start gathering, for trickle ICE (RFC 8838). Host candidates are
+    /// ready at once, the others arrive as the servers answer. Must be called
+    /// from within a tokio runtime.
+    pub async fn trickle(
+        config: AgentConfig,
+        role: Role,
+    ) -> Result<(Self, LocalCandidates), IceError> {
+        let hosts = gather::bind_hosts(&config).await?;
+        let servers = gather::query_servers(&config, &hosts.bases, &hosts.candidates);
+        let (tx, rx) = mpsc::channel(FORWARD_QUEUE_LEN);
+        let random_string = |len| {
+            rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(len)
+                .map(char::from)
+                .collect::<String>()
+        };
+        let inner = Arc::new(Inner {
+            config,
+            local_ufrag: random_string(8),
+            local_pwd: random_string(24),
+            tie_breaker: random(),
+            state: Mutex::new(State {
+                role,
+                local: hosts.candidates.clone(),
+                bases: hosts.bases.into_iter().map(Arc::new).collect(),
+                local_complete: false,
+                remote_credentials: None,
+                remote: Vec::new(),
+                remote_complete: false,
+                check_list: CheckList::default(),
+                selected: None,
+                first_success: None,
+                consent: None,
+            }),
+            data: tx,
+            tasks: Mutex::new(Vec::new()),
+            path_lost: Mutex::new(None),
+        });
+        for (base, demuxed) in hosts.demuxed {
+            inner.spawn_demuxed(base, demuxed);
+        }
+
+        let (candidates_tx, candidates_rx) = mpsc::unbounded_channel();
+        for candidate in hosts.candidates {
+            info!("Gathered {:?} {}", candidate.kind, candidate.address);
+            let _ = candidates_tx.send(Some(candidate));
+        }
+        let gathering = {
+            let inner = inner.clone();
+            let mut servers = servers;
+            tokio::spawn(async move {
+                while let Some(res) = servers.join_next().await {
+                    if let Some(candidate) = res.ok().flatten().and_then(|x| inner.add_found(x)) {
+                        let _ = candidates_tx.send(Some(candidate));
+                    }
+                }
+                inner.state.lock().unwrap().local_complete = true;
+                let _ = candidates_tx.send(None);
+            })
+        };
+        inner.tasks.lock().unwrap().push(gathering);
+
+        let agent = Self {
+            inner,
+            data: tokio::sync::Mutex::new(rx),
+        };
+        Ok((agent, candidates_rx))
+    }
+
+    pub fn role(&self) -> Role {
+        self.inner.state.lock().unwrap().role
+    }
+
+    /// Our username fragment and password, for the peer
+    pub fn local_credentials(&self) -> (String, String) {
+        (self.inner.local_ufrag.clone(), self.inner.local_pwd.clone())
+    }
+
+    /// The gathered candidates, for the peer
+    pub fn local_candidates(&self) -> Vec<Candidate> {
+        self.inner
+            .state
+            .lock()
+            .unwrap()
+            .local
+            .iter()
+            .filter(|x| x.kind != CandidateType::PeerReflexive)
+            .cloned()
+            .collect()
+    }
+
+    /// Set the peer's credentials and all of its candidates.
+    pub fn set_remote(&self, ufrag: &str, pwd: &str, candidates: Vec<Candidate>) {
+        self.set_remote_credentials(ufrag, pwd);
+        for candidate in candidates {
+            self.add_remote_candidate(candidate);
+        }
+        self.end_of_remote_candidates();
+    }
+
+    /// Checks start once the peer's credentials are known.
+    pub fn set_remote_credentials(&self, ufrag: &str, pwd: &str) {
+        self.inner.state.lock().unwrap().remote_credentials = Some(RemoteCredentials {
+            ufrag: String::from(ufrag),
+            pwd: String::from(pwd),
+        });
+    }
+
+    /// Pair a candidate from the peer with our bases, even while connecting.
+    /// One we already know as peer-reflexive takes the signalled type,
+    /// priority and foundation (RFC 8838 section 11.4).
+    pub fn add_remote_candidate(&self, candidate: Candidate) {
+        if candidate.component != COMPONENT {
+            return;
+        }
+        let mut guard = self.inner.state.lock().unwrap();
+        let state = &mut *guard;
+        if let Some(i) = state
+            .remote
+            .iter()
+            .position(|x| x.address == candidate.address)
+        {
+            if state.remote[i].kind == CandidateType::PeerReflexive {
+                debug!(
+                    "Peer-reflexive {} is {:?}",
+                    candidate.address, candidate.kind
+                );
+                state.remote[i] = candidate;
+                for j in 0..state.check_list.pairs.len() {
+                    let pair = &state.check_list.pairs[j];
+                    if pair.remote != i {
+                        continue;
+                    }
+                    let foundation = format!(
+                        "{}:{}",
+                        state.local[pair.local].foundation, state.remote[i].foundation
+                    );
+                    let priority = state.pair_priority(pair);
+                    let pair = &mut state.check_list.pairs[j];
+                    pair.foundation = foundation;
+                    pair.priority = priority;
+                }
+            }
+            return;
+        }
+
+        state.remote.push(candidate);
+        let remote = state.remote.len() - 1;
+        for base in 0..state.bases.len() {
+            state.form_pair(base, remote, self.inner.config.max_pairs);
+        }
+        state.check_list.unfreeze_initial();
+        debug!("{} candidate pairs", state.check_list.pairs.len());
+    }
+
+    /// The peer has no more candidates, so running out of pairs means
+    /// failure.
+    pub fn end_of_remote_candidates(&self) {
+        self.inner.state.lock().unwrap().remote_complete = true;
+    }
+
+    /// Run connectivity checks until a pair is selected. Consent checks on
+    /// the pair then keep it alive.
+    pub async fn connect(&self) -> Result<CandidatePair, IceError> {
+        let deadline = Instant::now() + self.inner.config.connect_timeout;
+        let mut tick = interval(self.inner.config.ta);
+        loop {
+            tick.tick().await;
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(IceError::Timeout);
+            }
+            let (outgoing, outcome) = self.inner.tick(now);
+            self.inner.send_all(outgoing).await;
+            if let Some(outcome) = outcome {
+                if outcome.is_ok() {
+                    self.inner.start_consent();
+                }
+                return outcome;
+            }
+        }
+    }
+
+    pub fn selected(&self) -> Option<CandidatePair> {
+        let state = self.inner.state.lock().unwrap();
+        state.selected.map(|i| state.candidate_pair(i))
+    }
+
+    /// Call `callback` once if consent for the selected pair expires, after
+    /// which nothing more can be sent.
+    pub fn on_path_lost(&self, callback: impl FnOnce(CandidatePair) + Send + 'static) {
+        let lost = {
+            let state = self.inner.state.lock().unwrap();
+            match &state.consent {
+                Some(consent) if consent.lost => state.selected.map(|i| state.candidate_pair(i)),
+                _ => None,
+            }
+        };
+        match lost {
+            Some(pair) => callback(pair),
+            None => *self.inner.path_lost.lock().unwrap() = Some(Box::new(callback)),
+        }
+    }
+
+    /// Whether the peer still wants our traffic on the selected pair
+    pub fn has_consent(&self) -> bool {
+        let state = self.inner.state.lock().unwrap();
+        state.consent.as_ref().is_some_and(|consent| !consent.lost)
+    }
+
+    /// Send to the peer on the selected pair.
+    pub async fn send(&self, data: &[u8]) -> Result<(), IceError> {
+        let (base, to) = {
+            let state = self.inner.state.lock().unwrap();
+            if state.consent.as_ref().is_some_and(|consent| consent.lost) {
+                return Err(IceError::ConsentExpired);
+            }
+            let pair = &state.check_list.pairs[state.selected.ok_or(IceError::NotConnected)?];
+            (
+                state.bases[pair.base].clone(),
+                state.remote[pair.remote].address,
+            )
+        };
+        base.send_to(data, to).await
+    }
+
+    /// Receive data from any of the peer's candidates.
+    pub async fn recv(&self) -> Result<(Bytes, SocketAddr), IceError> {
+        self.data.lock().await.recv().await.ok_or(IceError::Closed)
+    }
+}
+
+impl Drop for Agent {
+    fn drop(&mut self) {
+        for task in self.inner.tasks.lock().unwrap().iter() {
+            task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn agent(role: Role) -> Agent {
+        let config = AgentConfig {
+            bind: vec![SocketAddr::from(([127, 0, 0, 1], 0))],
+            ta: Duration::from_millis(5),
+            nomination_delay: Duration::from_millis(20),
+            connect_timeout: Duration::from_secs(5),
+            ..Default::default()
+        };
+        Agent::gather(config, role).await.unwrap()
+    }
+
+    /// Swap credentials and candidates
+    fn introduce(a: &Agent, b: &Agent) {
+        let (ufrag, pwd) = b.local_credentials();
+        a.set_remote(&ufrag, &pwd, b.local_candidates());
+        let (ufrag, pwd) = a.local_credentials();
+        b.set_remote(&ufrag, &pwd, a.local_candidates());
+    }
+
+    fn address(agent: &Agent) -> SocketAddr {
+        agent.local_candidates()[0].address
+    }
+
+    /// Hand what `from` sent to `to` without the network, returning the reply
+    fn deliver(from: &Agent, to: &Agent, outgoing: &Outgoing) -> Option<(Outgoing, Message)> {
+        assert_eq!(outgoing.to, address(to));
+        let message = Message::from_bytes(&mut outgoing.bytes.clone()).unwrap();
+        if message.class == MessageClass::Request {
+            let reply = to
+                .inner
+                .handle_request(0, &message, &outgoing.bytes, address(from));
+            let response = Message::from_bytes(&mut reply.bytes.clone()).unwrap();
+            Some((reply, response))
+        } else {
+            to.inner
+                .handle_response(&message, &outgoing.bytes, address(from));
+            None
+        }
+    }
+
+    /// Deliver requests and their replies, returning whether any nominated
+    fn exchange(from: &Agent, to: &Agent, outgoing: Vec<Outgoing>) -> bool {
+        let mut nominated = false;
+        for x in outgoing {
+            let message = Message::from_bytes(&mut x.bytes.clone()).unwrap();
+            nominated |= message.attribute(AttributeType::UseCandidate).is_some();
+            if let Some((reply, _)) = deliver(from, to, &x) {
+                deliver(to, from, &reply);
+            }
+        }
+        nominated
+    }
+
+    /// Check until both have selected a pair, then start consent
+    async fn connected() -> (Agent, Agent) {
+        let (a, b) = (
+            agent(Role::Controlling).await,
+            agent(Role::Controlled).await,
+        );
+        introduce(&a, &b);
+        let mut now = Instant::now();
+        for _ in 0..100 {
+            for (from, to) in [(&a, &b), (&b, &a)] {
+                exchange(from, to, from.inner.tick(now).0);
+            }
+            now += a.inner.config.ta;
+        }
+        assert!(a.selected().is_some() && b.selected().is_some());
+        a.inner.start_consent();
+        b.inner.start_consent();
+        (a, b)
+    }
+
+    #[tokio::test]
+    async fn pair_priority_by_role() {
+        let (a, b) = (
+            agent(Role::Controlling).await,
+            agent(Role::Controlled).await,
+        );
+        introduce(&a, &b);
+        let (ours, theirs) = (
+            a.local_candidates()[0].priority,
+            b.local_candidates()[0].priority,
+        );
+        let expected = candidate::pair_priority(ours, theirs);
+        assert_eq!(
+            a.inner.state.lock().unwrap().check_list.pairs[0].priority,
+            expected
+        );
+        // The controlling agent's candidate goes first on both sides
+        assert_eq!(
+            b.inner.state.lock().unwrap().check_list.pairs[0].priority,
+            expected
+        );
+
+        let mut state = a.inner.state.lock().unwrap();
+        state.set_role(Role::Controlled);
+        assert_eq!(
+            state.check_list.pairs[0].priority,
+            candidate::pair_priority(theirs, ours)
+        );
+    }
+
+    #[tokio::test]
+    async fn request_triggers_check() {
+        let (a, b) = (
+            agent(Role::Controlling).await,
+            agent(Role::Controlled).await,
+        );
+        introduce(&a, &b);
+        // B has yet to check the pair itself
+        let now = Instant::now();
+        let (outgoing, outcome) = a.inner.tick(now);
+        assert!(outcome.is_none());
+        let (reply, response) = deliver(&a, &b, &outgoing[0]).unwrap();
+        assert_eq!(response.class, MessageClass::ResponseSuccess);
+        assert_eq!(response.mapped_address(), Some(address(&a)));
+        assert_eq!(reply.to, address(&a));
+        {
+            let state = b.inner.state.lock().unwrap();
+            assert_eq!(state.check_list.triggered, [0]);
+            assert_eq!(state.check_list.pairs[0].state, PairState::Waiting);
+        }
+
+        let (outgoing, _) = b.inner.tick(now);
+        assert_eq!(outgoing.len(), 1);
+        assert_eq!(outgoing[0].to, address(&a));
+        let state = b.inner.state.lock().unwrap();
+        assert_eq!(state.check_list.pairs[0].state, PairState::InProgress);
+    }
+
+    #[tokio::test]
+    async fn role_conflict() {
+        let (a, b) = (
+            agent(Role::Controlling).await,
+            agent(Role::Controlling).await,
+        );
+        introduce(&a, &b);
+        let (winner, loser) = if a.inner.tie_breaker >= b.inner.tie_breaker {
+            (a, b)
+        } else {
+            (b, a)
+        };
+        let now = Instant::now();
+
+        // The loser checks and is told to switch
+        let (outgoing, _) = loser.inner.tick(now);
+        let (reply, response) = deliver(&loser, &winner, &outgoing[0]).unwrap();
+        assert_eq!(response.class, MessageClass::ResponseFailure);
+        assert!(matches!(
+            response.attribute(AttributeType::ErrorCode),
+            Some(Attribute::ErrorCode(487, _))
+        ));
+        assert_eq!(winner.role(), Role::Controlling);
+        assert!(deliver(&winner, &loser, &reply).is_none());
+        assert_eq!(loser.role(), Role::Controlled);
+        {
+            let state = loser.inner.state.lock().unwrap();
+            assert_eq!(state.check_list.pairs[0].state, PairState::Waiting);
+            assert_eq!(state.check_list.triggered, [0]);
+        }
+
+        // Its retried check no longer conflicts
+        let (outgoing, _) = loser.inner.tick(now);
+        let (_, response) = deliver(&loser, &winner, &outgoing[0]).unwrap();
+        assert_eq!(response.class, MessageClass::ResponseSuccess);
+        assert_eq!(winner.role(), Role::Controlling);
+    }
+
+    #[tokio::test]
+    async fn nominate_with_use_candidate() {
+        let (a, b) = (
+            agent(Role::Controlling).await,
+            agent(Role::Controlled).await,
+        );
+        introduce(&a, &b);
+        let mut now = Instant::now();
+        let mut nominated = false;
+        for _ in 0..100 {
+            if a.selected().is_some() && b.selected().is_some() {
+                break;
+            }
+            for (from, to) in [(&a, &b), (&b, &a)] {
+                nominated |= exchange(from, to, from.inner.tick(now).0);
+            }
+            // Only the controlled agent selects on USE-CANDIDATE
+            assert!(nominated || b.selected().is_none());
+            now += a.inner.config.ta;
+        }
+        assert_eq!(a.selected().unwrap().remote.address, address(&b));
+        assert_eq!(b.selected().unwrap().remote.address, address(&a));
+    }
+
+    #[tokio::test]
+    async fn connect_over_loopback() {
+        let (a, b) = (
+            agent(Role::Controlling).await,
+            agent(Role::Controlled).await,
+        );
+        introduce(&a, &b);
+        let (pair_a, pair_b) = tokio::join!(a.connect(), b.connect());
+        let (pair_a, pair_b) = (pair_a.unwrap(), pair_b.unwrap());
+        assert_eq!(pair_a.local.address, pair_b.remote.address);
+        assert_eq!(pair_a.remote.address, pair_b.local.address);
+        assert!(a.has_consent() && b.has_consent());
+
+        a.send(b"hello").await.unwrap();
+        let (data, from) = b.recv().await.unwrap();
+        assert_eq!(&data[..], b"hello");
+        assert_eq!(from, pair_a.local.address);
+    }
+
+    #[tokio::test]
+    async fn consent_refreshed_by_responses() {
+        let (a, b) = connected().await;
+        let timeout = a.inner.config.consent_timeout;
+        let start = Instant::now();
+        let mut now = start;
+        // Well past the first timeout, with every check answered
+        while now < start + 3 * timeout {
+            now += a.inner.config.consent_interval;
+            let outgoing = a.inner.consent_check(now);
+            assert!(!exchange(&a, &b, vec![outgoing]));
+            assert!(a.inner.consent_expired(now + timeout / 2).is_none());
+        }
+        assert!(a.has_consent());
+        assert!(a.inner.consent_expired(now + timeout).is_some());
+    }
+
+    #[tokio::test]
+    async fn consent_expires_without_responses() {
+        let (a, b) = connected().await;
+        let timeout = a.inner.config.consent_timeout;
+        let start = Instant::now();
+        let mut unanswered = Vec::new();
+        let mut now = start;
+        while now + a.inner.config.consent_interval < start + timeout {
+            now += a.inner.config.consent_interval;
+            unanswered.push(a.inner.consent_check(now));
+            assert!(a.inner.consent_expired(now).is_none());
+        }
+
+        let pair = a.inner.consent_expired(start + timeout).unwrap();
+        assert_eq!(pair.remote.address, address(&b));
+        assert!(!a.has_consent());
+        assert!(matches!(
+            a.send(b"late").await,
+            Err(IceError::ConsentExpired)
+        ));
+        let lost = Arc::new(Mutex::new(None));
+        a.on_path_lost({
+            let lost = lost.clone();
+            move |pair| *lost.lock().unwrap() = Some(pair)
+        });
+        assert!(lost.lock().unwrap().is_some());
+
+        // Answers that come too late do not bring it back
+        exchange(&a, &b, unanswered);
+        assert!(!a.has_consent());
+    }
+}
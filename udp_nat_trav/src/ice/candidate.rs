@@ -0,0 +1,343 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    fmt::{self, Display, Formatter},
+    hash::{Hash, Hasher},
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use crate::error::CandidateError;
+
+/// We only have one component, as with RTP/RTCP multiplexing
+pub const COMPONENT: u16 = 1;
+
+/// Sent after the last candidate when trickling (RFC 8840 section 8.2)
+pub const END_OF_CANDIDATES: &str = "a=end-of-candidates";
+
+#[derive(PartialEq, Eq, Clone, Copy, Hash, Debug)]
+pub enum CandidateType {
+    Host,
+    ServerReflexive,
+    PeerReflexive,
+    Relayed,
+}
+
+impl CandidateType {
+    /// Recommended type preferences (RFC 8445 section 5.1.2.2)
+    pub fn preference(&self) -> u32 {
+        match self {
+            Self::Host => 126,
+            Self::PeerReflexive => 110,
+            Self::ServerReflexive => 100,
+            Self::Relayed => 0,
+        }
+    }
+
+    /// The `typ` in SDP
+    pub fn sdp_name(&self) -> &'static str {
+        match self {
+            Self::Host => "host",
+            Self::ServerReflexive => "srflx",
+            Self::PeerReflexive => "prflx",
+            Self::Relayed => "relay",
+        }
+    }
+
+    pub fn from_sdp_name(name: &str) -> Option<Self> {
+        match name {
+            "host" => Some(Self::Host),
+            "srflx" => Some(Self::ServerReflexive),
+            "prflx" => Some(Self::PeerReflexive),
+            "relay" => Some(Self::Relayed),
+            _ => None,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Candidate {
+    /// Candidates with the same type, base IP and server share a foundation
+    pub foundation: String,
+    pub component: u16,
+    pub priority: u32,
+    pub address: SocketAddr,
+    pub kind: CandidateType,
+    /// The base of a reflexive candidate, or the mapped address of a relayed
+    /// one
+    pub related: Option<SocketAddr>,
+}
+
+impl Candidate {
+    /// A local candidate. `server` is the STUN or TURN server it came from.
+    pub fn new(
+        kind: CandidateType,
+        address: SocketAddr,
+        base_ip: IpAddr,
+        server: Option<SocketAddr>,
+        related: Option<SocketAddr>,
+        local_preference: u16,
+    ) -> Self {
+        Self {
+            foundation: foundation(kind, base_ip, server),
+            component: COMPONENT,
+            priority: priority(kind, local_preference, COMPONENT),
+            address,
+            kind,
+            related,
+        }
+    }
+
+    /// The local preference part of the priority
+    pub fn local_preference(&self) -> u16 {
+        (self.priority >> 8) as u16
+    }
+
+    /// The SDP attribute line, `a=candidate:...`
+    pub fn to_sdp(&self) -> String {
+        format!("a={}", self)
+    }
+}
+
+/// The `candidate:...` attribute value (RFC 8839 section 5.1), as WebRTC
+/// stacks take it
+impl Display for Candidate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "candidate:{} {} udp {} {} {} typ {}",
+            self.foundation,
+            self.component,
+            self.priority,
+            self.address.ip(),
+            self.address.port(),
+            self.kind.sdp_name()
+        )?;
+        if let Some(related) = self.related {
+            write!(f, " raddr {} rport {}", related.ip(), related.port())?;
+        }
+        Ok(())
+    }
+}
+
+/// Takes the attribute with or without `a=`. Extensions such as
+/// `generation` are ignored.
+impl FromStr for Candidate {
+    type Err = CandidateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let s = s.strip_prefix("a=").unwrap_or(s);
+        let s = s
+            .strip_prefix("candidate:")
+            .ok_or(CandidateError::NotCandidate)?;
+        let mut fields = s.split_ascii_whitespace();
+        let mut next = |name| fields.next().ok_or(CandidateError::Missing(name));
+
+        let foundation = next("foundation")?;
+        let valid_foundation = foundation.len() <= 32
+            && foundation
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '/');
+        if !valid_foundation {
+            return Err(CandidateError::Invalid("foundation"));
+        }
+        let component = next("component")?
+            .parse()
+            .map_err(|_| CandidateError::Invalid("component"))?;
+        let transport = next("transport")?;
+        if !transport.eq_ignore_ascii_case("udp") {
+            return Err(CandidateError::UnsupportedTransport(String::from(
+                transport,
+            )));
+        }
+        let priority = next("priority")?
+            .parse()
+            .map_err(|_| CandidateError::Invalid("priority"))?;
+        let ip: IpAddr = next("address")?
+            .parse()
+            .map_err(|_| CandidateError::Invalid("address"))?;
+        let port = next("port")?
+            .parse()
+            .map_err(|_| CandidateError::Invalid("port"))?;
+        if next("typ")? != "typ" {
+            return Err(CandidateError::Missing("typ"));
+        }
+        let kind =
+            CandidateType::from_sdp_name(next("type")?).ok_or(CandidateError::Invalid("type"))?;
+
+        let (mut raddr, mut rport) = (None, None);
+        while let (Some(name), Some(value)) = (fields.next(), fields.next()) {
+            match name {
+                "raddr" => {
+                    let ip: IpAddr = value
+                        .parse()
+                        .map_err(|_| CandidateError::Invalid("raddr"))?;
+                    raddr = Some(ip);
+                }
+                "rport" => {
+                    let port: u16 = value
+                        .parse()
+                        .map_err(|_| CandidateError::Invalid("rport"))?;
+                    rport = Some(port);
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            foundation: String::from(foundation),
+            component,
+            priority,
+            address: SocketAddr::new(ip, port),
+            kind,
+            related: raddr.zip(rport).map(|(ip, port)| SocketAddr::new(ip, port)),
+        })
+    }
+}
+
+/// RFC 8445 section 5.1.2.1
+pub fn priority(kind: CandidateType, local_preference: u16, component: u16) -> u32 {
+    (kind.preference() << 24) + ((local_preference as u32) << 8) + (256 - component as u32)
+}
+
+/// The priority of a pair, from the candidate priorities of the controlling
+/// and controlled agents (RFC 8445 section 6.1.2.3)
+pub fn pair_priority(controlling: u32, controlled: u32) -> u64 {
+    let (g, d) = (controlling as u64, controlled as u64);
+    (1 << 32) * g.min(d) + 2 * g.max(d) + u64::from(g > d)
+}
+
+fn foundation(kind: CandidateType, base_ip: IpAddr, server: Option<SocketAddr>) -> String {
+    let mut hasher = DefaultHasher::new();
+    (kind, base_ip, server).hash(&mut hasher);
+    format!("{}", hasher.finish() as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(line: &str) -> Candidate {
+        let candidate: Candidate = line.parse().unwrap();
+        assert_eq!(candidate.to_sdp(), line);
+        assert_eq!(line[2..].parse::<Candidate>().unwrap(), candidate);
+        candidate
+    }
+
+    #[test]
+    fn host() {
+        let candidate = round_trip("a=candidate:1 1 udp 2130706431 192.0.2.1 54400 typ host");
+        assert_eq!(candidate.kind, CandidateType::Host);
+        assert_eq!(candidate.address, "192.0.2.1:54400".parse().unwrap());
+        assert_eq!(candidate.priority, priority(CandidateType::Host, 65535, 1));
+        assert_eq!(candidate.local_preference(), 65535);
+        assert_eq!(candidate.related, None);
+    }
+
+    #[test]
+    fn server_reflexive() {
+        let candidate = round_trip(
+            "a=candidate:2 1 udp 1694498815 198.51.100.7 61000 typ srflx raddr 10.0.0.2 rport 54400",
+        );
+        assert_eq!(candidate.kind, CandidateType::ServerReflexive);
+        assert_eq!(candidate.related, Some("10.0.0.2:54400".parse().unwrap()));
+    }
+
+    #[test]
+    fn relayed() {
+        let candidate = round_trip(
+            "a=candidate:3 1 udp 16777215 203.0.113.9 49152 typ relay raddr 198.51.100.7 rport 61000",
+        );
+        assert_eq!(candidate.kind, CandidateType::Relayed);
+        assert_eq!(
+            candidate.related,
+            Some("198.51.100.7:61000".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn ipv6() {
+        let candidate = round_trip("a=candidate:4 1 udp 2130706431 2001:db8::1 54400 typ host");
+        assert_eq!(candidate.address, "[2001:db8::1]:54400".parse().unwrap());
+
+        let candidate = Candidate::new(
+            CandidateType::ServerReflexive,
+            "[2001:db8::2]:61000".parse().unwrap(),
+            "fe80::1".parse().unwrap(),
+            Some("[2001:db8::3]:3478".parse().unwrap()),
+            Some("[fe80::1]:54400".parse().unwrap()),
+            100,
+        );
+        assert_eq!(
+            candidate.to_string().parse::<Candidate>().unwrap(),
+            candidate
+        );
+    }
+
+    #[test]
+    fn extensions_ignored() {
+        let candidate: Candidate =
+            "candidate:5 1 UDP 2130706431 192.0.2.1 54400 typ host generation 0 ufrag abcd"
+                .parse()
+                .unwrap();
+        assert_eq!(candidate.address, "192.0.2.1:54400".parse().unwrap());
+    }
+
+    #[test]
+    fn end_of_candidates() {
+        assert!(matches!(
+            END_OF_CANDIDATES.parse::<Candidate>(),
+            Err(CandidateError::NotCandidate)
+        ));
+    }
+
+    #[test]
+    fn malformed() {
+        for (line, expected) in [
+            ("", "not a candidate attribute"),
+            ("a=candidate:", "missing foundation"),
+            ("candidate:1 1 udp 100 192.0.2.1", "missing port"),
+            ("candidate:1 1 udp 100 192.0.2.1 54400 host", "missing typ"),
+            ("candidate:1 1 udp 100 192.0.2.1 54400 typ", "missing type"),
+            (
+                "candidate:a-b 1 udp 100 192.0.2.1 54400 typ host",
+                "invalid foundation",
+            ),
+            (
+                "candidate:1 x udp 100 192.0.2.1 54400 typ host",
+                "invalid component",
+            ),
+            (
+                "candidate:1 1 tcp 100 192.0.2.1 54400 typ host",
+                "unsupported transport tcp",
+            ),
+            (
+                "candidate:1 1 udp -1 192.0.2.1 54400 typ host",
+                "invalid priority",
+            ),
+            (
+                "candidate:1 1 udp 100 192.0.2 54400 typ host",
+                "invalid address",
+            ),
+            (
+                "candidate:1 1 udp 100 192.0.2.1 65536 typ host",
+                "invalid port",
+            ),
+            (
+                "candidate:1 1 udp 100 192.0.2.1 54400 typ nat",
+                "invalid type",
+            ),
+            (
+                "candidate:1 1 udp 100 192.0.2.1 54400 typ srflx raddr x rport 1",
+                "invalid raddr",
+            ),
+            (
+                "candidate:1 1 udp 100 192.0.2.1 54400 typ srflx raddr 10.0.0.1 rport x",
+                "invalid rport",
+            ),
+        ] {
+            let err = line.parse::<Candidate>().unwrap_err();
+            assert_eq!(err.to_string(), expected, "{}", line);
+        }
+    }
+}
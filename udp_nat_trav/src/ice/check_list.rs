@@ -0,0 +1,215 @@
+use std::{collections::VecDeque, time::Instant};
+
+use bytes::Bytes;
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub(crate) enum PairState {
+    Frozen,
+    Waiting,
+    InProgress,
+    Succeeded,
+    Failed,
+}
+
+/// A connectivity check in flight
+pub(crate) struct Check {
+    pub id: [u8; 12],
+    pub request: Bytes,
+    pub sends: u32,
+    /// When to retransmit, or give up
+    pub next: Instant,
+    pub use_candidate: bool,
+    /// Whether we were controlling when it was sent
+    pub controlling: bool,
+}
+
+pub(crate) struct Pair {
+    /// Index into the local candidates, the one pairs are formed with on
+    /// this base
+    pub local: usize,
+    /// Index into the remote candidates
+    pub remote: usize,
+    pub base: usize,
+    pub foundation: String,
+    pub priority: u64,
+    pub state: PairState,
+    /// Controlled: USE-CANDIDATE arrived before our own check succeeded
+    pub nominate_on_success: bool,
+    /// Controlling: the next check nominates the pair
+    pub use_candidate: bool,
+    pub check: Option<Check>,
+}
+
+impl Pair {
+    pub fn new(local: usize, remote: usize, base: usize, foundation: String) -> Self {
+        Self {
+            local,
+            remote,
+            base,
+            foundation,
+            priority: 0,
+            state: PairState::Frozen,
+            nominate_on_success: false,
+            use_candidate: false,
+            check: None,
+        }
+    }
+}
+
+/// Pairs are never removed, so their indices can be held on to.
+#[derive(Default)]
+pub(crate) struct CheckList {
+    pub pairs: Vec<Pair>,
+    /// Pairs to check before any others (RFC 8445 section 7.3.1.4)
+    pub triggered: VecDeque<usize>,
+}
+
+impl CheckList {
+    /// Each pair has its own base and remote candidate
+    pub fn find(&self, base: usize, remote: usize) -> Option<usize> {
+        self.pairs
+            .iter()
+            .position(|pair| pair.base == base && pair.remote == remote)
+    }
+
+    pub fn add(&mut self, pair: Pair) -> usize {
+        self.pairs.push(pair);
+        self.pairs.len() - 1
+    }
+
+    /// Make the highest priority Frozen pair of each foundation with
+    /// nothing else going on Waiting (RFC 8445 section 6.1.2.6).
+    pub fn unfreeze_initial(&mut self) {
+        let mut best: Vec<(String, usize)> = Vec::new();
+        for (i, pair) in self.pairs.iter().enumerate() {
+            let active = self.pairs.iter().any(|other| {
+                other.foundation == pair.foundation && other.state != PairState::Frozen
+            });
+            if pair.state != PairState::Frozen || active {
+                continue;
+            }
+            match best
+                .iter_mut()
+                .find(|(foundation, _)| *foundation == pair.foundation)
+            {
+                Some((_, j)) if self.pairs[*j].priority < pair.priority => *j = i,
+                Some(_) => {}
+                None => best.push((pair.foundation.clone(), i)),
+            }
+        }
+        for (_, i) in best {
+            self.pairs[i].state = PairState::Waiting;
+        }
+    }
+
+    /// A pair succeeded, so others like it probably will too
+    pub fn unfreeze_foundation(&mut self, foundation: &str) {
+        for pair in &mut self.pairs {
+            if pair.state == PairState::Frozen && pair.foundation == foundation {
+                pair.state = PairState::Waiting;
+            }
+        }
+    }
+
+    pub fn trigger(&mut self, i: usize) {
+        if !self.triggered.contains(&i) {
+            self.triggered.push_back(i);
+        }
+    }
+
+    /// The pair for the next check: a triggered one, else the highest
+    /// priority Waiting one, else the highest priority Frozen one.
+    pub fn next(&mut self) -> Option<usize> {
+        while let Some(i) = self.triggered.pop_front() {
+            if self.pairs[i].state != PairState::InProgress {
+                return Some(i);
+            }
+        }
+        self.highest(PairState::Waiting)
+            .or_else(|| self.highest(PairState::Frozen))
+    }
+
+    pub fn highest(&self, state: PairState) -> Option<usize> {
+        self.pairs
+            .iter()
+            .enumerate()
+            .filter(|(_, pair)| pair.state == state)
+            .max_by_key(|(_, pair)| pair.priority)
+            .map(|(i, _)| i)
+    }
+
+    /// Nothing left to check
+    pub fn is_done(&self) -> bool {
+        self.triggered.is_empty()
+            && self
+                .pairs
+                .iter()
+                .all(|pair| matches!(pair.state, PairState::Succeeded | PairState::Failed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_list(pairs: &[(&str, u64)]) -> CheckList {
+        let mut check_list = CheckList::default();
+        for (i, (foundation, priority)) in pairs.iter().enumerate() {
+            let mut pair = Pair::new(0, i, 0, String::from(*foundation));
+            pair.priority = *priority;
+            check_list.add(pair);
+        }
+        check_list
+    }
+
+    #[test]
+    fn unfreeze_best_of_each_foundation() {
+        let mut check_list = check_list(&[("a", 1), ("a", 3), ("b", 2), ("c", 5)]);
+        check_list.pairs[3].state = PairState::InProgress;
+        check_list.unfreeze_initial();
+        let states: Vec<_> = check_list.pairs.iter().map(|pair| pair.state).collect();
+        assert_eq!(
+            states,
+            [
+                PairState::Frozen,
+                PairState::Waiting,
+                PairState::Waiting,
+                PairState::InProgress
+            ]
+        );
+
+        check_list.unfreeze_foundation("a");
+        assert_eq!(check_list.pairs[0].state, PairState::Waiting);
+    }
+
+    #[test]
+    fn next_in_order() {
+        let mut check_list = check_list(&[("a", 1), ("b", 4), ("c", 3), ("d", 2)]);
+        check_list.pairs[2].state = PairState::Waiting;
+        check_list.pairs[3].state = PairState::Waiting;
+
+        // Triggered first, once each, unless already in progress
+        check_list.trigger(0);
+        check_list.trigger(0);
+        check_list.trigger(1);
+        check_list.pairs[1].state = PairState::InProgress;
+        assert_eq!(check_list.next(), Some(0));
+        check_list.pairs[0].state = PairState::InProgress;
+
+        // Then Waiting, then Frozen, by priority
+        assert_eq!(check_list.next(), Some(2));
+        check_list.pairs[2].state = PairState::InProgress;
+        assert_eq!(check_list.next(), Some(3));
+        check_list.pairs[3].state = PairState::Succeeded;
+        check_list.pairs[1].state = PairState::Frozen;
+        assert_eq!(check_list.next(), Some(1));
+        check_list.pairs[1].state = PairState::Failed;
+        assert_eq!(check_list.next(), None);
+
+        assert!(!check_list.is_done());
+        check_list.pairs[0].state = PairState::Failed;
+        check_list.pairs[2].state = PairState::Succeeded;
+        assert!(check_list.is_done());
+        assert_eq!(check_list.highest(PairState::Succeeded), Some(2));
+    }
+}
@@ -0,0 +1,219 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use rand::random;
+use stun_test::{
+    message::{Message, MessageClass, MessageMethod},
+    transaction::{AsyncClient, Demuxed},
+    turn::TurnClient,
+};
+use tokio::{net::UdpSocket, task::JoinSet, time::timeout};
+use tracing::warn;
+
+use super::{
+    agent::AgentConfig,
+    candidate::{Candidate, CandidateType},
+};
+use crate::error::IceError;
+
+/// Where checks and data for a local candidate are sent from
+pub(crate) enum Transport {
+    Socket(Arc<AsyncClient>),
+    Relay(Arc<TurnClient>),
+}
+
+pub(crate) struct Base {
+    pub transport: Transport,
+    /// The host or relayed candidate which remote candidates are paired with
+    pub candidate: usize,
+}
+
+impl Base {
+    pub async fn send_to(&self, bytes: &[u8], addr: SocketAddr) -> Result<(), IceError> {
+        match &self.transport {
+            Transport::Socket(client) => {
+                client.socket().send_to(bytes, addr).await?;
+            }
+            Transport::Relay(turn) => turn.send_to(bytes, addr).await?,
+        }
+        Ok(())
+    }
+}
+
+pub(crate) struct Hosts {
+    pub bases: Vec<Base>,
+    pub candidates: Vec<Candidate>,
+    /// What arrives on the socket of each base, STUN apart from data
+    pub demuxed: Vec<(usize, Demuxed)>,
+}
+
+/// A candidate from a server
+pub(crate) enum Found {
+    Reflexive(Candidate),
+    Relayed(Candidate, TurnClient),
+}
+
+/// A host candidate for each bind address, on its own socket
+pub(crate) async fn bind_hosts(config: &AgentConfig) -> Result<Hosts, IceError> {
+    let mut hosts = Hosts {
+        bases: Vec::new(),
+        candidates: Vec::new(),
+        demuxed: Vec::new(),
+    };
+    for (i, bind) in config.bind.iter().enumerate() {
+        let socket = UdpSocket::bind(bind).await?;
+        let local = socket.local_addr()?;
+        let ip = if local.ip().is_unspecified() {
+            default_ip(local.is_ipv6())
+        } else {
+            local.ip()
+        };
+        let addr = SocketAddr::new(ip, local.port());
+        let local_preference = u16::MAX - i as u16;
+        if hosts.candidates.iter().any(|x| x.address == addr) {
+            continue;
+        }
+
+        let (client, demuxed) = AsyncClient::with_demux(socket, config.transaction);
+        let base = hosts.bases.len();
+        hosts.bases.push(Base {
+            transport: Transport::Socket(Arc::new(client)),
+            candidate: hosts.candidates.len(),
+        });
+        hosts.candidates.push(Candidate::new(
+            CandidateType::Host,
+            addr,
+            ip,
+            None,
+            None,
+            local_preference,
+        ));
+        hosts.demuxed.push((base, demuxed));
+    }
+
+    if hosts.candidates.is_empty() {
+        return Err(IceError::NoCandidates);
+    }
+    Ok(hosts)
+}
+
+/// Ask each STUN server for a server-reflexive candidate and each TURN server
+/// for a relayed one, from each host. Servers which fail give nothing.
+pub(crate) fn query_servers(
+    config: &AgentConfig,
+    bases: &[Base],
+    candidates: &[Candidate],
+) -> JoinSet<Option<Found>> {
+    let mut join_set = JoinSet::new();
+    for base in bases {
+        let Transport::Socket(client) = &base.transport else {
+            continue;
+        };
+        let host = &candidates[base.candidate];
+        let (addr, local_preference) = (host.address, host.local_preference());
+
+        for server in &config.stun_servers {
+            let (client, server) = (client.clone(), *server);
+            let limit = config.gather_timeout;
+            join_set.spawn(async move {
+                let request = Message {
+                    method: MessageMethod::Binding,
+                    class: MessageClass::Request,
+                    id: random(),
+                    attributes: Vec::new(),
+                };
+                let mapped = match timeout(limit, client.transaction(request.bytes(), server)).await
+                {
+                    Ok(Ok(response)) => response.message.mapped_address(),
+                    Ok(Err(e)) => {
+                        warn!("STUN server {} failed: {}", server, e);
+                        None
+                    }
+                    Err(_) => {
+                        warn!("STUN server {} timed out", server);
+                        None
+                    }
+                };
+                let candidate = Candidate::new(
+                    CandidateType::ServerReflexive,
+                    mapped?,
+                    addr.ip(),
+                    Some(server),
+                    Some(addr),
+                    local_preference,
+                );
+                Some(Found::Reflexive(candidate))
+            });
+        }
+        for server in &config.turn_servers {
+            let server = server.clone();
+            let limit = config.gather_timeout;
+            let transaction = config.transaction;
+            join_set.spawn(async move {
+                let allocate = async {
+                    let socket = UdpSocket::bind(SocketAddr::new(addr.ip(), 0)).await?;
+                    let turn = TurnClient::allocate(
+                        socket,
+                        server.addr,
+                        &server.username,
+                        &server.password,
+                        transaction,
+                    )
+                    .await?;
+                    Ok::<_, IceError>(turn)
+                };
+                match timeout(limit, allocate).await {
+                    Ok(Ok(turn)) => {
+                        let candidate = Candidate::new(
+                            CandidateType::Relayed,
+                            turn.relayed_addr(),
+                            addr.ip(),
+                            Some(server.addr),
+                            turn.mapped_addr(),
+                            local_preference,
+                        );
+                        Some(Found::Relayed(candidate, turn))
+                    }
+                    Ok(Err(e)) => {
+                        warn!("TURN server {} failed: {}", server.addr, e);
+                        None
+                    }
+                    Err(_) => {
+                        warn!("TURN server {} timed out", server.addr);
+                        None
+                    }
+                }
+            });
+        }
+    }
+    join_set
+}
+
+/// The IP of the interface with the default route, or loopback if there is
+/// none. Connecting a UDP socket sends nothing.
+fn default_ip(ipv6: bool) -> IpAddr {
+    let (bind, remote) = if ipv6 {
+        ("[::]:0", "[2001:db8::1]:9")
+    } else {
+        ("0.0.0.0:0", "192.0.2.1:9")
+    };
+    let ip = std::net::UdpSocket::bind(bind)
+        .and_then(|socket| {
+            socket.connect(remote)?;
+            socket.local_addr()
+        })
+        .map(|addr| addr.ip());
+    match ip {
+        Ok(ip) => ip,
+        Err(e) => {
+            warn!("No default route ({}), using loopback", e);
+            if ipv6 {
+                IpAddr::V6(Ipv6Addr::LOCALHOST)
+            } else {
+                IpAddr::V4(Ipv4Addr::LOCALHOST)
+            }
+        }
+    }
+}
@@ -0,0 +1,24 @@
+//! ICE agent (RFC 8445)
+//!
+//! Gathers host, server-reflexive and relayed candidates, then runs paced
+//! connectivity checks against the peer's candidates and nominates a pair.
+//! Credentials and candidates are swapped out of band, e.g. through the
+//! rendezvous server, which then no longer needs to predict anything.
+//! Candidates can be trickled (RFC 8838) in their SDP form.
+//!
+//! Only one data stream with one component. A successful check makes the
+//! checked pair valid, rather than the pair built from the mapped address,
+//! which is the same thing unless the NAT rewrites the pair's base.
+//!
+//! Once connected, consent checks (RFC 7675) keep the NAT mapping alive and
+//! notice when the peer goes away.
+
+mod agent;
+mod candidate;
+mod check_list;
+mod gather;
+
+pub use agent::{Agent, AgentConfig, CandidatePair, LocalCandidates, PathLost, Role, TurnServer};
+pub use candidate::{
+    pair_priority, priority, Candidate, CandidateType, COMPONENT, END_OF_CANDIDATES,
+};
@@ -1 +1,3 @@
-
+pub mod error;
+pub mod ice;
+pub mod rendezvous;
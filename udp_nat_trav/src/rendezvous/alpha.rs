@@ -0,0 +1,198 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use tokio::{net::UdpSocket, task::JoinSet, time::sleep};
+use tracing::{trace, warn};
+
+use super::{Frame, Message, CARETAKER_INTERVAL, MAX_SIZE};
+
+pub struct AlphaData {
+    rx_events: Vec<(SocketAddr, Instant)>,
+    /// Number of alpha ports, so of datagrams a complete test sends
+    expected: usize,
+    threshold_percent: usize,
+}
+
+impl AlphaData {
+    fn new(expected: usize, threshold_percent: usize) -> Self {
+        Self {
+            rx_events: Vec::new(),
+            expected,
+            threshold_percent,
+        }
+    }
+
+    fn record_rx_event(&mut self, addr: SocketAddr) {
+        self.rx_events.push((addr, Instant::now()));
+    }
+
+    pub fn most_recent(&self) -> Option<Instant> {
+        self.rx_events
+            .iter()
+            .map(|(_, instant)| instant)
+            .max()
+            .copied()
+    }
+
+    /// Where the most recent datagram came from
+    pub fn most_recent_addr(&self) -> Option<SocketAddr> {
+        self.rx_events
+            .iter()
+            .max_by_key(|(_, instant)| instant)
+            .map(|(addr, _)| *addr)
+    }
+
+    pub fn test_count(&self) -> usize {
+        self.rx_events.len()
+    }
+
+    pub fn test_complete(&self) -> bool {
+        let threshold = self.expected * self.threshold_percent / 100;
+        self.rx_events.len() >= threshold
+    }
+
+    pub fn rx_events(&self) -> impl Iterator<Item = &SocketAddr> {
+        self.rx_events.iter().map(|(addr, _)| addr)
+    }
+
+    pub fn conclusion(&self) -> Option<AlphaResult> {
+        self.analysis().max_by_key(|x| x.1).map(|(res, _)| res)
+    }
+
+    pub fn analysis(&self) -> impl Iterator<Item = (AlphaResult, usize)> {
+        // (alpha_result, confidence 0..100)
+        let mut results: Vec<(AlphaResult, usize)> = Vec::new(); //
+        results.push((AlphaResult::Unknown, self.threshold_percent));
+
+        if self.test_complete() {
+            // Prep work
+            let test_count = self.test_count();
+            let mut map: HashMap<SocketAddr, usize> = HashMap::new();
+            for (addr, _) in &self.rx_events {
+                map.entry(*addr)
+                    .and_modify(|count| *count += 1)
+                    .or_insert(1);
+            }
+
+            // Was the NAT source IP and port mostly the same
+            for (addr, count) in &map {
+                results.push((
+                    AlphaResult::SrcIpPortConstant(addr.ip(), addr.port()),
+                    usize::checked_div(count * 100, test_count).unwrap_or(0),
+                ))
+            }
+
+            // Was the NAT source IP and port mostly different
+            let count = map.iter().filter(|(_, &count)| count == 1).count();
+
+            results.push((
+                AlphaResult::SrcIpPortInconstant,
+                usize::checked_div(count * 100, test_count).unwrap_or(0),
+            ));
+        }
+
+        results.into_iter()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AlphaResult {
+    Unknown,
+    SrcIpPortInconstant,
+    SrcIpPortConstant(IpAddr, u16),
+}
+
+pub(crate) struct AlphaManager {
+    data: Arc<DashMap<String, AlphaData>>,
+    threshold_percent: usize,
+    expiry: Duration,
+}
+
+impl AlphaManager {
+    pub fn new(threshold_percent: usize, expiry: Duration) -> Self {
+        Self {
+            data: Arc::new(DashMap::new()),
+            threshold_percent,
+            expiry,
+        }
+    }
+
+    pub fn data(&self) -> Arc<DashMap<String, AlphaData>> {
+        self.data.clone()
+    }
+
+    pub fn spawn_tasks(&self, sockets: Vec<UdpSocket>, join_set: &mut JoinSet<io::Result<()>>) {
+        let expected = sockets.len();
+        for socket in sockets {
+            let data_clone = self.data.clone();
+            join_set.spawn(Self::port_listen_task(
+                socket,
+                data_clone,
+                expected,
+                self.threshold_percent,
+            ));
+        }
+
+        let data_clone = self.data.clone();
+        join_set.spawn(Self::caretaker_task(data_clone, self.expiry));
+    }
+
+    async fn caretaker_task(
+        data: Arc<DashMap<String, AlphaData>>,
+        expiry: Duration,
+    ) -> io::Result<()> {
+        loop {
+            sleep(CARETAKER_INTERVAL).await;
+
+            // Delete expired data
+            data.retain(|_, peer_data| match peer_data.most_recent() {
+                Some(instant) => instant.elapsed() < expiry,
+                None => true,
+            })
+        }
+    }
+
+    async fn port_listen_task(
+        socket: UdpSocket,
+        data: Arc<DashMap<String, AlphaData>>,
+        expected: usize,
+        threshold_percent: usize,
+    ) -> io::Result<()> {
+        let port = socket.local_addr()?.port();
+        // One more byte than a frame may have, to tell oversized ones apart
+        let mut buf = [0; MAX_SIZE + 1];
+
+        loop {
+            let (len, addr) = socket.recv_from(&mut buf).await?;
+            let id = match Frame::decode(&buf[..len]) {
+                Ok(Frame {
+                    message: Message::Alpha { id },
+                    ..
+                }) => id,
+                Ok(frame) => {
+                    warn!(
+                        "Rx on {}: unexpected {:?} from {}",
+                        port, frame.message, addr
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Rx on {}: {} from {}", port, e, addr);
+                    continue;
+                }
+            };
+            trace!("Rx on {}: {}", port, id);
+
+            // Record event
+            data.entry(id)
+                .or_insert_with(|| AlphaData::new(expected, threshold_percent))
+                .record_rx_event(addr);
+        }
+    }
+}
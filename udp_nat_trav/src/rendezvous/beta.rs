@@ -1,36 +1,33 @@
 use std::{
     collections::HashMap,
-    net::{Ipv4Addr, SocketAddr},
-    str,
+    io,
+    net::SocketAddr,
     sync::Arc,
     time::{Duration, Instant},
 };
 
 use dashmap::DashMap;
 use tokio::{net::UdpSocket, task::JoinSet, time::sleep};
-use tracing::{info, trace};
+use tracing::{trace, warn};
 
-const PORT: u16 = 4010;
-const EXPECTED_TEST_COUNT: usize = 10;
-const THRESHOLD_PERCENT: usize = 80;
+use super::{Frame, Message, CARETAKER_INTERVAL, MAX_SIZE};
 
-pub struct PeerData {
+pub struct BetaData {
     rx_events: Vec<(SocketAddr, u16, u16, Instant)>,
+    /// Number of datagrams a complete test sends
+    expected: usize,
+    threshold_percent: usize,
 }
 
-impl PeerData {
-    fn new() -> Self {
+impl BetaData {
+    fn new(expected: usize, threshold_percent: usize) -> Self {
         Self {
             rx_events: Vec::new(),
+            expected,
+            threshold_percent,
         }
     }
 
-    fn with_event(addr: SocketAddr, orig_port: u16, seq_num: u16) -> Self {
-        let mut s = Self::new();
-        s.record_rx_event(addr, orig_port, seq_num);
-        s
-    }
-
     fn record_rx_event(&mut self, addr: SocketAddr, orig_port: u16, seq_num: u16) {
         self.rx_events
             .push((addr, orig_port, seq_num, Instant::now()));
@@ -44,6 +41,14 @@ impl PeerData {
             .copied()
     }
 
+    /// Where the most recent datagram came from
+    pub fn most_recent_addr(&self) -> Option<SocketAddr> {
+        self.rx_events
+            .iter()
+            .max_by_key(|(_, _, _, instant)| instant)
+            .map(|(addr, _, _, _)| *addr)
+    }
+
     pub fn test_count(&self) -> usize {
         self.rx_events.len()
     }
@@ -51,7 +56,7 @@ impl PeerData {
     pub fn test_complete(&self) -> bool {
         // TODO: allow for missed UDP packets, but the way below is not the way
         // we should go, I think
-        let threshold = EXPECTED_TEST_COUNT * THRESHOLD_PERCENT / 100;
+        let threshold = self.expected * self.threshold_percent / 100;
         self.rx_events.len() >= threshold
     }
 
@@ -62,15 +67,13 @@ impl PeerData {
     }
 
     pub fn conclusion(&self) -> Option<BetaResult> {
-        self.analysis()
-            .max_by_key(|x| x.1)
-            .and_then(|(res, _)| Some(res))
+        self.analysis().max_by_key(|x| x.1).map(|(res, _)| res)
     }
 
     pub fn analysis(&self) -> impl Iterator<Item = (BetaResult, usize)> {
         // (alpha_result, confidence 0..100)
         let mut results: HashMap<BetaResult, usize> = HashMap::new();
-        results.insert(BetaResult::Unknown, THRESHOLD_PERCENT);
+        results.insert(BetaResult::Unknown, self.threshold_percent);
 
         if self.test_complete() {
             // Prep work
@@ -116,7 +119,7 @@ impl PeerData {
                 .map(|(addr, _, seq_num, _)| (addr, seq_num))
                 .collect();
             tmp.sort_by_key(|x| x.1);
-            let mut diffs = Vec::with_capacity(tmp.len() - 1);
+            let mut diffs = Vec::with_capacity(tmp.len().saturating_sub(1));
             let mut iter = tmp.iter().map(|(&addr, _)| addr.port());
             if let Some(mut prev) = iter.next() {
                 for next in iter {
@@ -149,7 +152,7 @@ impl PeerData {
 // TODO: Could also have 'narrow range, but expect to be not less than previously
 // used (with wrapping)'? - NarrowRangeDataPointIsStart, NarrowRangeDataPointIsCenter.
 
-#[derive(Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum BetaResult {
     Unknown,
     SrcPortAsOrig,
@@ -162,73 +165,95 @@ pub enum BetaResult {
                         // SrcPortRandom, // TODO: Is this unknown?
 }
 
-pub struct BetaManager {
-    data: Arc<DashMap<String, PeerData>>,
+pub(crate) struct BetaManager {
+    data: Arc<DashMap<String, BetaData>>,
+    expected: usize,
+    threshold_percent: usize,
+    expiry: Duration,
 }
 
 impl BetaManager {
-    pub fn new() -> Self {
+    pub fn new(expected: usize, threshold_percent: usize, expiry: Duration) -> Self {
         Self {
             data: Arc::new(DashMap::new()),
+            expected,
+            threshold_percent,
+            expiry,
         }
     }
 
-    pub fn data(&self) -> Arc<DashMap<String, PeerData>> {
+    pub fn data(&self) -> Arc<DashMap<String, BetaData>> {
         self.data.clone()
     }
 
-    pub fn spawn_tasks(&self, join_set: &mut JoinSet<Result<(), anyhow::Error>>) {
+    pub fn spawn_tasks(&self, socket: UdpSocket, join_set: &mut JoinSet<io::Result<()>>) {
         let data_clone = self.data.clone();
-        join_set.spawn(Self::port_listen_task(PORT, data_clone));
+        join_set.spawn(Self::port_listen_task(
+            socket,
+            data_clone,
+            self.expected,
+            self.threshold_percent,
+        ));
 
         let data_clone = self.data.clone();
-        join_set.spawn(Self::caretaker_task(data_clone));
+        join_set.spawn(Self::caretaker_task(data_clone, self.expiry));
     }
 
-    async fn caretaker_task(data: Arc<DashMap<String, PeerData>>) -> anyhow::Result<()> {
+    async fn caretaker_task(
+        data: Arc<DashMap<String, BetaData>>,
+        expiry: Duration,
+    ) -> io::Result<()> {
         loop {
-            sleep(Duration::from_millis(1000)).await; // TODO: Magic number
+            sleep(CARETAKER_INTERVAL).await;
 
             // Delete expired data
-            data.retain(|_, peer_data| {
-                match peer_data.most_recent() {
-                    Some(instant) => instant.elapsed() < Duration::from_millis(3000), // TODO: magic number
-                    None => true,
-                }
+            data.retain(|_, peer_data| match peer_data.most_recent() {
+                Some(instant) => instant.elapsed() < expiry,
+                None => true,
             })
         }
     }
 
     async fn port_listen_task(
-        port: u16,
-        data: Arc<DashMap<String, PeerData>>,
-    ) -> anyhow::Result<()> {
-        let socket = UdpSocket::bind((Ipv4Addr::new(0, 0, 0, 0), port)).await?;
-        let mut buf = [0; 1024]; // TODO: Is this large enough? Use a vec?
+        socket: UdpSocket,
+        data: Arc<DashMap<String, BetaData>>,
+        expected: usize,
+        threshold_percent: usize,
+    ) -> io::Result<()> {
+        let port = socket.local_addr()?.port();
+        // One more byte than a frame may have, to tell oversized ones apart
+        let mut buf = [0; MAX_SIZE + 1];
 
         loop {
             let (len, addr) = socket.recv_from(&mut buf).await?;
-            let payload = str::from_utf8(&buf[..len])?; // TODO: should not allow a utf8 issue stop the loop
-            trace!("Rx on {}: {}", port, payload);
-
-            // Parse payload
-            let mut parts = payload.split('#');
-            let id = parts.next().expect("unexpected error");
-            let orig_port: u16 = parts
-                .next()
-                .expect("unexpected error")
-                .parse()
-                .expect("failed to parse payload");
-            let seq_num: u16 = parts
-                .next()
-                .expect("unexpected error")
-                .parse()
-                .expect("failed to parse payload");
+            let (id, orig_port, seq_num) = match Frame::decode(&buf[..len]) {
+                Ok(Frame {
+                    message:
+                        Message::Beta {
+                            id,
+                            local_port,
+                            seq_num,
+                        },
+                    ..
+                }) => (id, local_port, seq_num),
+                Ok(frame) => {
+                    warn!(
+                        "Rx on {}: unexpected {:?} from {}",
+                        port, frame.message, addr
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Rx on {}: {} from {}", port, e, addr);
+                    continue;
+                }
+            };
+            trace!("Rx on {}: {} {} {}", port, id, orig_port, seq_num);
 
             // Record event
-            data.entry(String::from(id))
-                .and_modify(|peer_data| peer_data.record_rx_event(addr, orig_port, seq_num))
-                .or_insert(PeerData::with_event(addr, orig_port, seq_num));
+            data.entry(id)
+                .or_insert_with(|| BetaData::new(expected, threshold_percent))
+                .record_rx_event(addr, orig_port, seq_num);
         }
     }
 }
@@ -0,0 +1,108 @@
+//! Rendezvous server for UDP NAT hole punching
+//!
+//! Peers send to the server so it can classify their NAT:
+//!
+//! - alpha: one socket sends [`Message::Alpha`] to each of several ports. Does
+//!   the NAT keep the same public IP and port for different destinations?
+//! - beta: several sockets each send [`Message::Beta`] to one port. How does
+//!   the NAT pick public ports for new mappings?
+//!
+//! The API port answers queries about peers and relays ICE credentials and
+//! candidates between them. A query is answered with where the peer can be
+//! reached, ranked by [`predict()`], and how to punch a hole to it, once both
+//! peers have completed their tests. Peers can also ask the server to have
+//! them both punch at the same time with [`Message::PunchReq`].
+//!
+//! Every port speaks [`Message`]s, each framed in one datagram by [`Frame`].
+
+mod alpha;
+mod beta;
+mod predict;
+mod punch;
+mod query;
+mod server;
+mod wire;
+
+use std::{net::IpAddr, time::Duration};
+
+pub use alpha::{AlphaData, AlphaResult};
+pub use beta::{BetaData, BetaResult};
+pub use predict::{predict, Candidate, Observations, PortPrediction, Prediction};
+pub use punch::{unix_micros, until, ClockEstimate, PunchOutcome, PROBE_COUNT};
+pub use query::{QueryResponse, Strategy};
+pub use server::{Classification, RendezvousBuilder, RendezvousServer};
+pub use wire::{Frame, MAGIC, MAX_SIZE, VERSION};
+
+pub const ALPHA_PORT_BASE: u16 = 4000;
+pub const ALPHA_PORT_COUNT: u16 = 10;
+pub const BETA_PORT: u16 = 4010;
+pub const BETA_COUNT: usize = 10;
+pub const API_PORT: u16 = 4011;
+
+/// How often expired test data is deleted
+const CARETAKER_INTERVAL: Duration = Duration::from_millis(1000);
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    /// Sent to each alpha port from one socket
+    Alpha { id: String },
+    /// Sent to the beta port from a new socket each, bound to `local_port`
+    Beta {
+        id: String,
+        local_port: u16,
+        seq_num: u16,
+    },
+    /// How to reach `peer_id`, from the socket bound to `local_port`
+    QueryReq {
+        id: String,
+        peer_id: String,
+        local_port: u16,
+    },
+    /// The answer to a `QueryReq`
+    QueryRes(QueryResponse),
+    /// ICE username fragment and password, relayed to `peer_id`
+    IceCredentials {
+        id: String,
+        peer_id: String,
+        ufrag: String,
+        pwd: String,
+    },
+    /// An `a=candidate:` line or `a=end-of-candidates`, relayed to `peer_id`
+    IceCandidate {
+        id: String,
+        peer_id: String,
+        candidate: String,
+    },
+    /// Ask to punch a hole to `peer_id` at the same time as it does, from
+    /// the socket bound to `local_port`
+    PunchReq {
+        id: String,
+        peer_id: String,
+        local_port: u16,
+    },
+    /// To be echoed at once, for the server to estimate the peer's clock
+    ClockProbe { server_time: u64 },
+    /// `peer_time` is when the probe arrived, in the peer's clock
+    ClockEcho {
+        id: String,
+        server_time: u64,
+        peer_time: u64,
+    },
+    /// Punch a hole to `peer_id` at `at`, microseconds since the UNIX epoch in
+    /// the receiver's clock
+    PunchAt {
+        peer_id: String,
+        at: u64,
+        public_ip: IpAddr,
+        ports: Vec<Candidate>,
+        strategy: Strategy,
+    },
+    /// Sent between peers while punching, and answered once
+    Punch { id: String },
+    /// Whether `id` heard from `peer_id` after punching
+    PunchReport {
+        id: String,
+        peer_id: String,
+        outcome: PunchOutcome,
+    },
+}
@@ -0,0 +1,380 @@
+//! Which public port a peer's NAT will use toward another peer
+//!
+//! The alpha and beta analyses give each behaviour a confidence. These are
+//! turned into probabilities in turn: an endpoint-independent mapping gives
+//! its port exactly, a port-preserving NAT the peer's local port plus the
+//! observed offset, a round robin NAT a window after the last port seen, and
+//! whatever is left goes to a wide random range.
+
+use std::{collections::HashMap, net::IpAddr, net::SocketAddr, ops::RangeInclusive};
+
+use serde::{Deserialize, Serialize};
+
+use super::{AlphaData, AlphaResult, BetaData, BetaResult};
+
+/// Steps between consecutive mappings a round robin window covers
+const WINDOW_STEPS: u16 = 10;
+/// Most ports a round robin window covers, however large the step
+const MAX_WINDOW: u16 = 1000;
+/// Half the width of the window around the local port of a NAT which picks
+/// ports close to it, as in [`BetaData::analysis`]
+const CLOSE_TO_ORIG: u16 = 100;
+/// Larger steps are taken as random, as in [`BetaData::analysis`]
+const MAX_STEP: u16 = 5000;
+/// Where a NAT with no pattern is assumed to pick ports from
+const RANDOM_PORTS: RangeInclusive<u16> = 1024..=65535;
+
+/// A port, or ports, the NAT may use
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PortPrediction {
+    Exact(u16),
+    /// First and last port, inclusive, around where the NAT was last seen
+    Range(u16, u16),
+    /// First and last port, inclusive, with no port more likely than another
+    Random(u16, u16),
+}
+
+impl PortPrediction {
+    pub fn ports(&self) -> RangeInclusive<u16> {
+        match *self {
+            Self::Exact(port) => port..=port,
+            Self::Range(first, last) | Self::Random(first, last) => first..=last,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Candidate {
+    pub port: PortPrediction,
+    /// 0 to 1. A peer's candidates add up to 1.
+    pub probability: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Prediction {
+    /// The public IP, None without any datagrams
+    pub ip: Option<IpAddr>,
+    /// Most probable first
+    pub candidates: Vec<Candidate>,
+}
+
+impl Prediction {
+    pub fn best(&self) -> Option<&Candidate> {
+        self.candidates.first()
+    }
+}
+
+/// What is known of a peer's NAT
+#[derive(Clone, Debug, Default)]
+pub struct Observations {
+    /// Results with confidence 0..100, as from [`AlphaData::analysis`]
+    pub alpha: Vec<(AlphaResult, usize)>,
+    /// Results with confidence 0..100, as from [`BetaData::analysis`]
+    pub beta: Vec<(BetaResult, usize)>,
+    /// Public address of each alpha datagram, in the order received
+    pub alpha_events: Vec<SocketAddr>,
+    /// Public address, local port and sequence number of each beta datagram
+    pub beta_events: Vec<(SocketAddr, u16, u16)>,
+    /// Public address of the last datagram of either test
+    pub most_recent: Option<SocketAddr>,
+    /// The local port the peer will punch from, if known
+    pub local_port: Option<u16>,
+}
+
+impl Observations {
+    /// What the server has seen of a peer
+    pub fn new(alpha: &AlphaData, beta: &BetaData) -> Self {
+        Self {
+            alpha: alpha.analysis().collect(),
+            beta: beta.analysis().collect(),
+            alpha_events: alpha.rx_events().copied().collect(),
+            beta_events: beta
+                .rx_events()
+                .map(|(addr, orig_port, seq_num)| (*addr, *orig_port, *seq_num))
+                .collect(),
+            most_recent: if alpha.most_recent() > beta.most_recent() {
+                alpha.most_recent_addr()
+            } else {
+                beta.most_recent_addr()
+            },
+            local_port: None,
+        }
+    }
+
+    /// With the local port the peer will punch from
+    pub fn with_local_port(mut self, local_port: u16) -> Self {
+        self.local_port = Some(local_port);
+        self
+    }
+
+    fn beta_confidence(&self, result: BetaResult) -> f64 {
+        self.beta
+            .iter()
+            .filter(|(x, _)| *x == result)
+            .map(|(_, confidence)| *confidence as f64 / 100.0)
+            .fold(0.0, f64::max)
+    }
+
+    /// The most common difference between public and local port
+    fn port_offset(&self) -> Option<i32> {
+        let mut offsets: HashMap<i32, usize> = HashMap::new();
+        for (addr, orig_port, _) in &self.beta_events {
+            *offsets
+                .entry(addr.port() as i32 - *orig_port as i32)
+                .or_default() += 1;
+        }
+        offsets
+            .into_iter()
+            .max_by_key(|(_, count)| *count)
+            .map(|(offset, _)| offset)
+    }
+
+    /// The median step between the ports of consecutive mappings, ignoring
+    /// steps too large to be round robin
+    fn port_step(&self) -> u16 {
+        let mut beta: Vec<_> = self.beta_events.iter().collect();
+        beta.sort_by_key(|(_, _, seq_num)| *seq_num);
+        let beta_ports = beta.iter().map(|(addr, _, _)| addr.port());
+        let alpha_ports = self.alpha_events.iter().map(SocketAddr::port);
+
+        let mut steps: Vec<u16> = Vec::new();
+        for ports in [beta_ports.collect::<Vec<_>>(), alpha_ports.collect()] {
+            steps.extend(
+                ports
+                    .windows(2)
+                    .map(|w| w[1].wrapping_sub(w[0]))
+                    .filter(|step| (1..=MAX_STEP).contains(step)),
+            );
+        }
+        steps.sort_unstable();
+        steps.get(steps.len() / 2).copied().unwrap_or(1)
+    }
+}
+
+/// Rank the ports a peer's NAT may use for a new destination.
+pub fn predict(observations: &Observations) -> Prediction {
+    let mut candidates: Vec<Candidate> = Vec::new();
+    let mut remaining = 1.0;
+    let mut add = |port: PortPrediction, confidence: f64| {
+        let probability = remaining * confidence.clamp(0.0, 1.0);
+        if probability > 0.0 {
+            remaining -= probability;
+            candidates.push(Candidate { port, probability });
+        }
+    };
+
+    // Endpoint-independent mapping
+    let constant = observations
+        .alpha
+        .iter()
+        .filter_map(|(result, confidence)| match result {
+            AlphaResult::SrcIpPortConstant(ip, port) => Some((*ip, *port, *confidence)),
+            _ => None,
+        })
+        .max_by_key(|(_, _, confidence)| *confidence);
+    if let Some((_, port, confidence)) = constant {
+        add(PortPrediction::Exact(port), confidence as f64 / 100.0);
+    }
+
+    // Port preserving, or close to the local port
+    if let Some(local_port) = observations.local_port {
+        let preserving = observations
+            .beta_confidence(BetaResult::SrcPortAsOrig)
+            .max(observations.beta_confidence(BetaResult::SrcPortConstantDiffToOrig));
+        if let Some(offset) = observations.port_offset() {
+            let port = (local_port as i32 + offset).clamp(1, u16::MAX as i32) as u16;
+            add(PortPrediction::Exact(port), preserving);
+        }
+        add(
+            PortPrediction::Range(
+                local_port.saturating_sub(CLOSE_TO_ORIG).max(1),
+                local_port.saturating_add(CLOSE_TO_ORIG),
+            ),
+            observations.beta_confidence(BetaResult::SrcPortCloseToOrig),
+        );
+    }
+
+    // Round robin, continuing from the last port seen
+    if let Some(last) = observations.most_recent {
+        let step = observations.port_step();
+        let width = step.saturating_mul(WINDOW_STEPS).min(MAX_WINDOW);
+        add(
+            PortPrediction::Range(
+                last.port().saturating_sub(step).max(1),
+                last.port().saturating_add(width),
+            ),
+            observations.beta_confidence(BetaResult::SrcPortRoundRobin),
+        );
+    }
+
+    add(
+        PortPrediction::Random(*RANDOM_PORTS.start(), *RANDOM_PORTS.end()),
+        1.0,
+    );
+    candidates.sort_by(|a, b| b.probability.total_cmp(&a.probability));
+
+    Prediction {
+        ip: constant
+            .map(|(ip, _, _)| ip)
+            .or_else(|| most_common_ip(observations)),
+        candidates,
+    }
+}
+
+fn most_common_ip(observations: &Observations) -> Option<IpAddr> {
+    let mut ips: HashMap<IpAddr, usize> = HashMap::new();
+    let addrs = observations
+        .alpha_events
+        .iter()
+        .chain(observations.beta_events.iter().map(|(addr, _, _)| addr));
+    for addr in addrs {
+        *ips.entry(addr.ip()).or_default() += 1;
+    }
+    ips.into_iter()
+        .max_by_key(|(_, count)| *count)
+        .map(|(ip, _)| ip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IP: [u8; 4] = [203, 0, 113, 5];
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from((IP, port))
+    }
+
+    fn check(prediction: &Prediction) {
+        let total: f64 = prediction.candidates.iter().map(|x| x.probability).sum();
+        assert!((total - 1.0).abs() < 1e-9, "{:?}", prediction);
+        assert!(prediction
+            .candidates
+            .windows(2)
+            .all(|w| w[0].probability >= w[1].probability));
+    }
+
+    #[test]
+    fn predictions() {
+        let random = PortPrediction::Random(1024, 65535);
+        let cases = [
+            (
+                "endpoint independent",
+                Observations {
+                    alpha: vec![(AlphaResult::SrcIpPortConstant(IP.into(), 40000), 100)],
+                    alpha_events: vec![addr(40000); 3],
+                    most_recent: Some(addr(40000)),
+                    ..Default::default()
+                },
+                vec![(PortPrediction::Exact(40000), 1.0)],
+            ),
+            (
+                "port preserving",
+                Observations {
+                    beta: vec![(BetaResult::SrcPortConstantDiffToOrig, 100)],
+                    beta_events: vec![(addr(5012), 5010, 0), (addr(6002), 6000, 1)],
+                    ..Default::default()
+                }
+                .with_local_port(7000),
+                vec![(PortPrediction::Exact(7002), 1.0)],
+            ),
+            (
+                "round robin",
+                Observations {
+                    beta: vec![(BetaResult::SrcPortRoundRobin, 80)],
+                    beta_events: vec![
+                        (addr(30000), 5000, 0),
+                        (addr(30002), 5001, 1),
+                        (addr(30004), 5002, 2),
+                    ],
+                    most_recent: Some(addr(30004)),
+                    ..Default::default()
+                },
+                vec![
+                    (PortPrediction::Range(30002, 30024), 0.8),
+                    (random.clone(), 0.2),
+                ],
+            ),
+            (
+                "round robin with large steps",
+                Observations {
+                    beta: vec![(BetaResult::SrcPortRoundRobin, 100)],
+                    beta_events: vec![
+                        (addr(10000), 5000, 0),
+                        (addr(15000), 5001, 1),
+                        (addr(20000), 5002, 2),
+                    ],
+                    most_recent: Some(addr(20000)),
+                    ..Default::default()
+                },
+                vec![(PortPrediction::Range(15000, 20000 + MAX_WINDOW), 1.0)],
+            ),
+            (
+                "no pattern",
+                Observations {
+                    alpha: vec![(AlphaResult::SrcIpPortInconstant, 100)],
+                    beta: vec![(BetaResult::Unknown, 100)],
+                    alpha_events: vec![addr(41234), addr(17)],
+                    ..Default::default()
+                },
+                vec![(random.clone(), 1.0)],
+            ),
+        ];
+        for (name, observations, expected) in cases {
+            let prediction = predict(&observations);
+            check(&prediction);
+            assert_eq!(prediction.ip, Some(IP.into()), "{}", name);
+            let candidates: Vec<_> = prediction
+                .candidates
+                .iter()
+                .map(|x| (x.port.clone(), x.probability))
+                .collect();
+            assert_eq!(
+                candidates.len(),
+                expected.len(),
+                "{}: {:?}",
+                name,
+                candidates
+            );
+            for ((port, probability), (expected_port, expected_probability)) in
+                candidates.iter().zip(&expected)
+            {
+                assert_eq!(port, expected_port, "{}", name);
+                assert!(
+                    (probability - expected_probability).abs() < 1e-9,
+                    "{}",
+                    name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn mixed_confidences() {
+        let observations = Observations {
+            alpha: vec![(AlphaResult::SrcIpPortConstant(IP.into(), 40000), 30)],
+            beta: vec![
+                (BetaResult::SrcPortAsOrig, 50),
+                (BetaResult::SrcPortCloseToOrig, 60),
+                (BetaResult::SrcPortRoundRobin, 90),
+            ],
+            beta_events: vec![(addr(5000), 5000, 0), (addr(5001), 5001, 1)],
+            most_recent: Some(addr(40000)),
+            ..Default::default()
+        }
+        .with_local_port(6000);
+        let prediction = predict(&observations);
+        check(&prediction);
+        assert_eq!(prediction.candidates.len(), 5);
+        assert_eq!(prediction.best().unwrap().port, PortPrediction::Exact(6000));
+        assert!((prediction.best().unwrap().probability - 0.35).abs() < 1e-9);
+    }
+
+    #[test]
+    fn nothing_seen() {
+        let prediction = predict(&Observations::default());
+        check(&prediction);
+        assert_eq!(prediction.ip, None);
+        assert_eq!(prediction.candidates.len(), 1);
+    }
+}
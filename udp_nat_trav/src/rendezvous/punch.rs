@@ -0,0 +1,351 @@
+//! Coordinated hole punching
+//!
+//! Both peers send [`Message::PunchReq`] naming each other, from the socket
+//! they will punch from. The server estimates each peer's clock offset and
+//! round trip time from a few [`Message::ClockProbe`]s, then sends both a
+//! [`Message::PunchAt`] for the same instant, in each peer's own clock. Each
+//! peer reports with [`Message::PunchReport`] whether it heard from the other.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use super::Message;
+
+/// Probes sent for each request
+pub const PROBE_COUNT: usize = 5;
+/// Echoes needed before a peer's clock estimate is used
+const MIN_SAMPLES: usize = 3;
+/// Added to the slowest round trip before the punch time, for jitter and
+/// processing
+const PUNCH_MARGIN: Duration = Duration::from_millis(100);
+/// How long a request waits for the peer's, a probe for its echo, and a
+/// report is kept
+const REQUEST_EXPIRY: Duration = Duration::from_secs(10);
+
+/// Microseconds since the UNIX epoch, in this machine's clock
+pub fn unix_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_micros() as u64)
+}
+
+/// How long until `at`, microseconds since the UNIX epoch in this machine's
+/// clock. Zero if it has passed.
+pub fn until(at: u64) -> Duration {
+    Duration::from_micros(at.saturating_sub(unix_micros()))
+}
+
+/// A peer's clock compared to the server's
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClockEstimate {
+    /// Peer clock minus server clock, in microseconds
+    pub offset: i64,
+    pub rtt: Duration,
+}
+
+impl ClockEstimate {
+    /// From a probe the server sent at `server_time`, stamped `peer_time` by
+    /// the peer and echoed back to arrive at `received`. Assumes the two
+    /// directions take as long as each other.
+    pub fn new(server_time: u64, peer_time: u64, received: u64) -> Self {
+        let rtt = received.saturating_sub(server_time);
+        Self {
+            offset: peer_time as i64 - (server_time + rtt / 2) as i64,
+            rtt: Duration::from_micros(rtt),
+        }
+    }
+}
+
+/// What a peer reported after punching
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PunchOutcome {
+    /// Heard from the peer at this address
+    Success(SocketAddr),
+    Failure,
+}
+
+/// A peer to send [`Message::PunchAt`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Scheduled {
+    /// Of the peer's request
+    pub request_id: u32,
+    pub id: String,
+    pub peer_id: String,
+    pub addr: SocketAddr,
+    /// Microseconds since the UNIX epoch, in the peer's clock
+    pub at: u64,
+}
+
+struct Request {
+    request_id: u32,
+    peer_id: String,
+    addr: SocketAddr,
+    received: Instant,
+    /// When each probe not yet echoed was sent
+    probes: Vec<u64>,
+    samples: Vec<ClockEstimate>,
+}
+
+impl Request {
+    /// The sample least delayed by queueing
+    fn estimate(&self) -> Option<ClockEstimate> {
+        if self.samples.len() < MIN_SAMPLES {
+            return None;
+        }
+        self.samples.iter().min_by_key(|sample| sample.rtt).copied()
+    }
+}
+
+/// Pairs up punch requests and schedules them
+pub(crate) struct Coordinator {
+    requests: HashMap<String, Request>,
+    reports: Arc<DashMap<(String, String), (PunchOutcome, Instant)>>,
+}
+
+impl Coordinator {
+    pub fn new() -> Self {
+        Self {
+            requests: HashMap::new(),
+            reports: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// Outcomes and when they were reported, by (id, peer_id)
+    pub fn reports(&self) -> Arc<DashMap<(String, String), (PunchOutcome, Instant)>> {
+        self.reports.clone()
+    }
+
+    /// Record `id`'s request, replacing any earlier one, and return the probes
+    /// to send to `addr`. None for a peer naming itself.
+    pub fn request(
+        &mut self,
+        request_id: u32,
+        id: &str,
+        peer_id: &str,
+        addr: SocketAddr,
+    ) -> Vec<Message> {
+        self.expire();
+        if id == peer_id {
+            debug!("Ignoring punch request from {} to itself", id);
+            return Vec::new();
+        }
+        let probes: Vec<u64> = (0..PROBE_COUNT).map(|_| unix_micros()).collect();
+        self.requests.insert(
+            String::from(id),
+            Request {
+                request_id,
+                peer_id: String::from(peer_id),
+                addr,
+                received: Instant::now(),
+                probes: probes.clone(),
+                samples: Vec::with_capacity(PROBE_COUNT),
+            },
+        );
+
+        probes
+            .into_iter()
+            .map(|server_time| Message::ClockProbe { server_time })
+            .collect()
+    }
+
+    /// Record an echo of a probe, ignored unless it came `from` the address
+    /// of `id`'s request, carries the time of one of its probes not echoed
+    /// yet, and took no longer than [`REQUEST_EXPIRY`]. Once both peers of a
+    /// pair of requests have clock estimates, the requests are removed and
+    /// both are scheduled.
+    pub fn echo(
+        &mut self,
+        id: &str,
+        from: SocketAddr,
+        server_time: u64,
+        peer_time: u64,
+    ) -> Option<[Scheduled; 2]> {
+        let request = self
+            .requests
+            .get_mut(id)
+            .filter(|request| request.addr == from)?;
+        let Some(probe) = request.probes.iter().position(|x| *x == server_time) else {
+            debug!("Echo from {} of no probe of ours", from);
+            return None;
+        };
+        request.probes.swap_remove(probe);
+        let sample = ClockEstimate::new(server_time, peer_time, unix_micros());
+        if sample.rtt > REQUEST_EXPIRY {
+            debug!("Echo from {} took {:?}", from, sample.rtt);
+            return None;
+        }
+        request.samples.push(sample);
+
+        let ours = request.estimate()?;
+        let peer_id = request.peer_id.clone();
+        let theirs = self
+            .requests
+            .get(&peer_id)
+            .filter(|request| request.peer_id == id)?
+            .estimate()?;
+
+        // Late enough for both instructions to arrive
+        let at = unix_micros() + (ours.rtt.max(theirs.rtt) + PUNCH_MARGIN).as_micros() as u64;
+        let ours_request = self.requests.remove(id)?;
+        let theirs_request = self.requests.remove(&peer_id)?;
+        Some([
+            Scheduled {
+                request_id: ours_request.request_id,
+                id: String::from(id),
+                peer_id: peer_id.clone(),
+                addr: ours_request.addr,
+                at: at.saturating_add_signed(ours.offset),
+            },
+            Scheduled {
+                request_id: theirs_request.request_id,
+                id: peer_id,
+                peer_id: String::from(id),
+                addr: theirs_request.addr,
+                at: at.saturating_add_signed(theirs.offset),
+            },
+        ])
+    }
+
+    pub fn report(&mut self, id: &str, peer_id: &str, outcome: PunchOutcome) {
+        self.expire();
+        self.reports.insert(
+            (String::from(id), String::from(peer_id)),
+            (outcome, Instant::now()),
+        );
+    }
+
+    fn expire(&mut self) {
+        self.requests
+            .retain(|_, request| request.received.elapsed() < REQUEST_EXPIRY);
+        self.reports
+            .retain(|_, (_, reported)| reported.elapsed() < REQUEST_EXPIRY);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock_estimate() {
+        // Peer ahead: sent at 1000, stamped 5100, back at 1200
+        let ahead = ClockEstimate::new(1000, 5100, 1200);
+        assert_eq!(ahead.rtt, Duration::from_micros(200));
+        assert_eq!(ahead.offset, 4000);
+
+        let behind = ClockEstimate::new(1000, 100, 1200);
+        assert_eq!(behind.offset, -1000);
+
+        // A clock going backwards gives no negative round trip
+        assert_eq!(ClockEstimate::new(1000, 1000, 900).rtt, Duration::ZERO);
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn probe_times(probes: Vec<Message>) -> Vec<u64> {
+        assert_eq!(probes.len(), PROBE_COUNT);
+        probes
+            .into_iter()
+            .map(|probe| match probe {
+                Message::ClockProbe { server_time } => server_time,
+                msg => panic!("not a probe: {:?}", msg),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn schedule_pair() {
+        // Alice's clock is a second ahead, Bob's a second behind
+        const SECOND: i64 = 1_000_000;
+        let mut coordinator = Coordinator::new();
+        let alice = probe_times(coordinator.request(1, "alice", "bob", addr(1)));
+        let bob = probe_times(coordinator.request(2, "bob", "alice", addr(2)));
+
+        for &time in &alice[..MIN_SAMPLES] {
+            assert!(coordinator
+                .echo("alice", addr(1), time, time.saturating_add_signed(SECOND))
+                .is_none());
+        }
+        for &time in &bob[..MIN_SAMPLES - 1] {
+            assert!(coordinator
+                .echo("bob", addr(2), time, time.saturating_add_signed(-SECOND))
+                .is_none());
+        }
+        let now = unix_micros();
+        let time = bob[MIN_SAMPLES - 1];
+        let [bob, alice] = coordinator
+            .echo("bob", addr(2), time, time.saturating_add_signed(-SECOND))
+            .unwrap();
+
+        assert_eq!(
+            (bob.request_id, bob.id.as_str(), bob.peer_id.as_str()),
+            (2, "bob", "alice")
+        );
+        assert_eq!(
+            (alice.request_id, alice.id.as_str(), alice.addr),
+            (1, "alice", addr(1))
+        );
+        // The same instant in each clock, after the margin
+        let diff = alice.at as i64 - bob.at as i64;
+        assert!((diff - 2 * SECOND).abs() < 10_000, "{}", diff);
+        let at = bob.at.saturating_add_signed(SECOND);
+        assert!(at >= now + PUNCH_MARGIN.as_micros() as u64);
+        assert!(at < now + 2 * PUNCH_MARGIN.as_micros() as u64);
+
+        // Both requests are done with
+        assert!(coordinator.requests.is_empty());
+    }
+
+    #[test]
+    fn unmatched_requests() {
+        let mut coordinator = Coordinator::new();
+        assert!(coordinator.request(1, "alice", "alice", addr(1)).is_empty());
+        assert!(coordinator.requests.is_empty());
+
+        // Bob wants someone else
+        let alice = probe_times(coordinator.request(1, "alice", "bob", addr(1)));
+        let bob = probe_times(coordinator.request(2, "bob", "carol", addr(2)));
+        for (id, from, times) in [("alice", addr(1), &alice), ("bob", addr(2), &bob)] {
+            for time in times {
+                assert!(coordinator.echo(id, from, *time, *time).is_none());
+            }
+        }
+        assert_eq!(coordinator.requests["alice"].samples.len(), PROBE_COUNT);
+    }
+
+    #[test]
+    fn bad_echoes() {
+        let mut coordinator = Coordinator::new();
+        coordinator.request(1, "alice", "bob", addr(1));
+        // Probes sent in the same microsecond share a time
+        let now = unix_micros();
+        let times: Vec<u64> = (0..PROBE_COUNT as u64).map(|i| now - i).collect();
+        coordinator.requests.get_mut("alice").unwrap().probes = times.clone();
+
+        // From elsewhere, or with a time we never sent
+        coordinator.echo("alice", addr(9), times[0], times[0]);
+        coordinator.echo("alice", addr(1), times[0] + 12_345_678, times[0]);
+        coordinator.echo("carol", addr(1), times[0], times[0]);
+        // The same probe twice
+        coordinator.echo("alice", addr(1), times[1], times[1]);
+        coordinator.echo("alice", addr(1), times[1], times[1]);
+        assert_eq!(coordinator.requests["alice"].samples.len(), 1);
+
+        // Too slow
+        let request = coordinator.requests.get_mut("alice").unwrap();
+        let old = unix_micros() - 2 * REQUEST_EXPIRY.as_micros() as u64;
+        request.probes.push(old);
+        coordinator.echo("alice", addr(1), old, old);
+        assert_eq!(coordinator.requests["alice"].samples.len(), 1);
+    }
+}
@@ -0,0 +1,104 @@
+use std::{net::IpAddr, time::Instant};
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    alpha::AlphaManager,
+    beta::BetaManager,
+    predict::{self, Candidate, Observations, PortPrediction, Prediction},
+};
+
+/// How the querying peer should punch a hole to the other
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Strategy {
+    /// Both NATs keep their ports: send to the peer's predicted address
+    Direct,
+    /// Only the peer's NAT keeps its port: send to it from many sockets,
+    /// each a new mapping on our NAT
+    ManySockets,
+    /// Our NAT keeps its port but the peer's does not: send from one socket
+    /// to the peer's predicted ports, or to many random ones
+    ManyPorts,
+    /// Neither NAT is predictable enough, use a relay
+    Relay,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum QueryResponse {
+    /// How to reach `peer_id`
+    Ready {
+        peer_id: String,
+        public_ip: IpAddr,
+        /// Ports the peer's NAT may use toward us, most probable first
+        ports: Vec<Candidate>,
+        strategy: Strategy,
+    },
+    /// These ids have not completed both tests, or their results have
+    /// expired. Query again after testing.
+    NotEnoughData {
+        peer_id: String,
+        missing: Vec<String>,
+    },
+}
+
+/// Answer `id`'s query about `peer_id`. `local_ports` has the port each id
+/// punches from, as in its last request, and when that was.
+pub(crate) fn answer(
+    alpha_manager: &AlphaManager,
+    beta_manager: &BetaManager,
+    local_ports: &DashMap<String, (u16, Instant)>,
+    id: &str,
+    peer_id: &str,
+) -> QueryResponse {
+    let alpha_data = alpha_manager.data();
+    let beta_data = beta_manager.data();
+    let predict = |id: &str| {
+        let alpha = alpha_data.get(id)?;
+        let beta = beta_data.get(id)?;
+        if !alpha.test_complete() || !beta.test_complete() {
+            return None;
+        }
+        let observations = Observations::new(&alpha, &beta);
+        let observations = match local_ports.get(id) {
+            Some(entry) => observations.with_local_port(entry.0),
+            None => observations,
+        };
+        Some(predict::predict(&observations))
+    };
+
+    match (predict(id), predict(peer_id)) {
+        (
+            Some(ours),
+            Some(Prediction {
+                ip: Some(public_ip),
+                candidates,
+            }),
+        ) => QueryResponse::Ready {
+            peer_id: String::from(peer_id),
+            public_ip,
+            strategy: strategy(&ours.candidates, &candidates),
+            ports: candidates,
+        },
+        (ours, theirs) => QueryResponse::NotEnoughData {
+            peer_id: String::from(peer_id),
+            missing: [(id, ours.is_none()), (peer_id, theirs.is_none())]
+                .into_iter()
+                .filter(|(_, missing)| *missing)
+                .map(|(id, _)| String::from(id))
+                .collect(),
+        },
+    }
+}
+
+/// From each peer's most probable ports
+fn strategy(ours: &[Candidate], theirs: &[Candidate]) -> Strategy {
+    let best = |candidates: &[Candidate]| candidates.first().map(|c| c.port.clone());
+    match (best(ours), best(theirs)) {
+        (Some(PortPrediction::Exact(_)), Some(PortPrediction::Exact(_))) => Strategy::Direct,
+        (_, Some(PortPrediction::Exact(_))) => Strategy::ManySockets,
+        (Some(PortPrediction::Exact(_)), _) => Strategy::ManyPorts,
+        (Some(PortPrediction::Range(..)), Some(PortPrediction::Range(..))) => Strategy::ManyPorts,
+        _ => Strategy::Relay,
+    }
+}
@@ -0,0 +1,595 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use tokio::{
+    net::UdpSocket,
+    task::JoinSet,
+    time::{interval, sleep},
+};
+use tracing::{info, trace, warn};
+
+use super::{
+    alpha::{AlphaData, AlphaManager, AlphaResult},
+    beta::{BetaData, BetaManager, BetaResult},
+    punch::{Coordinator, PunchOutcome, Scheduled},
+    query::{self, QueryResponse},
+    Frame, Message, ALPHA_PORT_BASE, ALPHA_PORT_COUNT, API_PORT, BETA_COUNT, BETA_PORT,
+    CARETAKER_INTERVAL, MAX_SIZE,
+};
+
+// TODO: Characterize if we expect NAT to use a different source IP or port with
+// another peer than it did with us.
+
+// TODO: acknowledgements of received packets, and timeouts for peer and server.
+
+/// How often recent test data is logged
+const MONITOR_INTERVAL: Duration = Duration::from_millis(1000);
+/// How long where a peer was heard from, its local port and what is queued
+/// for it are kept
+const SIGNAL_EXPIRY: Duration = Duration::from_secs(60);
+/// Most ICE messages queued for a peer not heard from yet
+const MAX_PENDING: usize = 64;
+
+/// What the tests concluded about a peer's NAT, so far
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Classification {
+    pub alpha: Option<AlphaResult>,
+    pub beta: Option<BetaResult>,
+}
+
+#[derive(Clone, Debug)]
+pub struct RendezvousBuilder {
+    ip: IpAddr,
+    alpha_ports: Vec<u16>,
+    beta_port: u16,
+    api_port: u16,
+    beta_count: usize,
+    threshold_percent: usize,
+    expiry: Duration,
+}
+
+impl Default for RendezvousBuilder {
+    fn default() -> Self {
+        Self {
+            ip: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            alpha_ports: (ALPHA_PORT_BASE..ALPHA_PORT_BASE + ALPHA_PORT_COUNT).collect(),
+            beta_port: BETA_PORT,
+            api_port: API_PORT,
+            beta_count: BETA_COUNT,
+            threshold_percent: 80,
+            expiry: Duration::from_millis(3000),
+        }
+    }
+}
+
+impl RendezvousBuilder {
+    /// IP to listen on, 0.0.0.0 by default
+    pub fn ip(mut self, ip: IpAddr) -> Self {
+        self.ip = ip;
+        self
+    }
+
+    /// Ports for the alpha test. Port 0 is any free port, so several zeros
+    /// give several ports.
+    pub fn alpha_ports(mut self, ports: impl IntoIterator<Item = u16>) -> Self {
+        self.alpha_ports = ports.into_iter().collect();
+        self
+    }
+
+    pub fn beta_port(mut self, port: u16) -> Self {
+        self.beta_port = port;
+        self
+    }
+
+    pub fn api_port(mut self, port: u16) -> Self {
+        self.api_port = port;
+        self
+    }
+
+    /// Number of datagrams a peer sends in the beta test
+    pub fn beta_count(mut self, count: usize) -> Self {
+        self.beta_count = count;
+        self
+    }
+
+    /// Percentage of a test's datagrams which must arrive before it is
+    /// analysed
+    pub fn threshold_percent(mut self, percent: usize) -> Self {
+        self.threshold_percent = percent;
+        self
+    }
+
+    /// How long a peer's test data is kept after its last datagram
+    pub fn expiry(mut self, expiry: Duration) -> Self {
+        self.expiry = expiry;
+        self
+    }
+
+    /// Bind every port and start serving. Must be called from within a
+    /// tokio runtime.
+    pub async fn start(self) -> io::Result<RendezvousServer> {
+        let mut alpha_sockets = Vec::with_capacity(self.alpha_ports.len());
+        for port in &self.alpha_ports {
+            alpha_sockets.push(UdpSocket::bind((self.ip, *port)).await?);
+        }
+        let beta_socket = UdpSocket::bind((self.ip, self.beta_port)).await?;
+        let api_socket = UdpSocket::bind((self.ip, self.api_port)).await?;
+
+        let alpha_addrs = alpha_sockets
+            .iter()
+            .map(UdpSocket::local_addr)
+            .collect::<io::Result<_>>()?;
+        let beta_addr = beta_socket.local_addr()?;
+        let api_addr = api_socket.local_addr()?;
+
+        // Spawn tasks for tests
+        let alpha_manager = Arc::new(AlphaManager::new(self.threshold_percent, self.expiry));
+        let mut tasks = JoinSet::new();
+        alpha_manager.spawn_tasks(alpha_sockets, &mut tasks);
+        let beta_manager = Arc::new(BetaManager::new(
+            self.beta_count,
+            self.threshold_percent,
+            self.expiry,
+        ));
+        beta_manager.spawn_tasks(beta_socket, &mut tasks);
+
+        tasks.spawn(monitor_task(alpha_manager.clone(), beta_manager.clone()));
+        let coordinator = Coordinator::new();
+        let punch_reports = coordinator.reports();
+        let local_ports = Arc::new(DashMap::new());
+        tasks.spawn(api_task(
+            api_socket,
+            alpha_manager.clone(),
+            beta_manager.clone(),
+            local_ports.clone(),
+            coordinator,
+        ));
+        info!("Rendezvous API on {}", api_addr);
+
+        Ok(RendezvousServer {
+            alpha_manager,
+            beta_manager,
+            local_ports,
+            punch_reports,
+            alpha_addrs,
+            beta_addr,
+            api_addr,
+            tasks,
+        })
+    }
+}
+
+/// Runs until shut down or dropped
+pub struct RendezvousServer {
+    alpha_manager: Arc<AlphaManager>,
+    beta_manager: Arc<BetaManager>,
+    /// The port each id punches from, as in its last request, and when
+    local_ports: Arc<DashMap<String, (u16, Instant)>>,
+    punch_reports: Arc<DashMap<(String, String), (PunchOutcome, Instant)>>,
+    alpha_addrs: Vec<SocketAddr>,
+    beta_addr: SocketAddr,
+    api_addr: SocketAddr,
+    tasks: JoinSet<io::Result<()>>,
+}
+
+impl RendezvousServer {
+    pub fn builder() -> RendezvousBuilder {
+        RendezvousBuilder::default()
+    }
+
+    pub fn alpha_addrs(&self) -> &[SocketAddr] {
+        &self.alpha_addrs
+    }
+
+    pub fn beta_addr(&self) -> SocketAddr {
+        self.beta_addr
+    }
+
+    pub fn api_addr(&self) -> SocketAddr {
+        self.api_addr
+    }
+
+    pub fn classification(&self, id: &str) -> Classification {
+        classify(&self.alpha_manager, &self.beta_manager, id)
+    }
+
+    /// What the API port would answer `id` about `peer_id`
+    pub fn query(&self, id: &str, peer_id: &str) -> QueryResponse {
+        query::answer(
+            &self.alpha_manager,
+            &self.beta_manager,
+            &self.local_ports,
+            id,
+            peer_id,
+        )
+    }
+
+    /// What `id` last reported after punching to `peer_id`, if recently
+    pub fn punch_outcome(&self, id: &str, peer_id: &str) -> Option<PunchOutcome> {
+        self.punch_reports
+            .get(&(String::from(id), String::from(peer_id)))
+            .map(|report| report.0.clone())
+    }
+
+    /// Test data of the peers heard from recently, by id
+    pub fn alpha_data(&self) -> Arc<DashMap<String, AlphaData>> {
+        self.alpha_manager.data()
+    }
+
+    pub fn beta_data(&self) -> Arc<DashMap<String, BetaData>> {
+        self.beta_manager.data()
+    }
+
+    /// Wait until any task stops, which it only does when its socket fails.
+    /// The other ports are still served until shut down.
+    pub async fn wait(&mut self) -> io::Result<()> {
+        match self.tasks.join_next().await {
+            Some(res) => res.map_err(io::Error::other)?,
+            None => Ok(()),
+        }
+    }
+
+    /// Stop every task and close the sockets.
+    pub async fn shutdown(mut self) {
+        self.tasks.shutdown().await;
+        info!("Rendezvous server on {} shut down", self.api_addr);
+    }
+}
+
+fn classify(alpha_manager: &AlphaManager, beta_manager: &BetaManager, id: &str) -> Classification {
+    Classification {
+        alpha: alpha_manager
+            .data()
+            .get(id)
+            .and_then(|peer_data| peer_data.conclusion()),
+        beta: beta_manager
+            .data()
+            .get(id)
+            .and_then(|peer_data| peer_data.conclusion()),
+    }
+}
+
+async fn api_task(
+    socket: UdpSocket,
+    alpha_manager: Arc<AlphaManager>,
+    beta_manager: Arc<BetaManager>,
+    local_ports: Arc<DashMap<String, (u16, Instant)>>,
+    mut coordinator: Coordinator,
+) -> io::Result<()> {
+    // One more byte than a frame may have, to tell oversized ones apart
+    let mut buf = [0; MAX_SIZE + 1];
+
+    // Where and when each id was last heard from, and what is waiting for
+    // ids not heard from yet, with when the first of it arrived
+    let mut addrs: HashMap<String, (SocketAddr, Instant)> = HashMap::new();
+    let mut pending: HashMap<String, (Vec<Vec<u8>>, Instant)> = HashMap::new();
+    let mut caretaker = interval(CARETAKER_INTERVAL);
+
+    loop {
+        let (len, from) = tokio::select! {
+            res = socket.recv_from(&mut buf) => res?,
+            _ = caretaker.tick() => {
+                // Delete expired signalling
+                addrs.retain(|_, (_, heard)| heard.elapsed() < SIGNAL_EXPIRY);
+                pending.retain(|_, (_, queued)| queued.elapsed() < SIGNAL_EXPIRY);
+                local_ports.retain(|_, (_, heard)| heard.elapsed() < SIGNAL_EXPIRY);
+                continue;
+            }
+        };
+        let Frame {
+            request_id,
+            message,
+        } = match Frame::decode(&buf[..len]) {
+            Ok(frame) => frame,
+            Err(e) => {
+                warn!("Unable to decode message from {}: {}", from, e);
+                continue;
+            }
+        };
+
+        match &message {
+            // Not the password
+            Message::IceCredentials { id, peer_id, .. } => {
+                info!("Rx: IceCredentials from {} for {}", id, peer_id)
+            }
+            message => info!("Rx: {:?}", message),
+        }
+        match message {
+            Message::QueryReq {
+                id,
+                peer_id,
+                local_port,
+            } => {
+                local_ports.insert(id.clone(), (local_port, Instant::now()));
+                let ours = classify(&alpha_manager, &beta_manager, &id);
+                let theirs = classify(&alpha_manager, &beta_manager, &peer_id);
+                info!("{}: {:?} {:?}", id, ours.alpha, ours.beta);
+                info!("{}: {:?} {:?}", peer_id, theirs.alpha, theirs.beta);
+
+                let response =
+                    query::answer(&alpha_manager, &beta_manager, &local_ports, &id, &peer_id);
+                info!("Tx to {}: {:?}", from, response);
+                let frame = Frame::new(request_id, Message::QueryRes(response));
+                send_frame(&socket, &frame, from).await;
+            }
+            // Relayed as they came
+            Message::IceCredentials { id, peer_id, .. }
+            | Message::IceCandidate { id, peer_id, .. } => {
+                addrs.insert(id.clone(), (from, Instant::now()));
+                let queued = pending.remove(&id).map(|(queue, _)| queue);
+                for buf in queued.unwrap_or_default() {
+                    send(&socket, &buf, from).await;
+                }
+                match addrs.get(&peer_id) {
+                    Some((addr, _)) => {
+                        send(&socket, &buf[0..len], *addr).await;
+                    }
+                    None => {
+                        let (queue, _) = pending
+                            .entry(peer_id.clone())
+                            .or_insert_with(|| (Vec::new(), Instant::now()));
+                        if queue.len() < MAX_PENDING {
+                            queue.push(buf[0..len].to_vec());
+                        } else {
+                            warn!("Dropping message from {}: queue for {} full", id, peer_id);
+                        }
+                    }
+                }
+            }
+            Message::PunchReq {
+                id,
+                peer_id,
+                local_port,
+            } => {
+                local_ports.insert(id.clone(), (local_port, Instant::now()));
+                for probe in coordinator.request(request_id, &id, &peer_id, from) {
+                    send_frame(&socket, &Frame::new(request_id, probe), from).await;
+                }
+            }
+            Message::ClockEcho {
+                id,
+                server_time,
+                peer_time,
+            } => {
+                let Some(scheduled) = coordinator.echo(&id, from, server_time, peer_time) else {
+                    continue;
+                };
+                for Scheduled {
+                    request_id,
+                    id,
+                    peer_id,
+                    addr,
+                    at,
+                } in scheduled
+                {
+                    let response =
+                        query::answer(&alpha_manager, &beta_manager, &local_ports, &id, &peer_id);
+                    let msg = match response {
+                        QueryResponse::Ready {
+                            peer_id,
+                            public_ip,
+                            ports,
+                            strategy,
+                        } => Message::PunchAt {
+                            peer_id,
+                            at,
+                            public_ip,
+                            ports,
+                            strategy,
+                        },
+                        response => Message::QueryRes(response),
+                    };
+                    info!("Tx to {}: {:?}", addr, msg);
+                    send_frame(&socket, &Frame::new(request_id, msg), addr).await;
+                }
+            }
+            Message::PunchReport {
+                id,
+                peer_id,
+                outcome,
+            } => coordinator.report(&id, &peer_id, outcome),
+            Message::Alpha { .. }
+            | Message::Beta { .. }
+            | Message::QueryRes(_)
+            | Message::ClockProbe { .. }
+            | Message::PunchAt { .. }
+            | Message::Punch { .. } => warn!("Unexpected message from {}", from),
+        }
+    }
+}
+
+/// Send `buf`, or warn if it cannot be. One peer we cannot reach must not
+/// stop the server.
+async fn send(socket: &UdpSocket, buf: &[u8], addr: SocketAddr) {
+    if let Err(e) = socket.send_to(buf, addr).await {
+        warn!("Unable to send to {}: {}", addr, e);
+    }
+}
+
+/// Send `frame`, or warn if it does not fit in a datagram or cannot be sent
+async fn send_frame(socket: &UdpSocket, frame: &Frame, addr: SocketAddr) {
+    match frame.encode() {
+        Ok(buf) => send(socket, &buf, addr).await,
+        Err(e) => warn!("Unable to encode {:?}: {}", frame.message, e),
+    }
+}
+
+async fn monitor_task(
+    alpha_manager: Arc<AlphaManager>,
+    beta_manager: Arc<BetaManager>,
+) -> io::Result<()> {
+    loop {
+        sleep(MONITOR_INTERVAL).await;
+
+        if !alpha_manager.data().is_empty() || !beta_manager.data().is_empty() {
+            let data = alpha_manager.data();
+            for ref_multi in data.iter() {
+                let id = ref_multi.key();
+                let peer_data = ref_multi.value();
+                if let Some(instant) = peer_data.most_recent() {
+                    if instant.elapsed() <= MONITOR_INTERVAL {
+                        info!("Alpha...");
+                        info!("  id: {}", id);
+                        info!("    elapsed: {:?}", instant.elapsed());
+                        info!("    analysis...");
+                        peer_data.analysis().for_each(|x| info!("      {:?}", x));
+                        trace!("    rx_events...");
+                        peer_data.rx_events().for_each(|x| trace!("      {:?}", x));
+                    }
+                }
+            }
+
+            let data = beta_manager.data();
+            for ref_multi in data.iter() {
+                let id = ref_multi.key();
+                let peer_data = ref_multi.value();
+                if let Some(instant) = peer_data.most_recent() {
+                    if instant.elapsed() <= MONITOR_INTERVAL {
+                        info!("Beta...");
+                        info!("  id: {}", id);
+                        info!("    elapsed: {:?}", instant.elapsed());
+                        info!("    analysis...");
+                        peer_data.analysis().for_each(|x| info!("      {:?}", x));
+                        trace!("    rx_events...");
+                        peer_data.rx_events().for_each(|x| trace!("      {:?}", x));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rendezvous::{Candidate, PortPrediction, Strategy};
+
+    const BETA_SOCKETS: usize = 4;
+
+    /// Run the alpha and beta tests as `id`, returning the alpha socket
+    async fn run_tests(server: &RendezvousServer, id: &str) -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let alpha = Frame::new(
+            0,
+            Message::Alpha {
+                id: String::from(id),
+            },
+        );
+        for addr in server.alpha_addrs() {
+            socket
+                .send_to(&alpha.encode().unwrap(), addr)
+                .await
+                .unwrap();
+        }
+
+        for seq_num in 0..BETA_SOCKETS {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let beta = Message::Beta {
+                id: String::from(id),
+                local_port: socket.local_addr().unwrap().port(),
+                seq_num: seq_num as u16,
+            };
+            let beta = Frame::new(seq_num as u32, beta).encode().unwrap();
+            socket.send_to(&beta, server.beta_addr()).await.unwrap();
+        }
+
+        socket
+    }
+
+    async fn query(
+        socket: &UdpSocket,
+        server: &RendezvousServer,
+        id: &str,
+        peer_id: &str,
+    ) -> Frame {
+        let msg = Message::QueryReq {
+            id: String::from(id),
+            peer_id: String::from(peer_id),
+            local_port: socket.local_addr().unwrap().port(),
+        };
+        let request = Frame::new(7, msg).encode().unwrap();
+        socket.send_to(&request, server.api_addr()).await.unwrap();
+
+        let mut buf = [0; MAX_SIZE + 1];
+        let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+        assert_eq!(from, server.api_addr());
+        Frame::decode(&buf[..len]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn alpha_beta_and_query_on_loopback() {
+        let server = RendezvousServer::builder()
+            .ip(IpAddr::V4(Ipv4Addr::LOCALHOST))
+            .alpha_ports([0; 4])
+            .beta_port(0)
+            .api_port(0)
+            .beta_count(BETA_SOCKETS)
+            .threshold_percent(100)
+            .start()
+            .await
+            .unwrap();
+
+        let alice = run_tests(&server, "alice").await;
+        let bob = run_tests(&server, "bob").await;
+        let tested = async {
+            while ["alice", "bob"].iter().any(|id| {
+                let alpha = server.alpha_data();
+                let beta = server.beta_data();
+                !alpha.get(*id).is_some_and(|data| data.test_complete())
+                    || !beta.get(*id).is_some_and(|data| data.test_complete())
+            }) {
+                sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(2), tested)
+            .await
+            .expect("tests not complete");
+
+        // No NAT on loopback: one mapping, and the local port kept
+        let bob_addr = bob.local_addr().unwrap();
+        assert_eq!(
+            server.classification("bob").alpha,
+            Some(AlphaResult::SrcIpPortConstant(
+                bob_addr.ip(),
+                bob_addr.port()
+            ))
+        );
+        assert!(server
+            .beta_data()
+            .get("bob")
+            .unwrap()
+            .analysis()
+            .any(|x| x == (BetaResult::SrcPortAsOrig, 100)));
+
+        let frame = query(&alice, &server, "alice", "bob").await;
+        let expected = QueryResponse::Ready {
+            peer_id: String::from("bob"),
+            public_ip: bob_addr.ip(),
+            ports: vec![Candidate {
+                port: PortPrediction::Exact(bob_addr.port()),
+                probability: 1.0,
+            }],
+            strategy: Strategy::Direct,
+        };
+        assert_eq!(frame, Frame::new(7, Message::QueryRes(expected.clone())));
+        assert_eq!(server.query("alice", "bob"), expected);
+
+        let frame = query(&alice, &server, "alice", "carol").await;
+        let expected = QueryResponse::NotEnoughData {
+            peer_id: String::from("carol"),
+            missing: vec![String::from("carol")],
+        };
+        assert_eq!(frame.message, Message::QueryRes(expected));
+
+        let api_addr = server.api_addr();
+        server.shutdown().await;
+        // The ports are free again
+        UdpSocket::bind(api_addr).await.unwrap();
+    }
+}
@@ -0,0 +1,451 @@
+//! Framing of [`Message`]s, one per datagram
+//!
+//! ```text
+//! 0       1         2      3            7
+//! | magic | version | type | request id | body ...
+//! ```
+//!
+//! The request id is big-endian. A reply carries the id of the request it
+//! answers. The body is the message's fields as a CBOR array, in the order
+//! they are declared, with nothing after it. A frame is at most [`MAX_SIZE`]
+//! bytes.
+//!
+//! Adding, removing or reordering a field changes the body, so it needs a new
+//! [`VERSION`]. Older peers then get [`WireError::UnsupportedVersion`] rather
+//! than a misread message.
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::Message;
+use crate::error::WireError;
+
+pub const MAGIC: u8 = 0xa7;
+pub const VERSION: u8 = 1;
+/// Fits in a datagram on any path with the IPv6 minimum MTU
+pub const MAX_SIZE: usize = 1200;
+const HEADER_SIZE: usize = 7;
+
+const ALPHA: u8 = 1;
+const BETA: u8 = 2;
+const QUERY_REQ: u8 = 3;
+const QUERY_RES: u8 = 4;
+const ICE_CREDENTIALS: u8 = 5;
+const ICE_CANDIDATE: u8 = 6;
+const PUNCH_REQ: u8 = 7;
+const CLOCK_PROBE: u8 = 8;
+const CLOCK_ECHO: u8 = 9;
+const PUNCH_AT: u8 = 10;
+const PUNCH: u8 = 11;
+const PUNCH_REPORT: u8 = 12;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub request_id: u32,
+    pub message: Message,
+}
+
+impl Frame {
+    pub fn new(request_id: u32, message: Message) -> Self {
+        Self {
+            request_id,
+            message,
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, WireError> {
+        let mut buf = Vec::with_capacity(MAX_SIZE);
+        buf.extend_from_slice(&[MAGIC, VERSION, 0]);
+        buf.extend_from_slice(&self.request_id.to_be_bytes());
+
+        let kind = match &self.message {
+            Message::Alpha { id } => write(&mut buf, ALPHA, &(id,)),
+            Message::Beta {
+                id,
+                local_port,
+                seq_num,
+            } => write(&mut buf, BETA, &(id, local_port, seq_num)),
+            Message::QueryReq {
+                id,
+                peer_id,
+                local_port,
+            } => write(&mut buf, QUERY_REQ, &(id, peer_id, local_port)),
+            Message::QueryRes(response) => write(&mut buf, QUERY_RES, &(response,)),
+            Message::IceCredentials {
+                id,
+                peer_id,
+                ufrag,
+                pwd,
+            } => write(&mut buf, ICE_CREDENTIALS, &(id, peer_id, ufrag, pwd)),
+            Message::IceCandidate {
+                id,
+                peer_id,
+                candidate,
+            } => write(&mut buf, ICE_CANDIDATE, &(id, peer_id, candidate)),
+            Message::PunchReq {
+                id,
+                peer_id,
+                local_port,
+            } => write(&mut buf, PUNCH_REQ, &(id, peer_id, local_port)),
+            Message::ClockProbe { server_time } => write(&mut buf, CLOCK_PROBE, &(server_time,)),
+            Message::ClockEcho {
+                id,
+                server_time,
+                peer_time,
+            } => write(&mut buf, CLOCK_ECHO, &(id, server_time, peer_time)),
+            Message::PunchAt {
+                peer_id,
+                at,
+                public_ip,
+                ports,
+                strategy,
+            } => write(
+                &mut buf,
+                PUNCH_AT,
+                &(peer_id, at, public_ip, ports, strategy),
+            ),
+            Message::Punch { id } => write(&mut buf, PUNCH, &(id,)),
+            Message::PunchReport {
+                id,
+                peer_id,
+                outcome,
+            } => write(&mut buf, PUNCH_REPORT, &(id, peer_id, outcome)),
+        }?;
+        buf[2] = kind;
+
+        if buf.len() > MAX_SIZE {
+            return Err(WireError::TooLarge(buf.len()));
+        }
+        Ok(buf)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, WireError> {
+        if buf.len() > MAX_SIZE {
+            return Err(WireError::TooLarge(buf.len()));
+        }
+        if buf.len() < HEADER_SIZE {
+            return Err(WireError::Truncated);
+        }
+        if buf[0] != MAGIC {
+            return Err(WireError::BadMagic(buf[0]));
+        }
+        if buf[1] != VERSION {
+            return Err(WireError::UnsupportedVersion(buf[1]));
+        }
+        let request_id = u32::from_be_bytes(buf[3..7].try_into().expect("4 bytes"));
+        let body = &buf[HEADER_SIZE..];
+
+        let message = match buf[2] {
+            ALPHA => {
+                let (id,) = read(body)?;
+                Message::Alpha { id }
+            }
+            BETA => {
+                let (id, local_port, seq_num) = read(body)?;
+                Message::Beta {
+                    id,
+                    local_port,
+                    seq_num,
+                }
+            }
+            QUERY_REQ => {
+                let (id, peer_id, local_port) = read(body)?;
+                Message::QueryReq {
+                    id,
+                    peer_id,
+                    local_port,
+                }
+            }
+            QUERY_RES => {
+                let (response,) = read(body)?;
+                Message::QueryRes(response)
+            }
+            ICE_CREDENTIALS => {
+                let (id, peer_id, ufrag, pwd) = read(body)?;
+                Message::IceCredentials {
+                    id,
+                    peer_id,
+                    ufrag,
+                    pwd,
+                }
+            }
+            ICE_CANDIDATE => {
+                let (id, peer_id, candidate) = read(body)?;
+                Message::IceCandidate {
+                    id,
+                    peer_id,
+                    candidate,
+                }
+            }
+            PUNCH_REQ => {
+                let (id, peer_id, local_port) = read(body)?;
+                Message::PunchReq {
+                    id,
+                    peer_id,
+                    local_port,
+                }
+            }
+            CLOCK_PROBE => {
+                let (server_time,) = read(body)?;
+                Message::ClockProbe { server_time }
+            }
+            CLOCK_ECHO => {
+                let (id, server_time, peer_time) = read(body)?;
+                Message::ClockEcho {
+                    id,
+                    server_time,
+                    peer_time,
+                }
+            }
+            PUNCH_AT => {
+                let (peer_id, at, public_ip, ports, strategy) = read(body)?;
+                Message::PunchAt {
+                    peer_id,
+                    at,
+                    public_ip,
+                    ports,
+                    strategy,
+                }
+            }
+            PUNCH => {
+                let (id,) = read(body)?;
+                Message::Punch { id }
+            }
+            PUNCH_REPORT => {
+                let (id, peer_id, outcome) = read(body)?;
+                Message::PunchReport {
+                    id,
+                    peer_id,
+                    outcome,
+                }
+            }
+            kind => return Err(WireError::UnknownType(kind)),
+        };
+
+        Ok(Self {
+            request_id,
+            message,
+        })
+    }
+}
+
+fn write<T: Serialize>(buf: &mut Vec<u8>, kind: u8, body: &T) -> Result<u8, WireError> {
+    ciborium::into_writer(body, &mut *buf)?;
+    Ok(kind)
+}
+
+fn read<T: DeserializeOwned>(body: &[u8]) -> Result<T, WireError> {
+    let mut rest = body;
+    let value = ciborium::from_reader(&mut rest)?;
+    if !rest.is_empty() {
+        return Err(WireError::TrailingBytes(rest.len()));
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rendezvous::{Candidate, PortPrediction, PunchOutcome, QueryResponse, Strategy};
+
+    fn messages() -> Vec<Message> {
+        let id = String::from("alice");
+        let peer_id = String::from("bob");
+        let ports = vec![
+            Candidate {
+                port: PortPrediction::Exact(40000),
+                probability: 0.75,
+            },
+            Candidate {
+                port: PortPrediction::Random(1024, 65535),
+                probability: 0.25,
+            },
+        ];
+        vec![
+            Message::Alpha { id: id.clone() },
+            Message::Beta {
+                id: id.clone(),
+                local_port: 5000,
+                seq_num: 3,
+            },
+            Message::QueryReq {
+                id: id.clone(),
+                peer_id: peer_id.clone(),
+                local_port: 5000,
+            },
+            Message::QueryRes(QueryResponse::Ready {
+                peer_id: peer_id.clone(),
+                public_ip: "2001:db8::1".parse().unwrap(),
+                ports: ports.clone(),
+                strategy: Strategy::ManyPorts,
+            }),
+            Message::QueryRes(QueryResponse::NotEnoughData {
+                peer_id: peer_id.clone(),
+                missing: vec![id.clone(), peer_id.clone()],
+            }),
+            Message::IceCredentials {
+                id: id.clone(),
+                peer_id: peer_id.clone(),
+                ufrag: String::from("abcd1234"),
+                pwd: String::from("0123456789abcdefghijklmn"),
+            },
+            Message::IceCandidate {
+                id: id.clone(),
+                peer_id: peer_id.clone(),
+                candidate: String::from("a=end-of-candidates"),
+            },
+            Message::PunchReq {
+                id: id.clone(),
+                peer_id: peer_id.clone(),
+                local_port: 5000,
+            },
+            Message::ClockProbe {
+                server_time: u64::MAX,
+            },
+            Message::ClockEcho {
+                id: id.clone(),
+                server_time: 1,
+                peer_time: 2,
+            },
+            Message::PunchAt {
+                peer_id: peer_id.clone(),
+                at: 1_700_000_000_000_000,
+                public_ip: "203.0.113.5".parse().unwrap(),
+                ports,
+                strategy: Strategy::Direct,
+            },
+            Message::Punch { id: id.clone() },
+            Message::PunchReport {
+                id: id.clone(),
+                peer_id: peer_id.clone(),
+                outcome: PunchOutcome::Success("203.0.113.5:40000".parse().unwrap()),
+            },
+            Message::PunchReport {
+                id,
+                peer_id,
+                outcome: PunchOutcome::Failure,
+            },
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut kinds = Vec::new();
+        for (i, message) in messages().into_iter().enumerate() {
+            let frame = Frame::new(0xdead_0000 + i as u32, message);
+            let buf = frame.encode().unwrap();
+            assert_eq!(&buf[..2], [MAGIC, VERSION]);
+            assert_eq!(buf[3..7], frame.request_id.to_be_bytes());
+            assert_eq!(Frame::decode(&buf).unwrap(), frame);
+            kinds.push(buf[2]);
+        }
+        kinds.dedup();
+        assert_eq!(kinds, (ALPHA..=PUNCH_REPORT).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn bad_header() {
+        let buf = Frame::new(
+            7,
+            Message::Punch {
+                id: String::from("a"),
+            },
+        )
+        .encode()
+        .unwrap();
+
+        let mut bad = buf.clone();
+        bad[0] = 0x42;
+        assert!(matches!(
+            Frame::decode(&bad),
+            Err(WireError::BadMagic(0x42))
+        ));
+
+        let mut bad = buf.clone();
+        bad[1] = VERSION + 1;
+        assert!(matches!(
+            Frame::decode(&bad),
+            Err(WireError::UnsupportedVersion(v)) if v == VERSION + 1
+        ));
+
+        let mut bad = buf.clone();
+        bad[2] = 0;
+        assert!(matches!(
+            Frame::decode(&bad),
+            Err(WireError::UnknownType(0))
+        ));
+        bad[2] = PUNCH_REPORT + 1;
+        assert!(matches!(
+            Frame::decode(&bad),
+            Err(WireError::UnknownType(_))
+        ));
+
+        for len in 0..HEADER_SIZE {
+            assert!(matches!(
+                Frame::decode(&buf[..len]),
+                Err(WireError::Truncated)
+            ));
+        }
+    }
+
+    #[test]
+    fn bad_body() {
+        let buf = Frame::new(
+            7,
+            Message::Punch {
+                id: String::from("a"),
+            },
+        )
+        .encode()
+        .unwrap();
+
+        // Cut short, or missing entirely
+        assert!(matches!(
+            Frame::decode(&buf[..buf.len() - 1]),
+            Err(WireError::Decode(_))
+        ));
+        assert!(matches!(
+            Frame::decode(&buf[..HEADER_SIZE]),
+            Err(WireError::Decode(_))
+        ));
+
+        let mut long = buf.clone();
+        long.extend_from_slice(&[0, 0]);
+        assert!(matches!(
+            Frame::decode(&long),
+            Err(WireError::TrailingBytes(2))
+        ));
+
+        // The body of another type
+        let mut other = buf.clone();
+        other[2] = CLOCK_PROBE;
+        assert!(matches!(Frame::decode(&other), Err(WireError::Decode(_))));
+    }
+
+    #[test]
+    fn too_large() {
+        let frame = Frame::new(
+            0,
+            Message::Alpha {
+                id: "x".repeat(MAX_SIZE),
+            },
+        );
+        assert!(matches!(frame.encode(), Err(WireError::TooLarge(len)) if len > MAX_SIZE));
+
+        let fits = Frame::new(
+            0,
+            Message::Alpha {
+                id: "x".repeat(MAX_SIZE - HEADER_SIZE - 4),
+            },
+        );
+        let buf = fits.encode().unwrap();
+        assert_eq!(buf.len(), MAX_SIZE);
+        assert_eq!(Frame::decode(&buf).unwrap(), fits);
+
+        let mut buf = buf;
+        buf.push(0);
+        assert!(matches!(
+            Frame::decode(&buf),
+            Err(WireError::TooLarge(len)) if len == MAX_SIZE + 1
+        ));
+    }
+}